@@ -1,5 +1,21 @@
+use std::net::SocketAddr;
 use std::path::PathBuf;
 
+use glob::Pattern;
+
+use ltorrent::config::{Configuration, DEFAULT_UPLOAD_SLOTS};
+use ltorrent::download::connections::{
+    ConnectionLimits, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_HALF_OPEN,
+};
+use ltorrent::download::engine::MAX_PEERS;
+use ltorrent::download::smartban::DEFAULT_MAX_HASH_FAILURES;
+use ltorrent::net::class::{PeerClass, PeerClasses};
+use ltorrent::net::filter::IpFilter;
+use ltorrent::net::limit::Rates;
+use ltorrent::net::schedule::{parse_utc_offset, Schedule, TimeWindow};
+use ltorrent::piece::picker::Strategy;
+use ltorrent::storage::Allocation;
+
 #[derive(clap::Parser)]
 #[command(version, about, long_about = None)]
 pub(crate) struct Cli {
@@ -20,4 +36,213 @@ pub(crate) enum Command {
         torrent_path: PathBuf,
         peer_address: String,
     },
-} 
+    Download {
+        #[arg(short, long)]
+        output: PathBuf,
+        /// Order in which pieces are downloaded: rarest-first, sequential, random-first:<N>
+        /// or priority.
+        #[arg(long, default_value = "rarest-first")]
+        strategy: Strategy,
+        /// How the files take up disk space: sparse, full or grow.
+        #[arg(long, default_value = "sparse")]
+        allocation: Allocation,
+        /// File the resume data is kept in, to pick the download up after a restart.
+        /// Defaults to .<name>.resume in the output directory.
+        #[arg(long)]
+        resume: Option<PathBuf>,
+        /// Only downloads the files whose path matches the glob. Can be repeated.
+        #[arg(long)]
+        only: Vec<Pattern>,
+        /// Skips the files whose path matches the glob. Can be repeated.
+        #[arg(long)]
+        exclude: Vec<Pattern>,
+        #[command(flatten)]
+        transfer: TransferOptions,
+        torrent_path: PathBuf,
+    },
+    DownloadPiece {
+        #[arg(short, long)]
+        output: PathBuf,
+        torrent_path: PathBuf,
+        piece_index: usize,
+    },
+    /// Checks the content stored under a directory, and uploads it to other peers.
+    Seed {
+        /// Hands the pieces out one peer at a time, to spread a first copy with as little
+        /// uploading as possible.
+        #[arg(long)]
+        super_seed: bool,
+        #[command(flatten)]
+        transfer: TransferOptions,
+        torrent_path: PathBuf,
+        dir: PathBuf,
+    },
+    /// Downloads a torrent in order while streaming its files, over HTTP or to the standard
+    /// output.
+    Stream {
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+        /// Address to serve the files on, at /<info hash>/<file>.
+        #[arg(long, conflicts_with = "stdout", required_unless_present = "stdout")]
+        http: Option<SocketAddr>,
+        /// Path of a file of the torrent to write to the standard output.
+        #[arg(long)]
+        stdout: Option<String>,
+        #[command(flatten)]
+        transfer: TransferOptions,
+        torrent_path: PathBuf,
+    },
+    /// Checks the content stored under a directory against the hashes of the torrent.
+    Verify {
+        torrent_path: PathBuf,
+        dir: PathBuf,
+    },
+}
+
+/// Options of the commands that exchange pieces with peers.
+#[derive(clap::Args)]
+pub(crate) struct TransferOptions {
+    /// Number of peers that are unchoked, and can download from us, at once.
+    #[arg(long, default_value_t = DEFAULT_UPLOAD_SLOTS)]
+    upload_slots: usize,
+    /// Maximum number of peers the torrent is connected to.
+    #[arg(long, default_value_t = MAX_PEERS)]
+    max_peers: usize,
+    /// Maximum number of connections in total.
+    #[arg(long, default_value_t = DEFAULT_MAX_CONNECTIONS)]
+    max_connections: usize,
+    /// Maximum number of connections being set up at once.
+    #[arg(long, default_value_t = DEFAULT_MAX_HALF_OPEN)]
+    max_half_open: usize,
+    /// Maximum upload rate over every connection, in KiB/s.
+    #[arg(long, value_name = "KIB/S")]
+    upload_limit: Option<u64>,
+    /// Maximum download rate over every connection, in KiB/s.
+    #[arg(long, value_name = "KIB/S")]
+    download_limit: Option<u64>,
+    /// Maximum upload rate of the torrent, in KiB/s.
+    #[arg(long, value_name = "KIB/S")]
+    torrent_upload_limit: Option<u64>,
+    /// Maximum download rate of the torrent, in KiB/s.
+    #[arg(long, value_name = "KIB/S")]
+    torrent_download_limit: Option<u64>,
+    /// Maximum upload rate to each peer, in KiB/s.
+    #[arg(long, value_name = "KIB/S")]
+    peer_upload_limit: Option<u64>,
+    /// Maximum download rate from each peer, in KiB/s.
+    #[arg(long, value_name = "KIB/S")]
+    peer_download_limit: Option<u64>,
+    /// Alternative maximum upload rate over every connection, in KiB/s, in use during the
+    /// windows of --alt-schedule.
+    #[arg(long, value_name = "KIB/S")]
+    alt_upload_limit: Option<u64>,
+    /// Alternative maximum download rate over every connection, in KiB/s.
+    #[arg(long, value_name = "KIB/S")]
+    alt_download_limit: Option<u64>,
+    /// Weekly window in which the alternative limits are in use, such as
+    /// "mon-fri 09:00-17:00" or "daily 22:00-06:00". Can be repeated.
+    #[arg(long, value_name = "WINDOW")]
+    alt_schedule: Vec<TimeWindow>,
+    /// Offset from UTC of the times of --alt-schedule, such as +02:00.
+    #[arg(long, value_name = "+HH:MM", value_parser = parse_utc_offset, allow_hyphen_values = true, default_value = "+00:00")]
+    utc_offset: i32,
+    /// Class of peers by IP range, with limits of its own:
+    /// NAME:RANGE[,RANGE...][:up=KIB/S][:down=KIB/S][:max=N][:priority=N][:unthrottled].
+    /// Ranges are networks like 10.0.0.0/8 or ranges like 10.0.0.1-10.0.0.9. Can be repeated,
+    /// and the first class that matches a peer applies.
+    #[arg(long, value_name = "CLASS", value_parser = parse_peer_class)]
+    peer_class: Vec<PeerClass>,
+    /// Throttles the peers on local networks as well, which are not throttled otherwise.
+    #[arg(long)]
+    throttle_lan: bool,
+    /// Blocklist of addresses that are never connected to, in the eMule ipfilter.dat,
+    /// PeerGuardian P2P or CIDR format. Can be repeated, and is reloaded on SIGHUP.
+    #[arg(long, value_name = "PATH")]
+    ip_filter: Vec<PathBuf>,
+    /// Number of pieces failing their hash check a peer may send blocks of before it is
+    /// banned, or 0 for no limit. Peers found to have sent a corrupt block are banned right
+    /// away.
+    #[arg(long, default_value_t = DEFAULT_MAX_HASH_FAILURES)]
+    max_hash_failures: usize,
+}
+
+impl TransferOptions {
+    /// Returns the configuration with the options applied, once the blocklists are loaded.
+    pub(crate) async fn configuration(&self) -> anyhow::Result<Configuration> {
+        let ip_filter = IpFilter::new();
+        if !self.ip_filter.is_empty() {
+            ip_filter.load(&self.ip_filter).await?;
+        }
+        let config = Configuration::default()
+            .with_upload_slots(self.upload_slots)
+            .with_max_peers(self.max_peers)
+            .with_connection_limits(ConnectionLimits::new(
+                self.max_half_open,
+                self.max_connections,
+            ))
+            .with_global_limit(rates(self.upload_limit, self.download_limit))
+            .with_torrent_limit(rates(
+                self.torrent_upload_limit,
+                self.torrent_download_limit,
+            ))
+            .with_peer_limit(rates(self.peer_upload_limit, self.peer_download_limit))
+            .with_peer_classes(self.peer_classes())
+            .with_ip_filter(ip_filter)
+            .with_max_hash_failures(self.max_hash_failures);
+        if self.alt_schedule.is_empty() {
+            return Ok(config);
+        }
+        let schedule = Schedule::new(self.alt_schedule.clone()).with_utc_offset(self.utc_offset);
+        Ok(config
+            .with_alternative_limit(rates(self.alt_upload_limit, self.alt_download_limit))
+            .with_schedule(schedule))
+    }
+
+    /// Returns the peer classes of the options, in the order they were given, followed by the
+    /// built-in class of local networks unless they are throttled.
+    fn peer_classes(&self) -> PeerClasses {
+        let mut classes = if self.throttle_lan {
+            PeerClasses::new(Vec::new())
+        } else {
+            PeerClasses::default()
+        };
+        for class in self.peer_class.iter().rev() {
+            classes = classes.with_class(class.clone());
+        }
+        classes
+    }
+}
+
+/// Parses a peer class of the form
+/// `NAME:RANGE[,RANGE...][:up=KIB/S][:down=KIB/S][:max=N][:priority=N][:unthrottled]`.
+fn parse_peer_class(s: &str) -> anyhow::Result<PeerClass> {
+    let mut parts = s.split(':');
+    let name = parts.next().unwrap_or_default();
+    let ranges = parts
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Peer class {s} has no IP ranges."))?
+        .split(',')
+        .map(str::parse)
+        .collect::<anyhow::Result<_>>()?;
+    let mut class = PeerClass::new(name, ranges);
+    let (mut upload, mut download) = (None, None);
+    for option in parts {
+        let (key, value) = option.split_once('=').unwrap_or((option, ""));
+        match key {
+            "up" => upload = Some(value.parse()?),
+            "down" => download = Some(value.parse()?),
+            "max" => class = class.with_max_connections(value.parse()?),
+            "priority" => class = class.with_priority(value.parse()?),
+            "unthrottled" => class = class.unthrottled(),
+            _ => anyhow::bail!("Unknown option {key} of peer class {name}."),
+        }
+    }
+    Ok(class.with_limit(rates(upload, download)))
+}
+
+/// Converts rates in KiB/s into rates in bytes per second. Rates too large to count in bytes
+/// are capped, which leaves them as good as unlimited.
+fn rates(upload: Option<u64>, download: Option<u64>) -> Rates {
+    let bytes = |kib: u64| kib.saturating_mul(1024);
+    Rates::new(upload.map(bytes), download.map(bytes))
+}
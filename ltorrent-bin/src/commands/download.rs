@@ -0,0 +1,184 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use glob::Pattern;
+use tokio::net::TcpStream;
+
+use ltorrent::config::Configuration;
+use ltorrent::download::download_piece;
+use ltorrent::download::engine::Downloader;
+use ltorrent::download::priority::FilePriority;
+use ltorrent::net::bitfield::BitField;
+use ltorrent::net::peers::Peer;
+use ltorrent::piece::picker::Strategy;
+use ltorrent::storage::file::FileStorage;
+use ltorrent::storage::{Allocation, Storage};
+use ltorrent::torrent::{File, Keys, Torrent};
+use ltorrent::tracker::{Tracker, TrackerRequest};
+
+/// The files of a torrent to download, as globs matched against their paths.
+pub struct Selection {
+    /// Only the files that match one of these are downloaded, unless there are none.
+    pub only: Vec<Pattern>,
+    /// The files that match one of these are skipped.
+    pub exclude: Vec<Pattern>,
+}
+
+impl Selection {
+    /// Returns the priority of every file of the torrent, skipping the unselected ones.
+    ///
+    /// The paths are those of [`File::path`], or the name of a single file torrent.
+    fn priorities(&self, torrent: &Torrent) -> Vec<FilePriority> {
+        let paths = match torrent.keys() {
+            Keys::SingleFile { .. } => vec![torrent.name().to_string()],
+            Keys::MultiFile { files } => files.iter().map(File::path).collect(),
+        };
+        paths
+            .iter()
+            .map(|path| {
+                let included =
+                    self.only.is_empty() || self.only.iter().any(|glob| glob.matches(path));
+                if included && !self.exclude.iter().any(|glob| glob.matches(path)) {
+                    FilePriority::Normal
+                } else {
+                    FilePriority::Skip
+                }
+            })
+            .collect()
+    }
+}
+
+/// Downloads the content of a torrent into the directory `output`.
+///
+/// The progress of the download is printed to the standard error as pieces are verified.
+/// The resume data is kept in `resume`, or in `.<name>.resume` under `output`, and saved
+/// when the download is interrupted with Ctrl-C. Only the files in `selection` are
+/// downloaded.
+pub async fn torrent(
+    output: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    strategy: Strategy,
+    allocation: Allocation,
+    resume: Option<PathBuf>,
+    selection: Selection,
+    config: Configuration,
+) -> anyhow::Result<()> {
+    let torrent = Torrent::from_file(&path)
+        .await
+        .context("Failed to read torrent file.")?;
+    let name = torrent.name().to_string();
+    let resume = resume.unwrap_or_else(|| output.as_ref().join(format!(".{name}.resume")));
+
+    let priorities = selection.priorities(&torrent);
+    anyhow::ensure!(
+        priorities.contains(&FilePriority::Normal),
+        "No file of the torrent is selected."
+    );
+
+    let storage =
+        FileStorage::create_with_priorities(&torrent, output.as_ref(), allocation, &priorities)
+            .await
+            .context("Failed to create output files.")?;
+    super::schedule_limits(&config);
+    super::reload_ip_filter_on_hangup(&config);
+    let downloader = Downloader::new(torrent, config, storage)?
+        .with_strategy(strategy)
+        .with_file_priorities(priorities)
+        .with_resume(resume);
+    let control = downloader.control();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            control.stop();
+        }
+    });
+    let mut progress = downloader.progress();
+    let reporter = tokio::spawn(async move {
+        while progress.changed().await.is_ok() {
+            let progress = progress.borrow_and_update().clone();
+            eprint!(
+                "\rPieces: {}/{}  Peers: {}  Failed: {}  Banned: {}  Wasted: {} B{}",
+                progress.pieces,
+                progress.total_pieces,
+                progress.peers,
+                progress.failed,
+                progress.banned,
+                progress.wasted,
+                if progress.endgame { "  (endgame)" } else { "" }
+            );
+        }
+    });
+
+    let result = downloader.run().await;
+    reporter.abort();
+    eprintln!();
+    result?.release().await?;
+
+    println!("Downloaded {name} to {}.", output.as_ref().display());
+    Ok(())
+}
+
+/// Downloads a single piece of a torrent and writes it to `output`.
+///
+/// The peers are fetched from the tracker, and tried one after the other until one of them
+/// has the piece and sends it intact.
+pub async fn piece(
+    output: impl AsRef<Path>,
+    path: impl AsRef<Path>,
+    piece_i: usize,
+) -> anyhow::Result<()> {
+    let torrent = Torrent::from_file(&path)
+        .await
+        .context("Failed to read torrent file.")?;
+    anyhow::ensure!(
+        piece_i < torrent.n_pieces(),
+        "Torrent has {} pieces, there is no piece {}.",
+        torrent.n_pieces(),
+        piece_i
+    );
+    let info_hash = torrent
+        .info_hash()
+        .context("Failed to hash info dictionary")?;
+
+    let config = Configuration::default();
+    let peer_id: [u8; 20] = config.peer_id().as_bytes().try_into()?;
+    let tracker = Tracker::new(torrent.announce())?;
+    let request = TrackerRequest::new(
+        &info_hash,
+        config.peer_id(),
+        config.port(),
+        0,
+        0,
+        torrent.length(),
+        1,
+    );
+    let response = tracker.query(request).await?;
+
+    let no_pieces = BitField::new(torrent.n_pieces());
+    for &address in &response.peers().0 {
+        let mut peer = match Peer::<TcpStream>::new(address, peer_id, info_hash, &no_pieces).await {
+            Ok(peer) => peer,
+            Err(e) => {
+                eprintln!("Skipping peer {address}: {e:#}");
+                continue;
+            }
+        };
+        if !peer.has_piece(piece_i) {
+            continue;
+        }
+        match download_piece(&torrent, piece_i, &mut peer).await {
+            Ok(data) => {
+                tokio::fs::write(&output, data)
+                    .await
+                    .context("Failed to write piece to output file.")?;
+                println!(
+                    "Piece {piece_i} downloaded to {}.",
+                    output.as_ref().display()
+                );
+                return Ok(());
+            }
+            Err(e) => eprintln!("Failed to download piece from {address}: {e:#}"),
+        }
+    }
+
+    anyhow::bail!("No peer could provide piece {piece_i}.")
+}
@@ -1,2 +1,57 @@
+use ltorrent::config::Configuration;
+use ltorrent::net::schedule::{LimitScheduler, SpeedProfile};
+
+pub(crate) mod download;
 pub(crate) mod peers;
-pub(crate) mod info;
\ No newline at end of file
+pub(crate) mod info;
+pub(crate) mod seed;
+pub(crate) mod stream;
+pub(crate) mod verify;
+
+/// Switches the global rate limits on the schedule of the configuration, if it has one, and
+/// prints to the standard error whenever the limits in use change.
+pub(crate) fn schedule_limits(config: &Configuration) {
+    let Some(scheduler) = LimitScheduler::from_config(config) else {
+        return;
+    };
+    let mut active = scheduler.control().subscribe();
+    tokio::spawn(scheduler.run());
+    tokio::spawn(async move {
+        while active.changed().await.is_ok() {
+            match *active.borrow_and_update() {
+                SpeedProfile::Normal => eprintln!("\nSwitched to the normal speed limits."),
+                SpeedProfile::Alternative => eprintln!("\nSwitched to the alternative speed limits."),
+            }
+        }
+    });
+}
+
+/// Reloads the blocklists of the configuration whenever the process receives SIGHUP, and
+/// prints to the standard error how many ranges are blocked and how many attempts were.
+pub(crate) fn reload_ip_filter_on_hangup(config: &Configuration) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let filter = config.ip_filter().clone();
+        if filter.paths().is_empty() {
+            return;
+        }
+        let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+            return;
+        };
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                match filter.reload().await {
+                    Ok(ranges) => eprintln!(
+                        "\nReloaded the IP filter: {ranges} ranges blocked, {} attempts blocked so far.",
+                        filter.blocked_attempts()
+                    ),
+                    Err(error) => eprintln!("\nFailed to reload the IP filter: {error:#}"),
+                }
+            }
+        });
+    }
+    #[cfg(not(unix))]
+    let _ = config;
+}
\ No newline at end of file
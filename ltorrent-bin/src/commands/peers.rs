@@ -7,6 +7,7 @@ use anyhow::Context;
 use tokio::net::TcpStream;
 
 use ltorrent::config::Configuration;
+use ltorrent::net::bitfield::BitField;
 use ltorrent::net::peers::Peer;
 use ltorrent::torrent::Torrent;
 use ltorrent::tracker::{Tracker, TrackerRequest};
@@ -58,7 +59,8 @@ pub async fn handshake(path: impl AsRef<Path>, address: &str) -> anyhow::Result<
     let config = Configuration::default();
     let peer_id: [u8; 20] = config.peer_id().as_bytes().try_into()?;
 
-    let peer = Peer::<TcpStream>::new(address, peer_id, info_hash).await?;
+    let no_pieces = BitField::new(torrent.n_pieces());
+    let peer = Peer::<TcpStream>::new(address, peer_id, info_hash, &no_pieces).await?;
     let peer_id = hex::encode(peer.peer_id());
 
     let stdout = std::io::stdout();
@@ -0,0 +1,87 @@
+use std::net::Ipv4Addr;
+use std::path::Path;
+
+use anyhow::Context;
+use tokio::net::TcpListener;
+
+use ltorrent::config::Configuration;
+use ltorrent::download::engine::Downloader;
+use ltorrent::storage::file::FileStorage;
+use ltorrent::storage::verify::verify;
+use ltorrent::storage::{Allocation, Storage};
+use ltorrent::torrent::Torrent;
+
+/// Checks the content of a torrent stored under `dir`, and seeds it until Ctrl-C is pressed.
+/// With `super_seed`, the pieces are handed out one peer at a time.
+///
+/// Peers connect on the port of the configuration, which is announced to the tracker, and
+/// the number of bytes uploaded is printed to the standard error as blocks are sent.
+///
+/// # Errors
+///
+/// This function will return an error if the torrent file or the content cannot be read, if
+/// any piece fails the check, or if the port cannot be listened on.
+pub async fn invoke(
+    path: impl AsRef<Path>,
+    dir: impl AsRef<Path>,
+    super_seed: bool,
+    config: Configuration,
+) -> anyhow::Result<()> {
+    let torrent = Torrent::from_file(&path)
+        .await
+        .context("Failed to read torrent file.")?;
+    let n_pieces = torrent.n_pieces();
+
+    let verification = verify(&torrent, &dir, |piece_i, _| {
+        eprint!("\rChecked: {}/{}", piece_i + 1, n_pieces);
+    })
+    .await;
+    eprintln!();
+    let verification = verification?;
+    anyhow::ensure!(
+        verification.is_complete(),
+        "{} of {} pieces failed the check, run verify for details.",
+        n_pieces - verification.n_good(),
+        n_pieces
+    );
+
+    let listener = TcpListener::bind((Ipv4Addr::UNSPECIFIED, config.port()))
+        .await
+        .with_context(|| format!("Failed to listen on port {}.", config.port()))?;
+    let storage = FileStorage::create(&torrent, dir.as_ref(), Allocation::Sparse)
+        .await
+        .context("Failed to open content files.")?;
+    let name = torrent.name().to_string();
+    super::schedule_limits(&config);
+    super::reload_ip_filter_on_hangup(&config);
+    let mut downloader = Downloader::new(torrent, config, storage)?
+        .with_pieces(verification.pieces().clone())
+        .with_listener(listener)
+        .with_keep_running();
+    if super_seed {
+        downloader = downloader.with_super_seeding();
+    }
+    let control = downloader.control();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            control.stop();
+        }
+    });
+    let mut progress = downloader.progress();
+    let reporter = tokio::spawn(async move {
+        while progress.changed().await.is_ok() {
+            let progress = progress.borrow_and_update().clone();
+            eprint!(
+                "\rPeers: {}  Uploaded: {} B",
+                progress.peers, progress.uploaded
+            );
+        }
+    });
+
+    eprintln!("Seeding {name}.");
+    let result = downloader.run().await;
+    reporter.abort();
+    eprintln!();
+    result?.release().await?;
+    Ok(())
+}
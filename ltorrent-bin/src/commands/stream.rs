@@ -0,0 +1,124 @@
+use std::net::SocketAddr;
+use std::path::Path;
+
+use anyhow::Context;
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpListener;
+
+use ltorrent::config::Configuration;
+use ltorrent::download::engine::{DownloadControl, Downloader};
+use ltorrent::download::http::StreamServer;
+use ltorrent::download::priority::FilePriority;
+use ltorrent::piece::picker::Strategy;
+use ltorrent::storage::file::FileStorage;
+use ltorrent::storage::{Allocation, FileLayout, Storage};
+use ltorrent::torrent::Torrent;
+
+/// How the content is streamed while it downloads.
+pub enum Mode {
+    /// Serves every file over HTTP on an address.
+    Http(SocketAddr),
+    /// Writes a single file to the standard output.
+    Stdout(String),
+}
+
+/// Downloads a torrent into `output` in order, while streaming its files.
+///
+/// Over HTTP, the files are served at `http://<address>/<info hash>/<path>` until Ctrl-C is
+/// pressed, and the download keeps running once it completes. To the standard output, the
+/// command exits once the file has been written. The resume data is kept in `.<name>.resume`
+/// under `output`.
+pub async fn invoke(
+    path: impl AsRef<Path>,
+    output: impl AsRef<Path>,
+    mode: Mode,
+    config: Configuration,
+) -> anyhow::Result<()> {
+    let torrent = Torrent::from_file(&path)
+        .await
+        .context("Failed to read torrent file.")?;
+    let info_hash = torrent
+        .info_hash()
+        .context("Failed to hash info dictionary.")?;
+    let layout = FileLayout::new(&torrent)?;
+    let resume = output.as_ref().join(format!(".{}.resume", torrent.name()));
+
+    let storage = FileStorage::create(&torrent, output.as_ref(), Allocation::Sparse)
+        .await
+        .context("Failed to create output files.")?;
+    super::schedule_limits(&config);
+    super::reload_ip_filter_on_hangup(&config);
+    let downloader = Downloader::new(torrent, config, storage)?
+        .with_strategy(Strategy::Sequential)
+        .with_resume(resume)
+        .with_keep_running();
+    let control = downloader.control();
+    let mut download = tokio::spawn(downloader.run());
+
+    let streaming = async {
+        match mode {
+            Mode::Http(address) => serve(address, &info_hash, layout, &control).await,
+            Mode::Stdout(file) => pipe(&layout, &file, &control).await,
+        }
+    };
+    tokio::select! {
+        result = streaming => result?,
+        _ = tokio::signal::ctrl_c() => {}
+        // A download that keeps running only ends on its own when it fails.
+        result = &mut download => {
+            result??.release().await?;
+            return Ok(());
+        }
+    }
+
+    control.stop();
+    match download.await? {
+        Ok(storage) => storage.release().await?,
+        Err(e) => eprintln!("{e:#}"),
+    }
+    Ok(())
+}
+
+/// Serves the files of the download over HTTP on `address`.
+async fn serve(
+    address: SocketAddr,
+    info_hash: &[u8; 20],
+    layout: FileLayout,
+    control: &DownloadControl,
+) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(address)
+        .await
+        .with_context(|| format!("Failed to listen on {address}."))?;
+    let address = listener.local_addr()?;
+    for entry in layout.files() {
+        eprintln!(
+            "http://{address}/{}/{}",
+            hex::encode(info_hash),
+            entry.path().display()
+        );
+    }
+    let mut server = StreamServer::new();
+    server.add(info_hash, layout, control.clone());
+    server.serve(listener).await
+}
+
+/// Writes the file at `path` to the standard output as its pieces are verified.
+async fn pipe(layout: &FileLayout, path: &str, control: &DownloadControl) -> anyhow::Result<()> {
+    let entry = layout
+        .find(path)
+        .with_context(|| format!("The torrent has no file {path}."))?;
+    let range = entry.offset()..entry.offset() + entry.length();
+    let piece_length = control.piece_length();
+    control.raise_priority(
+        range.start / piece_length..range.end.div_ceil(piece_length),
+        FilePriority::High.piece_priority(),
+    );
+
+    let mut stdout = tokio::io::stdout();
+    let mut reader = control.stream(range);
+    while let Some(chunk) = reader.next_chunk().await? {
+        stdout.write_all(&chunk).await?;
+    }
+    stdout.flush().await?;
+    Ok(())
+}
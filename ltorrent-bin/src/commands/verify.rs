@@ -0,0 +1,51 @@
+use std::path::Path;
+
+use anyhow::Context;
+
+use ltorrent::storage::verify::{verify, FileStatus};
+use ltorrent::torrent::Torrent;
+
+/// Checks the content of a torrent stored under `dir` against the hashes of its pieces.
+///
+/// The progress is printed to the standard error as pieces are checked, and the files that
+/// are missing, have the wrong size or are corrupt are listed at the end.
+///
+/// # Errors
+///
+/// This function will return an error if the torrent file or the content cannot be read, or
+/// if any piece fails the check.
+pub async fn invoke(path: impl AsRef<Path>, dir: impl AsRef<Path>) -> anyhow::Result<()> {
+    let torrent = Torrent::from_file(&path)
+        .await
+        .context("Failed to read torrent file.")?;
+    let n_pieces = torrent.n_pieces();
+
+    let mut n_good = 0;
+    let verification = verify(&torrent, &dir, |piece_i, good| {
+        n_good += usize::from(good);
+        eprint!("\rChecked: {}/{}  Good: {}", piece_i + 1, n_pieces, n_good);
+    })
+    .await;
+    eprintln!();
+    let verification = verification?;
+
+    for (path, status) in verification.problems() {
+        match status {
+            FileStatus::Missing => println!("Missing: {}", path.display()),
+            FileStatus::WrongSize { expected, actual } => println!(
+                "Wrong size: {} ({actual} bytes, expected {expected})",
+                path.display()
+            ),
+            FileStatus::Corrupt => println!("Corrupt: {}", path.display()),
+        }
+    }
+    anyhow::ensure!(
+        verification.is_complete(),
+        "{} of {} pieces failed the check.",
+        n_pieces - verification.n_good(),
+        n_pieces
+    );
+
+    println!("All {n_pieces} pieces of {} are good.", torrent.name());
+    Ok(())
+}
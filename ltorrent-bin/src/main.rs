@@ -24,6 +24,38 @@ async fn main() -> anyhow::Result<()> {
         Command::Handshake { torrent_path, peer_address } => {
             commands::peers::handshake(torrent_path, peer_address.as_str()).await?;
         }
+        Command::Download { output, strategy, allocation, resume, only, exclude, transfer, torrent_path } => {
+            let selection = commands::download::Selection { only, exclude };
+            let config = transfer.configuration().await?;
+            commands::download::torrent(output, torrent_path, strategy, allocation, resume, selection, config)
+                .await
+                .context("Failed to download torrent")?;
+        }
+        Command::DownloadPiece { output, torrent_path, piece_index } => {
+            commands::download::piece(output, torrent_path, piece_index)
+                .await
+                .context("Failed to download piece")?;
+        }
+        Command::Seed { super_seed, transfer, torrent_path, dir } => {
+            commands::seed::invoke(torrent_path, dir, super_seed, transfer.configuration().await?)
+                .await
+                .context("Failed to seed torrent")?;
+        }
+        Command::Stream { output, http, stdout, transfer, torrent_path } => {
+            let mode = match (http, stdout) {
+                (Some(address), _) => commands::stream::Mode::Http(address),
+                (None, Some(file)) => commands::stream::Mode::Stdout(file),
+                (None, None) => unreachable!("Clap requires one of the modes."),
+            };
+            commands::stream::invoke(torrent_path, output, mode, transfer.configuration().await?)
+                .await
+                .context("Failed to stream torrent")?;
+        }
+        Command::Verify { torrent_path, dir } => {
+            commands::verify::invoke(torrent_path, dir)
+                .await
+                .context("Failed to verify content")?;
+        }
     }
     Ok(())
 }
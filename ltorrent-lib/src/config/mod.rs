@@ -1,7 +1,31 @@
+use crate::download::connections::ConnectionLimits;
+use crate::download::engine::MAX_PEERS;
+use crate::download::smartban::DEFAULT_MAX_HASH_FAILURES;
+use crate::net::class::PeerClasses;
+use crate::net::filter::IpFilter;
+use crate::net::limit::{RateLimits, Rates};
+use crate::net::schedule::Schedule;
+
+/// The number of peers that are unchoked at once, unless configured otherwise.
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
 /// Represents the configuration settings for the application.
+#[derive(Debug, Clone)]
 pub struct Configuration {
     peer_id: String,
     port: u16,
+    upload_slots: usize,
+    global_limits: RateLimits,
+    global_limit: Rates,
+    alternative_limit: Rates,
+    schedule: Option<Schedule>,
+    torrent_limit: Rates,
+    peer_limit: Rates,
+    peer_classes: PeerClasses,
+    ip_filter: IpFilter,
+    max_hash_failures: usize,
+    connection_limits: ConnectionLimits,
+    max_peers: usize,
 }
 
 impl Configuration {
@@ -14,6 +38,144 @@ impl Configuration {
     pub fn port(&self) -> u16 {
         self.port
     }
+
+    /// Returns the number of peers that are unchoked, and can download from us, at once.
+    pub fn upload_slots(&self) -> usize {
+        self.upload_slots
+    }
+
+    /// Sets the number of peers that are unchoked at once.
+    pub fn with_upload_slots(mut self, upload_slots: usize) -> Self {
+        self.upload_slots = upload_slots;
+        self
+    }
+
+    /// Returns the limits of the bytes sent and received over every connection.
+    ///
+    /// The limits are shared by the clones of the configuration, so every download started
+    /// with it draws from the same buckets, and changing their rates applies to all of them.
+    pub fn global_limits(&self) -> &RateLimits {
+        &self.global_limits
+    }
+
+    /// Returns the normal global upload and download rates, in bytes per second.
+    pub fn global_limit(&self) -> Rates {
+        self.global_limit
+    }
+
+    /// Sets the global upload and download rates, in bytes per second.
+    pub fn with_global_limit(mut self, rates: Rates) -> Self {
+        self.global_limit = rates;
+        self.global_limits.set_rates(rates);
+        self
+    }
+
+    /// Returns the alternative global rates, in bytes per second, which are in use during
+    /// the windows of the schedule.
+    pub fn alternative_limit(&self) -> Rates {
+        self.alternative_limit
+    }
+
+    /// Sets the alternative global rates, in bytes per second.
+    pub fn with_alternative_limit(mut self, rates: Rates) -> Self {
+        self.alternative_limit = rates;
+        self
+    }
+
+    /// Returns the weekly schedule of the alternative rates, if they have one.
+    pub fn schedule(&self) -> Option<&Schedule> {
+        self.schedule.as_ref()
+    }
+
+    /// Sets the weekly schedule of the alternative rates, which a
+    /// [`LimitScheduler`](crate::net::schedule::LimitScheduler) follows.
+    pub fn with_schedule(mut self, schedule: Schedule) -> Self {
+        self.schedule = Some(schedule);
+        self
+    }
+
+    /// Returns the upload and download rates of each torrent, in bytes per second.
+    pub fn torrent_limit(&self) -> Rates {
+        self.torrent_limit
+    }
+
+    /// Sets the upload and download rates of each torrent, in bytes per second.
+    pub fn with_torrent_limit(mut self, rates: Rates) -> Self {
+        self.torrent_limit = rates;
+        self
+    }
+
+    /// Returns the upload and download rates of each peer, in bytes per second.
+    pub fn peer_limit(&self) -> Rates {
+        self.peer_limit
+    }
+
+    /// Sets the upload and download rates of each peer, in bytes per second.
+    pub fn with_peer_limit(mut self, rates: Rates) -> Self {
+        self.peer_limit = rates;
+        self
+    }
+
+    /// Returns the classes peers are sorted into by their IP address. By default, the peers
+    /// on local networks are not throttled.
+    pub fn peer_classes(&self) -> &PeerClasses {
+        &self.peer_classes
+    }
+
+    /// Sets the classes peers are sorted into by their IP address.
+    pub fn with_peer_classes(mut self, peer_classes: PeerClasses) -> Self {
+        self.peer_classes = peer_classes;
+        self
+    }
+
+    /// Returns the filter of the addresses that are never connected to, which the clones of
+    /// the configuration share.
+    pub fn ip_filter(&self) -> &IpFilter {
+        &self.ip_filter
+    }
+
+    /// Sets the filter of the addresses that are never connected to.
+    pub fn with_ip_filter(mut self, ip_filter: IpFilter) -> Self {
+        self.ip_filter = ip_filter;
+        self
+    }
+
+    /// Returns the number of pieces that fail their hash check a peer may send blocks of
+    /// before it is banned.
+    pub fn max_hash_failures(&self) -> usize {
+        self.max_hash_failures
+    }
+
+    /// Sets the number of pieces that fail their hash check a peer may send blocks of before
+    /// it is banned. With 0, peers are only banned for the corrupt blocks found once the
+    /// pieces pass.
+    pub fn with_max_hash_failures(mut self, max_hash_failures: usize) -> Self {
+        self.max_hash_failures = max_hash_failures;
+        self
+    }
+
+    /// Returns the limits on the connections of every download, which the clones of the
+    /// configuration share.
+    pub fn connection_limits(&self) -> &ConnectionLimits {
+        &self.connection_limits
+    }
+
+    /// Sets the limits on the connections of every download.
+    pub fn with_connection_limits(mut self, connection_limits: ConnectionLimits) -> Self {
+        self.connection_limits = connection_limits;
+        self
+    }
+
+    /// Returns the number of peers a download connects to at most.
+    pub fn max_peers(&self) -> usize {
+        self.max_peers
+    }
+
+    /// Sets the number of peers a download connects to at most.
+    pub fn with_max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = max_peers;
+        self
+    }
 }
 
 impl Default for Configuration {
@@ -21,6 +183,18 @@ impl Default for Configuration {
         Configuration {
             peer_id: "00112233445566778899".to_string(),
             port: 6881,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+            global_limits: RateLimits::default(),
+            global_limit: Rates::UNLIMITED,
+            alternative_limit: Rates::UNLIMITED,
+            schedule: None,
+            torrent_limit: Rates::UNLIMITED,
+            peer_limit: Rates::UNLIMITED,
+            peer_classes: PeerClasses::default(),
+            ip_filter: IpFilter::default(),
+            max_hash_failures: DEFAULT_MAX_HASH_FAILURES,
+            connection_limits: ConnectionLimits::default(),
+            max_peers: MAX_PEERS,
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,270 @@
+use std::fmt::Debug;
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::time::Instant;
+
+/// Interval between the regular rounds of the choker.
+pub const UNCHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interval after which the optimistic unchoke moves on to another peer.
+pub const OPTIMISTIC_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time for which a peer counts as new, and is more likely to be unchoked optimistically.
+pub const NEW_PEER_TIME: Duration = Duration::from_secs(60);
+
+/// How much more likely a new peer is to be unchoked optimistically than the others.
+const NEW_PEER_WEIGHT: usize = 3;
+
+/// What the choker knows of a connected peer.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerStats {
+    /// The socket address of the peer.
+    pub address: SocketAddrV4,
+    /// Whether the peer is interested in our pieces.
+    pub interested: bool,
+    /// Bytes per second the peer sends us.
+    pub download_rate: f64,
+    /// Bytes per second we send the peer.
+    pub upload_rate: f64,
+    /// Time since the peer connected.
+    pub connected: Duration,
+    /// Priority of the peer, from its peer class. Higher is more important.
+    pub priority: u8,
+}
+
+/// An algorithm that decides which peers we upload to.
+///
+/// The choker is asked every [`UNCHOKE_INTERVAL`], whenever an unchoked peer changes its
+/// interest or a peer disconnects, and within a second of a choked peer changing its
+/// interest. The peers it returns are unchoked, and every other peer is choked.
+pub trait Choker: Debug + Send {
+    /// Chooses the peers to unchoke among `peers`. `seeding` is whether we have every piece
+    /// we want, in which case the download rates of the peers no longer mean anything.
+    fn choose(&mut self, peers: &[PeerStats], seeding: bool, now: Instant) -> Vec<SocketAddrV4>;
+}
+
+/// The choker of the reference client: tit-for-tat, with an optimistic unchoke.
+///
+/// All but one of the `slots` go to the interested peers that send us the most, so that
+/// peers that upload to us get uploaded to in return. Once we are seeding, they go to the
+/// peers we upload the fastest to instead, which spreads the content the quickest. Peers
+/// with a higher priority come first either way.
+///
+/// The last slot is the optimistic unchoke. It goes to another interested peer at random,
+/// and moves on every [`OPTIMISTIC_INTERVAL`], so that we find peers that would upload to us
+/// faster than the current ones, and new peers get their first pieces. Peers that connected
+/// less than [`NEW_PEER_TIME`] ago are three times as likely to get it.
+#[derive(Debug)]
+pub struct TitForTat {
+    slots: usize,
+    optimistic: Option<SocketAddrV4>,
+    rotated_at: Option<Instant>,
+}
+
+impl TitForTat {
+    /// Creates a choker that unchokes up to `slots` peers at once.
+    pub fn new(slots: usize) -> Self {
+        Self {
+            slots,
+            optimistic: None,
+            rotated_at: None,
+        }
+    }
+
+    /// Returns the optimistic unchoke, picking another one if it is due.
+    fn optimistic(&mut self, candidates: &[&PeerStats], now: Instant) -> Option<SocketAddrV4> {
+        let current = self
+            .optimistic
+            .filter(|address| candidates.iter().any(|peer| peer.address == *address));
+        let due = self
+            .rotated_at
+            .is_none_or(|rotated_at| now.duration_since(rotated_at) >= OPTIMISTIC_INTERVAL);
+        if current.is_some() && !due {
+            return current;
+        }
+
+        // The current optimistic unchoke only keeps its slot if nobody else can take it.
+        let others = candidates
+            .iter()
+            .filter(|peer| Some(peer.address) != current)
+            .collect::<Vec<_>>();
+        let weight = |peer: &PeerStats| {
+            if peer.connected < NEW_PEER_TIME {
+                NEW_PEER_WEIGHT
+            } else {
+                1
+            }
+        };
+        let total = others.iter().map(|peer| weight(peer)).sum::<usize>();
+        let mut next = current;
+        if total > 0 {
+            let mut point = rand::thread_rng().gen_range(0..total);
+            for peer in others {
+                if point < weight(peer) {
+                    next = Some(peer.address);
+                    break;
+                }
+                point -= weight(peer);
+            }
+        }
+        self.optimistic = next;
+        self.rotated_at = Some(now);
+        next
+    }
+}
+
+impl Choker for TitForTat {
+    fn choose(&mut self, peers: &[PeerStats], seeding: bool, now: Instant) -> Vec<SocketAddrV4> {
+        if self.slots == 0 {
+            return Vec::new();
+        }
+        let rate = |peer: &PeerStats| {
+            if seeding {
+                peer.upload_rate
+            } else {
+                peer.download_rate
+            }
+        };
+        let mut interested = peers
+            .iter()
+            .filter(|peer| peer.interested)
+            .collect::<Vec<_>>();
+        interested.sort_by(|a, b| {
+            b.priority
+                .cmp(&a.priority)
+                .then_with(|| rate(b).total_cmp(&rate(a)))
+        });
+
+        let regular = interested.len().min(self.slots - 1);
+        let mut unchoked = interested[..regular]
+            .iter()
+            .map(|peer| peer.address)
+            .collect::<Vec<_>>();
+        unchoked.extend(self.optimistic(&interested[regular..], now));
+        unchoked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16, download_rate: f64, upload_rate: f64) -> PeerStats {
+        PeerStats {
+            address: SocketAddrV4::new([127, 0, 0, 1].into(), port),
+            interested: true,
+            download_rate,
+            upload_rate,
+            connected: NEW_PEER_TIME * 2,
+            priority: 0,
+        }
+    }
+
+    /// Returns six peers, where the higher ports send us more and get sent less, and the
+    /// last one is not interested.
+    fn peers() -> Vec<PeerStats> {
+        let mut peers = (1..=6)
+            .map(|port| peer(port, port as f64, 10.0 - port as f64))
+            .collect::<Vec<_>>();
+        peers[5].interested = false;
+        peers
+    }
+
+    #[test]
+    fn test_unchokes_fastest_interested_peers() {
+        let peers = peers();
+        let mut choker = TitForTat::new(3);
+
+        // Peers 5 and 4 send the most. Peer 6 is the fastest, but is not interested.
+        let unchoked = choker.choose(&peers, false, Instant::now());
+        assert_eq!(unchoked.len(), 3);
+        assert_eq!(unchoked[..2], [peers[4].address, peers[3].address]);
+        assert!(peers[..3].iter().any(|peer| peer.address == unchoked[2]));
+    }
+
+    #[test]
+    fn test_rotates_optimistic_unchoke() {
+        let peers = peers();
+        let mut choker = TitForTat::new(3);
+        let now = Instant::now();
+        let optimistic = choker.choose(&peers, false, now)[2];
+
+        // The optimistic unchoke stays until its interval is over, and then moves on.
+        let unchoked = choker.choose(&peers, false, now + UNCHOKE_INTERVAL);
+        assert_eq!(unchoked[2], optimistic);
+        let unchoked = choker.choose(&peers, false, now + OPTIMISTIC_INTERVAL);
+        assert_ne!(unchoked[2], optimistic);
+    }
+
+    #[test]
+    fn test_keeps_optimistic_unchoke_without_other_candidates() {
+        let peers = peers();
+        let mut choker = TitForTat::new(5);
+        let now = Instant::now();
+
+        // Four regular slots leave a single candidate for the optimistic unchoke.
+        let unchoked = choker.choose(&peers, false, now);
+        assert_eq!(unchoked[4], peers[0].address);
+        let unchoked = choker.choose(&peers, false, now + OPTIMISTIC_INTERVAL);
+        assert_eq!(unchoked[4], peers[0].address);
+
+        // A slot the interested peers cannot fill stays empty.
+        let unchoked = TitForTat::new(10).choose(&peers, false, now);
+        assert_eq!(unchoked.len(), 5);
+    }
+
+    #[test]
+    fn test_unchokes_fastest_uploads_when_seeding() {
+        let peers = peers();
+        let mut choker = TitForTat::new(3);
+
+        let unchoked = choker.choose(&peers, true, Instant::now());
+        assert_eq!(unchoked.len(), 3);
+        assert_eq!(unchoked[..2], [peers[0].address, peers[1].address]);
+    }
+
+    #[test]
+    fn test_priority_wins_regular_slots() {
+        let mut peers = peers();
+        peers[2].priority = 1;
+        let mut choker = TitForTat::new(3);
+
+        let unchoked = choker.choose(&peers, true, Instant::now());
+        assert_eq!(unchoked[..2], [peers[2].address, peers[0].address]);
+        let unchoked = choker.choose(&peers, false, Instant::now());
+        assert_eq!(unchoked[..2], [peers[2].address, peers[4].address]);
+    }
+
+    #[test]
+    fn test_single_slot_is_the_optimistic_unchoke() {
+        let peers = peers();
+        let mut choker = TitForTat::new(1);
+        let now = Instant::now();
+
+        // The only slot goes to any interested peer, not the fastest one.
+        let unchoked = choker.choose(&peers, false, now);
+        assert_eq!(unchoked.len(), 1);
+        assert_ne!(unchoked[0], peers[5].address);
+        let unchoked_again = choker.choose(&peers, false, now + UNCHOKE_INTERVAL);
+        assert_eq!(unchoked_again, unchoked);
+        let rotated = choker.choose(&peers, false, now + OPTIMISTIC_INTERVAL);
+        assert_ne!(rotated, unchoked);
+
+        let uninterested = peers
+            .into_iter()
+            .map(|peer| PeerStats {
+                interested: false,
+                ..peer
+            })
+            .collect::<Vec<_>>();
+        assert!(choker.choose(&uninterested, false, now).is_empty());
+    }
+
+    #[test]
+    fn test_zero_slots_unchoke_nobody() {
+        let mut choker = TitForTat::new(0);
+        assert!(choker.choose(&peers(), false, Instant::now()).is_empty());
+    }
+}
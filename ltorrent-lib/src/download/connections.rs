@@ -0,0 +1,600 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, watch, OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+
+use crate::net::bitfield::BitField;
+use crate::net::filter::IpFilter;
+use crate::net::peers::{Peer, PeerSource};
+
+use super::engine::CONNECT_TIMEOUT;
+
+/// The number of connections being set up at once across every download, unless configured
+/// otherwise.
+pub const DEFAULT_MAX_HALF_OPEN: usize = 8;
+
+/// The number of connections across every download, unless configured otherwise.
+pub const DEFAULT_MAX_CONNECTIONS: usize = 200;
+
+/// Number of times a peer is connected to before it is given up on.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Time before a peer is connected to again after its first failure, which doubles with
+/// every failure after it.
+const RETRY_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Interval at which the candidates whose backoff is over are dialed.
+const DIAL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Limits on the connections of every download, shared by the clones of the configuration.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimits {
+    half_open: Arc<Semaphore>,
+    connections: Arc<Semaphore>,
+}
+
+impl ConnectionLimits {
+    /// Creates limits of `max_half_open` connections being set up at once, and
+    /// `max_connections` connections in total.
+    pub fn new(max_half_open: usize, max_connections: usize) -> Self {
+        Self {
+            half_open: Arc::new(Semaphore::new(max_half_open)),
+            connections: Arc::new(Semaphore::new(max_connections)),
+        }
+    }
+}
+
+impl Default for ConnectionLimits {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_HALF_OPEN, DEFAULT_MAX_CONNECTIONS)
+    }
+}
+
+/// Commands sent to a running connection manager.
+enum Command {
+    Add(Vec<SocketAddrV4>, PeerSource),
+    Incoming(TcpStream, SocketAddrV4),
+    Disconnected(SocketAddrV4),
+    /// A connection attempt ended, with the peer if it succeeded.
+    Done {
+        address: SocketAddrV4,
+        source: PeerSource,
+        peer: Option<Box<Peer<TcpStream>>>,
+        permit: OwnedSemaphorePermit,
+    },
+}
+
+/// A handle to give peers to a running [`ConnectionManager`].
+#[derive(Clone)]
+pub struct ConnectionHandle {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl ConnectionHandle {
+    /// Adds candidate addresses to connect to, found by `source`. Addresses that are already
+    /// known are ignored.
+    pub fn add_peers(&self, addresses: impl IntoIterator<Item = SocketAddrV4>, source: PeerSource) {
+        let addresses = addresses.into_iter().collect();
+        let _ = self.commands.send(Command::Add(addresses, source));
+    }
+
+    /// Hands over a connection that a peer opened to us, to be handshaken unless the limits
+    /// are reached.
+    pub fn incoming(&self, stream: TcpStream, address: SocketAddrV4) {
+        let _ = self.commands.send(Command::Incoming(stream, address));
+    }
+
+    /// Tells the manager that a peer it connected to has disconnected, which frees its slot.
+    pub fn disconnected(&self, address: SocketAddrV4) {
+        let _ = self.commands.send(Command::Disconnected(address));
+    }
+
+    /// Returns whether the manager has stopped.
+    pub fn is_closed(&self) -> bool {
+        self.commands.is_closed()
+    }
+}
+
+/// The state of a candidate address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting to be connected to, once its backoff is over.
+    Idle {
+        retry_at: Instant,
+    },
+    Connecting,
+    Connected,
+}
+
+#[derive(Debug)]
+struct Candidate {
+    source: PeerSource,
+    state: State,
+    /// Number of connections to the peer that failed or ended.
+    attempts: u32,
+}
+
+/// Decides which peers a download connects to, and connects to them.
+///
+/// The manager keeps the candidate addresses of the peers, from any [`PeerSource`], without
+/// duplicates, and dials them while there is room: the connections being set up and the
+/// connections in total are capped across every download by [`ConnectionLimits`], and the
+/// connections of the download by a limit of its own. Connections that peers open to us
+/// count against the same limits. A peer that fails to connect is dialed again after a
+/// backoff that doubles every time, and given up on after five attempts, like a peer that
+/// turns out to be ourselves. Addresses the [`IpFilter`] blocks are neither dialed nor
+/// accepted.
+///
+/// The connected peers are sent to the download, which reports their disconnection through
+/// a [`ConnectionHandle`]. The manager stops once the download stops receiving peers, or
+/// when it has no peer left to connect to and does not accept connections.
+pub struct ConnectionManager {
+    peer_id: [u8; 20],
+    info_hash: [u8; 20],
+    have: watch::Receiver<BitField>,
+    limits: ConnectionLimits,
+    max_connections: usize,
+    filter: IpFilter,
+    accepting: bool,
+    candidates: HashMap<SocketAddrV4, Candidate>,
+    /// Addresses that lead to ourselves.
+    own: HashSet<SocketAddrV4>,
+    /// The connections that are set up, with their permit of the global limit.
+    connections: HashMap<SocketAddrV4, OwnedSemaphorePermit>,
+    /// Number of connections being set up.
+    pending: usize,
+    peers: mpsc::Sender<Peer<TcpStream>>,
+    commands_tx: mpsc::UnboundedSender<Command>,
+    commands_rx: mpsc::UnboundedReceiver<Command>,
+}
+
+impl ConnectionManager {
+    /// Creates a manager that connects to the peers of the torrent with `info_hash`, sends
+    /// them the pieces in `have` and hands them over on `peers`, with at most
+    /// `max_connections` of them at once.
+    pub fn new(
+        peer_id: [u8; 20],
+        info_hash: [u8; 20],
+        have: watch::Receiver<BitField>,
+        limits: ConnectionLimits,
+        max_connections: usize,
+        peers: mpsc::Sender<Peer<TcpStream>>,
+    ) -> Self {
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        Self {
+            peer_id,
+            info_hash,
+            have,
+            limits,
+            max_connections,
+            filter: IpFilter::default(),
+            accepting: false,
+            candidates: HashMap::new(),
+            own: HashSet::new(),
+            connections: HashMap::new(),
+            pending: 0,
+            peers,
+            commands_tx,
+            commands_rx,
+        }
+    }
+
+    /// Never dials the addresses we listen on, on `port`, and keeps running while there are
+    /// no peers to connect to, since peers may connect to us.
+    pub fn with_listen_port(mut self, port: u16) -> Self {
+        self.own
+            .insert(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port));
+        self.own
+            .insert(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
+        self.accepting = true;
+        self
+    }
+
+    /// Never connects to the addresses `filter` blocks.
+    pub fn with_ip_filter(mut self, filter: IpFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Returns a handle to give peers to the manager.
+    pub fn handle(&self) -> ConnectionHandle {
+        ConnectionHandle {
+            commands: self.commands_tx.clone(),
+        }
+    }
+
+    /// Connects to the candidates until the download stops receiving peers, or there are no
+    /// peers left.
+    pub async fn run(mut self) {
+        let mut ticker = tokio::time::interval(DIAL_INTERVAL);
+        loop {
+            self.dial();
+            tokio::select! {
+                biased;
+                Some(command) = self.commands_rx.recv() => match command {
+                    Command::Add(addresses, source) => self.add(addresses, source),
+                    Command::Incoming(stream, address) => self.accept(stream, address),
+                    Command::Disconnected(address) => self.on_disconnected(address),
+                    Command::Done { address, source, peer, permit } => {
+                        self.pending -= 1;
+                        if let Some(peer) = peer {
+                            self.on_connected(address, *peer, source, permit).await;
+                        } else if source != PeerSource::Incoming {
+                            self.on_failed(address);
+                        }
+                    }
+                },
+                _ = ticker.tick() => {}
+                _ = self.peers.closed() => return,
+            }
+            if !self.accepting
+                && self.candidates.is_empty()
+                && self.connections.is_empty()
+                && self.pending == 0
+            {
+                return;
+            }
+        }
+    }
+
+    fn add(&mut self, addresses: Vec<SocketAddrV4>, source: PeerSource) {
+        for address in addresses {
+            if self.own.contains(&address) || address.port() == 0 {
+                continue;
+            }
+            if !self.candidates.contains_key(&address) && !self.filter.allows(*address.ip()) {
+                continue;
+            }
+            self.candidates.entry(address).or_insert(Candidate {
+                source,
+                state: State::Idle {
+                    retry_at: Instant::now(),
+                },
+                attempts: 0,
+            });
+        }
+    }
+
+    /// Returns whether the download has room for one more connection.
+    fn has_room(&self) -> bool {
+        self.connections.len() + self.pending < self.max_connections
+    }
+
+    /// Connects to the candidates whose backoff is over, while the limits allow it.
+    fn dial(&mut self) {
+        // The filter may have been reloaded since the candidates were added.
+        let filter = &self.filter;
+        self.candidates.retain(|address, candidate| {
+            !matches!(candidate.state, State::Idle { .. }) || filter.allows(*address.ip())
+        });
+        let now = Instant::now();
+        let mut ready = self
+            .candidates
+            .iter()
+            .filter(|(_, candidate)| {
+                matches!(candidate.state, State::Idle { retry_at } if retry_at <= now)
+            })
+            .map(|(&address, candidate)| (candidate.attempts, address))
+            .collect::<Vec<_>>();
+        // The peers that failed the least are the likeliest to answer.
+        ready.sort_unstable();
+        for (_, address) in ready {
+            if !self.has_room() {
+                return;
+            }
+            let Ok(half_open) = Arc::clone(&self.limits.half_open).try_acquire_owned() else {
+                return;
+            };
+            let Ok(permit) = Arc::clone(&self.limits.connections).try_acquire_owned() else {
+                return;
+            };
+            let candidate = self
+                .candidates
+                .get_mut(&address)
+                .expect("Candidate is ready.");
+            candidate.state = State::Connecting;
+            self.pending += 1;
+
+            let (peer_id, info_hash) = (self.peer_id, self.info_hash);
+            let bitfield = self.have.borrow().clone();
+            let source = candidate.source;
+            let commands = self.commands_tx.clone();
+            tokio::spawn(async move {
+                let connect = Peer::<TcpStream>::new(address, peer_id, info_hash, &bitfield);
+                let peer = tokio::time::timeout(CONNECT_TIMEOUT, connect).await;
+                drop(half_open);
+                let peer = peer.ok().and_then(Result::ok).map(Box::new);
+                let _ = commands.send(Command::Done {
+                    address,
+                    source,
+                    peer,
+                    permit,
+                });
+            });
+        }
+    }
+
+    /// Handshakes with a peer that connected to us, unless the limits are reached.
+    fn accept(&mut self, stream: TcpStream, address: SocketAddrV4) {
+        if !self.filter.allows(*address.ip()) {
+            return;
+        }
+        if !self.has_room() || self.connections.contains_key(&address) {
+            return;
+        }
+        let Ok(permit) = Arc::clone(&self.limits.connections).try_acquire_owned() else {
+            return;
+        };
+        self.pending += 1;
+        let (peer_id, info_hash) = (self.peer_id, self.info_hash);
+        let bitfield = self.have.borrow().clone();
+        let commands = self.commands_tx.clone();
+        tokio::spawn(async move {
+            let handshake = Peer::handshake(address, stream, peer_id, info_hash, &bitfield);
+            let peer = tokio::time::timeout(CONNECT_TIMEOUT, handshake).await;
+            let peer = peer.ok().and_then(Result::ok).map(Box::new);
+            let _ = commands.send(Command::Done {
+                address,
+                source: PeerSource::Incoming,
+                peer,
+                permit,
+            });
+        });
+    }
+
+    async fn on_connected(
+        &mut self,
+        address: SocketAddrV4,
+        mut peer: Peer<TcpStream>,
+        source: PeerSource,
+        permit: OwnedSemaphorePermit,
+    ) {
+        if *peer.peer_id() == self.peer_id {
+            self.candidates.remove(&address);
+            self.own.insert(address);
+            return;
+        }
+        if let Some(candidate) = self.candidates.get_mut(&address) {
+            candidate.state = State::Connected;
+        }
+        peer.set_source(source);
+        self.connections.insert(address, permit);
+        if self.peers.send(peer).await.is_err() {
+            self.connections.remove(&address);
+        }
+    }
+
+    fn on_failed(&mut self, address: SocketAddrV4) {
+        let Some(candidate) = self.candidates.get_mut(&address) else {
+            return;
+        };
+        candidate.attempts += 1;
+        if candidate.attempts >= MAX_ATTEMPTS {
+            self.candidates.remove(&address);
+            return;
+        }
+        let backoff = RETRY_BACKOFF * 2u32.pow(candidate.attempts - 1);
+        candidate.state = State::Idle {
+            retry_at: Instant::now() + backoff,
+        };
+    }
+
+    fn on_disconnected(&mut self, address: SocketAddrV4) {
+        if self.connections.remove(&address).is_some() {
+            self.on_failed(address);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::net::message::{Message, MessageFramer, MessageTag};
+
+    /// Listens for peers that answer the handshake with `peer_id`, and counts the connections.
+    async fn listen(peer_id: [u8; 20]) -> (SocketAddrV4, watch::Receiver<usize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let std::net::SocketAddr::V4(address) = listener.local_addr().unwrap() else {
+            unreachable!("Bound to an IPv4 address.");
+        };
+        let (count_tx, count_rx) = watch::channel(0);
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                count_tx.send_modify(|count| *count += 1);
+                tokio::spawn(async move {
+                    let mut handshake = [0u8; 68];
+                    stream.read_exact(&mut handshake).await.unwrap();
+                    handshake[48..].copy_from_slice(&peer_id);
+                    stream.write_all(&handshake).await.unwrap();
+                    let unchoke = Message::without_payload(MessageTag::UnChoke).unwrap();
+                    let mut framed = Framed::new(stream, MessageFramer);
+                    framed.send(unchoke).await.unwrap();
+                    while framed.next().await.is_some() {}
+                });
+            }
+        });
+        (address, count_rx)
+    }
+
+    /// Creates a manager of a download of `max_connections` peers, and the channel it sends
+    /// them on.
+    fn manager(
+        peer_id: [u8; 20],
+        limits: ConnectionLimits,
+        max_connections: usize,
+    ) -> (ConnectionManager, mpsc::Receiver<Peer<TcpStream>>) {
+        let (peers_tx, peers_rx) = mpsc::channel(8);
+        let have = watch::channel(BitField::new(0)).1;
+        let manager =
+            ConnectionManager::new(peer_id, [9; 20], have, limits, max_connections, peers_tx);
+        (manager, peers_rx)
+    }
+
+    #[tokio::test]
+    async fn test_connects_once_per_peer_within_limits() {
+        let (first, first_count) = listen([1; 20]).await;
+        let (second, _) = listen([2; 20]).await;
+        let (third, _) = listen([3; 20]).await;
+
+        let (manager, mut peers_rx) = manager([7; 20], ConnectionLimits::new(1, 10), 2);
+        let handle = manager.handle();
+        handle.add_peers([first, first], PeerSource::Tracker);
+        handle.add_peers([first, second, third], PeerSource::Pex);
+        tokio::spawn(manager.run());
+
+        // Only two peers fit the download.
+        let a = peers_rx.recv().await.unwrap();
+        let b = peers_rx.recv().await.unwrap();
+        assert_ne!(a.address(), b.address());
+        assert!(
+            tokio::time::timeout(Duration::from_millis(300), peers_rx.recv())
+                .await
+                .is_err()
+        );
+
+        // A disconnection frees a slot for the last peer.
+        handle.disconnected(a.address());
+        let c = peers_rx.recv().await.unwrap();
+        assert!(![a.address(), b.address()].contains(&c.address()));
+        assert_eq!(*first_count.borrow(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_keeps_the_first_source_of_a_peer() {
+        let (first, first_count) = listen([1; 20]).await;
+        let (second, _) = listen([2; 20]).await;
+
+        let (manager, mut peers_rx) = manager([7; 20], ConnectionLimits::default(), 3);
+        let handle = manager.handle();
+        handle.add_peers([first], PeerSource::Tracker);
+        handle.add_peers([first, second], PeerSource::Pex);
+        tokio::spawn(manager.run());
+
+        let mut peers = [
+            peers_rx.recv().await.unwrap(),
+            peers_rx.recv().await.unwrap(),
+        ];
+        peers.sort_by_key(|peer| peer.address() != first);
+        assert_eq!(peers[0].address(), first);
+        assert_eq!(peers[0].source(), PeerSource::Tracker);
+        assert_eq!(peers[1].address(), second);
+        assert_eq!(peers[1].source(), PeerSource::Pex);
+        assert_eq!(*first_count.borrow(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_drops_connections_to_ourselves() {
+        let our_id = [7; 20];
+        let (ourselves, own_count) = listen(our_id).await;
+
+        let (manager, mut peers_rx) = manager(our_id, ConnectionLimits::default(), 2);
+        manager.handle().add_peers([ourselves], PeerSource::Tracker);
+        let run = tokio::spawn(manager.run());
+
+        // With ourselves as the only candidate, the manager is left with nothing to do.
+        tokio::time::timeout(Duration::from_secs(5), run)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(peers_rx.recv().await.is_none());
+        assert_eq!(*own_count.borrow(), 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backs_off_exponentially_after_failures() {
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1);
+        let (mut manager, _peers_rx) = manager([7; 20], ConnectionLimits::default(), 2);
+        manager.add(vec![address], PeerSource::Tracker);
+
+        for attempts in 1..MAX_ATTEMPTS {
+            manager.on_failed(address);
+            let candidate = &manager.candidates[&address];
+            assert_eq!(candidate.attempts, attempts);
+            let backoff = RETRY_BACKOFF * 2u32.pow(attempts - 1);
+            assert_eq!(
+                candidate.state,
+                State::Idle {
+                    retry_at: Instant::now() + backoff
+                }
+            );
+        }
+        assert_eq!(
+            manager.candidates[&address].state,
+            State::Idle {
+                retry_at: Instant::now() + Duration::from_secs(40)
+            }
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dials_only_once_backoff_is_over() {
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1);
+        let (mut manager, _peers_rx) = manager([7; 20], ConnectionLimits::default(), 2);
+        manager.add(vec![address], PeerSource::Tracker);
+        manager.on_failed(address);
+
+        manager.dial();
+        assert!(matches!(
+            manager.candidates[&address].state,
+            State::Idle { .. }
+        ));
+        assert_eq!(manager.pending, 0);
+
+        tokio::time::advance(RETRY_BACKOFF).await;
+        manager.dial();
+        assert_eq!(manager.candidates[&address].state, State::Connecting);
+        assert_eq!(manager.pending, 1);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gives_up_after_max_attempts() {
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1);
+        let (mut manager, mut peers_rx) = manager([7; 20], ConnectionLimits::default(), 2);
+        manager.add(vec![address], PeerSource::Tracker);
+        for _ in 0..MAX_ATTEMPTS {
+            manager.on_failed(address);
+        }
+        assert!(manager.candidates.is_empty());
+
+        // A peer given up on is a new candidate if it is found again, and a manager without
+        // candidates that does not accept connections stops.
+        manager.add(vec![address], PeerSource::Pex);
+        assert_eq!(manager.candidates[&address].attempts, 0);
+        manager.candidates.clear();
+        manager.run().await;
+        assert!(peers_rx.recv().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_counts_disconnections_as_failures() {
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1);
+        let (mut manager, _peers_rx) = manager([7; 20], ConnectionLimits::default(), 2);
+        manager.add(vec![address], PeerSource::Tracker);
+        let permit = Arc::clone(&manager.limits.connections)
+            .try_acquire_owned()
+            .unwrap();
+        manager.candidates.get_mut(&address).unwrap().state = State::Connected;
+        manager.connections.insert(address, permit);
+
+        manager.on_disconnected(address);
+        assert!(manager.connections.is_empty());
+        assert_eq!(manager.candidates[&address].attempts, 1);
+        assert_eq!(
+            manager.limits.connections.available_permits(),
+            DEFAULT_MAX_CONNECTIONS
+        );
+
+        // A peer that is not connected has nothing to free.
+        manager.on_disconnected(address);
+        assert_eq!(manager.candidates[&address].attempts, 1);
+    }
+}
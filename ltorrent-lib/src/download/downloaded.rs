@@ -0,0 +1,265 @@
+use std::fmt;
+
+use sha1::{Digest, Sha1};
+
+use crate::net::block::{Block, BLOCK_SIZE};
+use crate::piece::hasher::HashPool;
+
+/// A piece being put together from the blocks downloaded from peers.
+///
+/// Blocks can arrive in any order. Once every block has arrived, the piece is checked against
+/// the SHA1 hash of the .torrent file before its data is handed out.
+///
+/// The piece is hashed incrementally: every block that arrives right after the blocks hashed
+/// so far is hashed on arrival. When blocks arrive in order, the hash is ready as soon as the
+/// last block lands, and only the blocks from the first one that arrived out of order are
+/// left to hash then, which [`DownloadedPiece::verify_on`] does on a [`HashPool`].
+#[derive(Debug)]
+pub struct DownloadedPiece {
+    piece_i: usize,
+    data: Vec<u8>,
+    received: Vec<bool>,
+    remaining: usize,
+    hasher: Sha1,
+    /// Number of leading blocks fed to `hasher`.
+    hashed: usize,
+}
+
+impl DownloadedPiece {
+    /// Creates an empty piece of `length` bytes.
+    pub fn new(piece_i: usize, length: usize) -> Self {
+        let n_blocks = length.div_ceil(BLOCK_SIZE);
+        Self {
+            piece_i,
+            data: vec![0; length],
+            received: vec![false; n_blocks],
+            remaining: n_blocks,
+            hasher: Sha1::new(),
+            hashed: 0,
+        }
+    }
+
+    /// Returns the index of the piece.
+    pub fn piece_i(&self) -> usize {
+        self.piece_i
+    }
+
+    /// Returns the length of the piece in bytes.
+    pub fn length(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns whether every block of the piece has arrived.
+    pub fn is_complete(&self) -> bool {
+        self.remaining == 0
+    }
+
+    /// Returns whether every block of the piece has been hashed.
+    pub fn is_hashed(&self) -> bool {
+        self.hashed == self.received.len()
+    }
+
+    /// Returns the offset and data of every block that has arrived, in order.
+    pub fn received_blocks(&self) -> impl Iterator<Item = (usize, &[u8])> {
+        self.data
+            .chunks(BLOCK_SIZE)
+            .zip(&self.received)
+            .enumerate()
+            .filter(|(_, (_, &received))| received)
+            .map(|(block_i, (data, _))| (block_i * BLOCK_SIZE, data))
+    }
+
+    /// Copies a block into the piece.
+    ///
+    /// Returns `false` if the block had already been added.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the block belongs to another piece, does not start at a block
+    /// boundary, or does not fit in the piece.
+    pub fn add_block(&mut self, block: &Block) -> anyhow::Result<bool> {
+        anyhow::ensure!(
+            block.piece_i() == self.piece_i,
+            "Block of piece {} added to piece {}.",
+            block.piece_i(),
+            self.piece_i
+        );
+        let begin = block.begin();
+        let end = begin + block.data().len();
+        anyhow::ensure!(
+            begin.is_multiple_of(BLOCK_SIZE) && end <= self.data.len(),
+            "Block at {}..{} does not fit in piece {} of {} bytes.",
+            begin,
+            end,
+            self.piece_i,
+            self.data.len()
+        );
+        let block_i = begin / BLOCK_SIZE;
+        if self.received[block_i] {
+            return Ok(false);
+        }
+
+        self.data[begin..end].copy_from_slice(block.data());
+        self.received[block_i] = true;
+        self.remaining -= 1;
+        if block_i == self.hashed {
+            self.hasher.update(block.data());
+            self.hashed += 1;
+        }
+        Ok(true)
+    }
+
+    /// Checks the complete piece against its SHA1 hash and returns its data.
+    ///
+    /// The blocks that have not been hashed yet are hashed on the current thread.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if some blocks are missing, or a [`HashMismatch`] with the data of the
+    /// piece if the hash does not match.
+    pub fn verify(self, hash: &[u8; 20]) -> anyhow::Result<Vec<u8>> {
+        anyhow::ensure!(
+            self.is_complete(),
+            "Piece {} is missing {} blocks.",
+            self.piece_i,
+            self.remaining
+        );
+        let mut hasher = self.hasher;
+        hasher.update(&self.data[(self.hashed * BLOCK_SIZE).min(self.data.len())..]);
+        let actual: [u8; 20] = hasher.finalize().into();
+        if actual != *hash {
+            return Err(HashMismatch {
+                piece_i: self.piece_i,
+                expected: *hash,
+                actual,
+                data: self.data,
+            }
+            .into());
+        }
+        Ok(self.data)
+    }
+
+    /// Checks the complete piece against its SHA1 hash like [`DownloadedPiece::verify`], but
+    /// hashes the blocks that are left and finalizes the hash on a thread of `pool`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if some blocks are missing, or a [`HashMismatch`] if the hash does not
+    /// match.
+    pub async fn verify_on(self, pool: &HashPool, hash: &[u8; 20]) -> anyhow::Result<Vec<u8>> {
+        let hash = *hash;
+        pool.run(move || self.verify(&hash)).await
+    }
+}
+
+/// The error of a piece that does not match its hash, which keeps the corrupt data so that
+/// the blocks that were wrong can be told apart once the piece is downloaded again.
+#[derive(Debug)]
+pub struct HashMismatch {
+    piece_i: usize,
+    expected: [u8; 20],
+    actual: [u8; 20],
+    data: Vec<u8>,
+}
+
+impl HashMismatch {
+    /// Returns the index of the piece.
+    pub fn piece_i(&self) -> usize {
+        self.piece_i
+    }
+
+    /// Returns the data of the piece, as it was downloaded.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl fmt::Display for HashMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Piece {} does not match its hash: expected {}, got {}.",
+            self.piece_i,
+            hex::encode(self.expected),
+            hex::encode(self.actual)
+        )
+    }
+}
+
+impl std::error::Error for HashMismatch {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_piece() {
+        let data = (0..BLOCK_SIZE + 100).map(|i| i as u8).collect::<Vec<_>>();
+        let hash: [u8; 20] = Sha1::digest(&data).into();
+
+        let mut piece = DownloadedPiece::new(0, data.len());
+        let last = Block::new(0, BLOCK_SIZE, data[BLOCK_SIZE..].to_vec());
+        assert!(piece.add_block(&last).unwrap());
+        assert!(!piece.add_block(&last).unwrap());
+        assert!(!piece.is_complete());
+        assert!(piece
+            .add_block(&Block::new(0, 1, data[1..10].to_vec()))
+            .is_err());
+
+        piece
+            .add_block(&Block::new(0, 0, data[..BLOCK_SIZE].to_vec()))
+            .unwrap();
+        assert_eq!(piece.verify(&hash).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_hashes_blocks_in_order_on_arrival() {
+        let data = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let hash: [u8; 20] = Sha1::digest(&data).into();
+        let mut piece = DownloadedPiece::new(0, data.len());
+        for begin in (0..data.len()).step_by(BLOCK_SIZE) {
+            piece
+                .add_block(&Block::new(0, begin, data[begin..][..BLOCK_SIZE].to_vec()))
+                .unwrap();
+        }
+        assert!(piece.is_hashed());
+        let pool = HashPool::new(1);
+        assert_eq!(piece.verify_on(&pool, &hash).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_leaves_blocks_after_a_gap_to_the_pool() {
+        let data = (0..2 * BLOCK_SIZE + 77)
+            .map(|i| i as u8)
+            .collect::<Vec<_>>();
+        let hash: [u8; 20] = Sha1::digest(&data).into();
+        let mut piece = DownloadedPiece::new(0, data.len());
+        // Filling the gap does not hash the blocks that arrived before it.
+        for begin in [0, 2 * BLOCK_SIZE, BLOCK_SIZE] {
+            let end = (begin + BLOCK_SIZE).min(data.len());
+            piece
+                .add_block(&Block::new(0, begin, data[begin..end].to_vec()))
+                .unwrap();
+        }
+        assert!(piece.is_complete() && !piece.is_hashed());
+        let pool = HashPool::new(1);
+        assert_eq!(piece.verify_on(&pool, &hash).await.unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn test_verify_on_rejects_incomplete_piece() {
+        let mut piece = DownloadedPiece::new(0, 2 * BLOCK_SIZE);
+        piece
+            .add_block(&Block::new(0, 0, vec![0; BLOCK_SIZE]))
+            .unwrap();
+        let pool = HashPool::new(1);
+        assert!(piece.verify_on(&pool, &[0; 20]).await.is_err());
+    }
+
+    #[test]
+    fn test_verify_rejects_corrupt_piece() {
+        let mut piece = DownloadedPiece::new(0, 10);
+        piece.add_block(&Block::new(0, 0, vec![1; 10])).unwrap();
+        assert!(piece.verify(&[0; 20]).is_err());
+    }
+}
@@ -0,0 +1,121 @@
+use std::net::Ipv4Addr;
+
+use crate::config::Configuration;
+use crate::net::limit::{RateLimits, Rates, Throttle};
+
+/// What a peer that joins the download gets from its peer class.
+pub(super) struct Admitted {
+    /// The bandwidth limits that the messages of the peer go through.
+    pub(super) throttle: Throttle,
+    /// The bandwidth limits of the peer alone, which are part of `throttle` unless its class
+    /// is unthrottled.
+    pub(super) limits: RateLimits,
+    /// Index of the peer class of the peer, if it belongs to one.
+    pub(super) class: Option<usize>,
+    /// Priority of the peer in the choker.
+    pub(super) priority: u8,
+}
+
+/// Decides which of the connected peers join the download, and with which limits.
+///
+/// Peers blocked by the IP filter of the configuration are refused, and so are the peers of
+/// a class that already has its maximum number of connections. The others go through the
+/// global, torrent and peer limits and those of their class, or only the limits of their
+/// class if it is unthrottled.
+pub(super) struct Admission<'a> {
+    config: &'a Configuration,
+    torrent_limits: &'a RateLimits,
+}
+
+impl<'a> Admission<'a> {
+    /// Creates the admission of the peers of a download limited by `torrent_limits`.
+    pub(super) fn new(config: &'a Configuration, torrent_limits: &'a RateLimits) -> Self {
+        Self {
+            config,
+            torrent_limits,
+        }
+    }
+
+    /// Admits a peer at `address`, giving it limits of `peer_limit` of its own, or returns
+    /// `None` if it is refused. `n_class_peers` returns the number of connected peers of a
+    /// class.
+    pub(super) fn admit(
+        &self,
+        address: Ipv4Addr,
+        peer_limit: Rates,
+        n_class_peers: impl Fn(usize) -> usize,
+    ) -> Option<Admitted> {
+        if !self.config.ip_filter().allows(address) {
+            return None;
+        }
+        let class = self.config.peer_classes().classify(address);
+        if let Some((class_i, class)) = class {
+            if class
+                .max_connections()
+                .is_some_and(|max| n_class_peers(class_i) >= max)
+            {
+                return None;
+            }
+        }
+
+        let limits = RateLimits::new(peer_limit);
+        let throttle = match class {
+            Some((_, class)) if class.is_unthrottled() => vec![class.limits().clone()],
+            _ => {
+                let mut throttle = vec![
+                    self.config.global_limits().clone(),
+                    self.torrent_limits.clone(),
+                    limits.clone(),
+                ];
+                throttle.extend(class.map(|(_, class)| class.limits().clone()));
+                throttle
+            }
+        };
+        Some(Admitted {
+            throttle: Throttle::new(throttle),
+            limits,
+            class: class.map(|(class_i, _)| class_i),
+            priority: class.map_or(0, |(_, class)| class.priority()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::net::class::{PeerClass, PeerClasses};
+    use crate::net::filter::IpFilter;
+
+    #[test]
+    fn test_refuses_blocked_peers_and_full_classes() {
+        let filter = IpFilter::new();
+        filter.ban([10, 1, 0, 9].into());
+        let office = PeerClass::new("office", vec!["10.1.0.0/16".parse().unwrap()])
+            .with_max_connections(2)
+            .with_priority(3);
+        let config = Configuration::default()
+            .with_ip_filter(filter)
+            .with_peer_classes(PeerClasses::default().with_class(office));
+        let torrent_limits = RateLimits::new(Rates::UNLIMITED);
+        let admission = Admission::new(&config, &torrent_limits);
+        let peer_limit = Rates::new(Some(1024), None);
+
+        let admitted = admission
+            .admit([10, 1, 0, 1].into(), peer_limit, |_| 1)
+            .unwrap();
+        assert_eq!((admitted.class, admitted.priority), (Some(0), 3));
+        assert_eq!(admitted.limits.rates(), peer_limit);
+        assert!(admission
+            .admit([10, 1, 0, 1].into(), peer_limit, |_| 2)
+            .is_none());
+        assert!(admission
+            .admit([10, 1, 0, 9].into(), peer_limit, |_| 0)
+            .is_none());
+
+        // Peers outside every class have no class limit to reach.
+        let admitted = admission
+            .admit([8, 8, 8, 8].into(), peer_limit, |_| usize::MAX)
+            .unwrap();
+        assert_eq!((admitted.class, admitted.priority), (None, 0));
+    }
+}
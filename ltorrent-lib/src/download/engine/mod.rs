@@ -0,0 +1,1878 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::time::Instant;
+
+use crate::config::Configuration;
+use crate::net::bitfield::BitField;
+use crate::net::block::{Block, BlockRequest, BLOCK_SIZE};
+use crate::net::filter::IpFilter;
+use crate::net::limit::{RateLimits, Rates};
+use crate::net::peers::{Peer, PeerSource};
+use crate::net::session::{PeerCommand, PeerEvent, PeerHandle, PeerSession, SessionEvent};
+use crate::piece::hasher::HashPool;
+use crate::piece::picker::Strategy;
+use crate::piece::set::PieceSet;
+use crate::storage::Storage;
+use crate::torrent::Torrent;
+use crate::tracker::{Tracker, TrackerRequest};
+
+use super::choker::{Choker, PeerStats, TitForTat, UNCHOKE_INTERVAL};
+use super::connections::{ConnectionHandle, ConnectionManager};
+use super::downloaded::{DownloadedPiece, HashMismatch};
+use super::priority::{piece_priorities, FilePriority};
+use super::requester::{BlockOutcome, BlockRequester};
+use super::smartban::SmartBan;
+use super::stream::StreamReader;
+use super::superseed::SuperSeeder;
+
+use self::admission::Admission;
+use self::reads::Reads;
+
+mod admission;
+mod reads;
+mod restore;
+
+/// Maximum number of peers the downloader connects to, unless configured otherwise.
+pub const MAX_PEERS: usize = 50;
+
+/// Time after which a peer that does not complete the handshake is given up on.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval at which timed out requests are checked.
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Interval at which the resume data is saved while the download runs.
+pub const RESUME_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The progress of a download.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Number of pieces downloaded and verified.
+    pub pieces: usize,
+    /// Number of pieces to download, which leaves out the pieces of skipped files.
+    pub total_pieces: usize,
+    /// Number of bytes downloaded and verified.
+    pub downloaded: usize,
+    /// Number of bytes of blocks sent to peers.
+    pub uploaded: usize,
+    /// Number of pieces that failed their hash check, and were downloaded again.
+    pub failed: usize,
+    /// Number of connected peers.
+    pub peers: usize,
+    /// Whether the download is in endgame, requesting its last blocks from several peers.
+    pub endgame: bool,
+    /// Number of bytes received that were discarded, mostly duplicates of endgame blocks.
+    pub wasted: usize,
+    /// Number of peers banned for sending corrupt data.
+    pub banned: usize,
+}
+
+/// Commands that change a running download.
+#[derive(Debug)]
+enum Control {
+    SetStrategy(Strategy),
+    SetDeadline {
+        pieces: Range<usize>,
+        deadline: Instant,
+    },
+    RaisePriority {
+        pieces: Range<usize>,
+        priority: u8,
+    },
+    Read {
+        range: Range<usize>,
+        reply: oneshot::Sender<anyhow::Result<Vec<u8>>>,
+    },
+    SetPeerLimit(Rates),
+    Stop,
+}
+
+/// A handle to change the behaviour of a download while it runs.
+#[derive(Debug, Clone)]
+pub struct DownloadControl {
+    commands: mpsc::UnboundedSender<Control>,
+    limits: RateLimits,
+    piece_length: usize,
+    length: usize,
+}
+
+impl DownloadControl {
+    /// Returns the length of the content of the torrent in bytes.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Returns the number of bytes in a piece, except for the last one which may be shorter.
+    pub fn piece_length(&self) -> usize {
+        self.piece_length
+    }
+
+    /// Makes a range of pieces time-critical, to be downloaded within `deadline` from now.
+    ///
+    /// Time-critical pieces are requested before any other piece, earliest deadline first,
+    /// and only from the peers that are faster than average. Once their deadline has passed,
+    /// their blocks are requested from several peers at once, as in endgame.
+    pub fn set_deadline(&self, pieces: Range<usize>, deadline: Duration) {
+        let deadline = Instant::now() + deadline;
+        let _ = self
+            .commands
+            .send(Control::SetDeadline { pieces, deadline });
+    }
+
+    /// Raises the priority of a range of pieces to at least `priority`, so that pieces of
+    /// skipped files are downloaded as well.
+    pub fn raise_priority(&self, pieces: Range<usize>, priority: u8) {
+        let _ = self
+            .commands
+            .send(Control::RaisePriority { pieces, priority });
+    }
+
+    /// Reads a range of the content, waiting until every piece it overlaps is downloaded and
+    /// verified. The pieces that are missing become time-critical, with a deadline of now.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the range goes past the end of the content, the
+    /// storage cannot be read, or the download ends before the pieces arrive.
+    pub async fn read(&self, range: Range<usize>) -> anyhow::Result<Vec<u8>> {
+        let (reply, data) = oneshot::channel();
+        let _ = self.commands.send(Control::Read { range, reply });
+        data.await
+            .map_err(|_| anyhow::anyhow!("Download is no longer running."))?
+    }
+
+    /// Returns a reader that streams a range of the content as it is downloaded.
+    pub fn stream(&self, range: Range<usize>) -> StreamReader {
+        StreamReader::new(self.clone(), range)
+    }
+
+    /// Returns the upload and download rates of the torrent, in bytes per second.
+    pub fn torrent_limit(&self) -> Rates {
+        self.limits.rates()
+    }
+
+    /// Changes the upload and download rates of the torrent, in bytes per second, which all
+    /// of its peers share.
+    pub fn set_torrent_limit(&self, rates: Rates) {
+        self.limits.set_rates(rates);
+    }
+
+    /// Changes the upload and download rates of each peer, in bytes per second, for the
+    /// connected peers and the ones that connect later.
+    pub fn set_peer_limit(&self, rates: Rates) {
+        let _ = self.commands.send(Control::SetPeerLimit(rates));
+    }
+
+    /// Switches the strategy used to pick the next pieces to download.
+    pub fn set_strategy(&self, strategy: Strategy) {
+        let _ = self.commands.send(Control::SetStrategy(strategy));
+    }
+
+    /// Stops the download, which then returns an error. The resume data is saved first, if
+    /// the download keeps any.
+    pub fn stop(&self) {
+        let _ = self.commands.send(Control::Stop);
+    }
+}
+
+/// Downloads a torrent from many peers at once.
+///
+/// The downloader gets the peers from the tracker and connects to them concurrently. Each
+/// connected peer is driven by a [`PeerSession`], and all the sessions report to a single
+/// engine loop, which spreads the pieces across the peers that have them. The order of the
+/// pieces is decided by a [`Strategy`], which is rarest-first unless set otherwise. Every
+/// piece is checked against its SHA1 hash before it is written to the [`Storage`], and pieces
+/// that fail the check are downloaded again from other peers.
+///
+/// While it downloads, and once it completes if it keeps running, the engine also uploads:
+/// a [`Choker`] decides which interested peers are unchoked, and their requests are answered
+/// from the pieces that have been verified. Peers are told of every verified piece with a
+/// `Have` message.
+///
+/// Every message sent and received goes through the bandwidth limits of the
+/// [`Configuration`]: the global limits shared by every download, the limits of the torrent,
+/// and the limits of each peer. See [`DownloadControl`] to change them while it runs. Peers
+/// that belong to a [`PeerClass`] go through the limits of their class as well, or only
+/// those if it is unthrottled, and are refused once their class has its maximum number of
+/// connections.
+///
+/// With [`Downloader::with_resume`], the state of the download is saved to a resume file
+/// every [`RESUME_INTERVAL`] and when the download ends, and loaded back when it starts
+/// again. See [`ResumeData`].
+///
+/// [`PeerClass`]: crate::net::class::PeerClass
+/// [`ResumeData`]: super::resume::ResumeData
+pub struct Downloader<T> {
+    torrent: Torrent,
+    config: Configuration,
+    peer_id: [u8; 20],
+    storage: T,
+    strategy: Strategy,
+    choker: Option<Box<dyn Choker>>,
+    file_priorities: Option<Vec<FilePriority>>,
+    resume: Option<PathBuf>,
+    keep_running: bool,
+    super_seeding: bool,
+    listener: Option<TcpListener>,
+    /// The bandwidth limits of the torrent, shared by all its peers.
+    limits: RateLimits,
+    /// The manager that connects to the peers, which is told when they disconnect.
+    connections: Option<ConnectionHandle>,
+    progress: watch::Sender<Progress>,
+    /// The pieces we have, which are sent to the peers we connect to.
+    have: watch::Sender<BitField>,
+    control_tx: mpsc::UnboundedSender<Control>,
+    control_rx: mpsc::UnboundedReceiver<Control>,
+}
+
+impl<T: Storage> Downloader<T> {
+    /// Creates a new downloader that saves the content of `torrent` to `storage`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the peer ID of the configuration is not 20 bytes long.
+    pub fn new(torrent: Torrent, config: Configuration, storage: T) -> anyhow::Result<Self> {
+        let peer_id: [u8; 20] = config
+            .peer_id()
+            .as_bytes()
+            .try_into()
+            .context("Peer ID must be 20 bytes long.")?;
+        let progress = Progress {
+            total_pieces: torrent.n_pieces(),
+            ..Progress::default()
+        };
+        let have = BitField::new(torrent.n_pieces());
+        let limits = RateLimits::new(config.torrent_limit());
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        Ok(Self {
+            torrent,
+            config,
+            peer_id,
+            storage,
+            strategy: Strategy::default(),
+            choker: None,
+            file_priorities: None,
+            resume: None,
+            keep_running: false,
+            super_seeding: false,
+            listener: None,
+            limits,
+            connections: None,
+            progress: watch::Sender::new(progress),
+            have: watch::Sender::new(have),
+            control_tx,
+            control_rx,
+        })
+    }
+
+    /// Sets the strategy used to pick the pieces to download.
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Sets the algorithm that decides which peers we upload to. Defaults to [`TitForTat`],
+    /// with the upload slots of the configuration.
+    pub fn with_choker(mut self, choker: impl Choker + 'static) -> Self {
+        self.choker = Some(Box::new(choker));
+        self
+    }
+
+    /// Sets the priority of every file of the torrent, in the order of its [`FileLayout`].
+    ///
+    /// Only the pieces that overlap files that are not skipped are downloaded, and the
+    /// download completes once it has those. Pieces take the highest priority of the files
+    /// they overlap, which [`Strategy::PriorityWeighted`] follows. The storage should be
+    /// created with the same priorities, such as with [`FileStorage::create_with_priorities`],
+    /// for the skipped files to be left out of it.
+    ///
+    /// [`FileLayout`]: crate::storage::FileLayout
+    /// [`FileStorage::create_with_priorities`]: crate::storage::file::FileStorage::create_with_priorities
+    pub fn with_file_priorities(mut self, priorities: Vec<FilePriority>) -> Self {
+        self.file_priorities = Some(priorities);
+        self
+    }
+
+    /// Keeps the resume data of the download in the file at `path`.
+    ///
+    /// If the file exists when the download starts and the files of the storage have not
+    /// changed since it was saved, the pieces it lists are trusted without being hashed. If
+    /// the files have changed, every piece in the storage is hashed again instead.
+    pub fn with_resume(mut self, path: impl Into<PathBuf>) -> Self {
+        self.resume = Some(path.into());
+        self
+    }
+
+    /// Keeps the download running once it completes, answering reads through its
+    /// [`DownloadControl`], until it is stopped. Stopping a complete download returns the
+    /// storage as usual.
+    pub fn with_keep_running(mut self) -> Self {
+        self.keep_running = true;
+        self
+    }
+
+    /// Marks pieces as downloaded before the download starts, such as the pieces that
+    /// [`verify`](crate::storage::verify::verify) found intact in the storage. Given every
+    /// piece, the download only seeds.
+    pub fn with_pieces(self, pieces: BitField) -> Self {
+        self.have.send_replace(pieces);
+        self
+    }
+
+    /// Seeds in super-seeding mode, which hides that we have every piece and hands the pieces
+    /// out one peer at a time, so that the first copy spreads across the swarm with as
+    /// little uploading as possible. See [`SuperSeeder`].
+    ///
+    /// It only applies if the download starts with every piece, as given to
+    /// [`Downloader::with_pieces`].
+    pub fn with_super_seeding(mut self) -> Self {
+        self.super_seeding = true;
+        self
+    }
+
+    /// Accepts connections from peers on `listener` as well, besides connecting to the peers
+    /// returned by the tracker. Peers that want to download from us connect to the port we
+    /// announce, so seeding needs a listener.
+    pub fn with_listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+
+    /// Returns a handle to change the download while it runs.
+    pub fn control(&self) -> DownloadControl {
+        DownloadControl {
+            commands: self.control_tx.clone(),
+            limits: self.limits.clone(),
+            piece_length: self.torrent.piece_length(),
+            length: self.torrent.length(),
+        }
+    }
+
+    /// Returns a receiver that is notified whenever the download progresses.
+    pub fn progress(&self) -> watch::Receiver<Progress> {
+        self.progress.subscribe()
+    }
+
+    /// Downloads the torrent from the peers returned by its tracker, and returns the storage
+    /// holding the complete content.
+    ///
+    /// The peers are connected to by a [`ConnectionManager`], within the connection limits of
+    /// the configuration, and peers that fail or disconnect are connected to again after a
+    /// backoff.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The tracker cannot be queried.
+    /// - The storage cannot be written.
+    /// - All the peers disconnect before the download completes, and cannot be connected to
+    ///   again.
+    pub async fn run(mut self) -> anyhow::Result<T> {
+        let info_hash = self
+            .torrent
+            .info_hash()
+            .context("Failed to hash info dictionary.")?;
+        let have = self.have.borrow().clone();
+        let left = (0..self.torrent.n_pieces())
+            .filter(|&piece_i| !have.contains_piece(piece_i))
+            .map(|piece_i| self.torrent.get_piece_length(piece_i))
+            .sum::<anyhow::Result<usize>>()?;
+        let tracker = Tracker::new(self.torrent.announce())?;
+        let request = TrackerRequest::new(
+            &info_hash,
+            self.config.peer_id(),
+            self.config.port(),
+            0,
+            0,
+            left,
+            1,
+        );
+        let response = tracker.query(request).await?;
+
+        // A super-seed tells the peers it has no pieces, and offers them one by one later.
+        let have = if self.is_super_seeding() {
+            watch::channel(BitField::new(0)).1
+        } else {
+            self.have.subscribe()
+        };
+        let (peers_tx, peers_rx) = mpsc::channel(MAX_PEERS);
+        let mut manager = ConnectionManager::new(
+            self.peer_id,
+            info_hash,
+            have,
+            self.config.connection_limits().clone(),
+            self.config.max_peers(),
+            peers_tx,
+        )
+        .with_ip_filter(self.config.ip_filter().clone());
+        if self.listener.is_some() {
+            manager = manager.with_listen_port(self.config.port());
+        }
+        let connections = manager.handle();
+        connections.add_peers(response.peers().0.iter().copied(), PeerSource::Tracker);
+        if let Some(listener) = self.listener.take() {
+            tokio::spawn(accept(listener, connections.clone()));
+        }
+        tokio::spawn(manager.run());
+        self.connections = Some(connections);
+
+        self.download_from(peers_rx).await
+    }
+
+    /// Returns whether the download super-seeds, which it only does if it starts with every
+    /// piece.
+    fn is_super_seeding(&self) -> bool {
+        let have = self.have.borrow();
+        self.super_seeding
+            && (0..self.torrent.n_pieces()).all(|piece_i| have.contains_piece(piece_i))
+    }
+
+    /// Downloads the torrent from the peers received on `peers`, and returns the storage
+    /// holding the complete content.
+    ///
+    /// The download fails once `peers` is closed and every received peer has disconnected.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The resume data cannot be read or saved.
+    /// - The storage cannot be read or written.
+    /// - All the peers disconnect before the download completes.
+    /// - The download is stopped through its [`DownloadControl`].
+    pub async fn download_from<S>(self, mut peers: mpsc::Receiver<Peer<S>>) -> anyhow::Result<T>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let super_seeding = self.is_super_seeding();
+        let Self {
+            torrent,
+            config,
+            storage,
+            strategy,
+            choker,
+            file_priorities,
+            resume,
+            keep_running,
+            limits,
+            connections,
+            progress,
+            have,
+            mut control_rx,
+            ..
+        } = self;
+        let mut pieces = PieceSet::new(&torrent, strategy);
+        if let Some(file_priorities) = &file_priorities {
+            let priorities = piece_priorities(storage.layout(), file_priorities)?;
+            for (piece_i, priority) in priorities.into_iter().enumerate() {
+                pieces.set_priority(piece_i, priority);
+            }
+            let total_pieces = pieces.n_wanted();
+            progress.send_modify(|progress| progress.total_pieces = total_pieces);
+        }
+        let choker = choker.unwrap_or_else(|| Box::new(TitForTat::new(config.upload_slots())));
+        let mut engine = Engine::new(&torrent, pieces, storage, &progress, &have, choker);
+        let verified = have.borrow().clone();
+        engine.add_verified(&verified)?;
+        if super_seeding {
+            engine.super_seeder = Some(SuperSeeder::new());
+        }
+        engine.peer_limit = config.peer_limit();
+        engine.ip_filter = config.ip_filter().clone();
+        engine.smart_ban = SmartBan::new(config.max_hash_failures());
+        if let Some(path) = &resume {
+            engine.restore(path).await?;
+        }
+        have.send_replace(engine.pieces.have().clone());
+        let admission = Admission::new(&config, &limits);
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let mut peers_open = true;
+        let mut ticker = tokio::time::interval(TICK_INTERVAL);
+        let mut choke_ticker = tokio::time::interval(UNCHOKE_INTERVAL);
+        let mut resume_ticker = tokio::time::interval_at(
+            tokio::time::Instant::now() + RESUME_INTERVAL,
+            RESUME_INTERVAL,
+        );
+
+        let result = async {
+            while keep_running || !engine.is_complete() {
+                if !peers_open && engine.peers.is_empty() && !engine.is_complete() {
+                    anyhow::bail!(
+                        "Ran out of peers with {} of {} pieces downloaded.",
+                        engine.pieces.n_have(),
+                        torrent.n_pieces()
+                    );
+                }
+
+                tokio::select! {
+                    peer = peers.recv(), if peers_open => match peer {
+                        Some(mut peer) => {
+                            let admitted = admission.admit(
+                                *peer.address().ip(),
+                                engine.peer_limit,
+                                |class_i| engine.n_class_peers(class_i),
+                            );
+                            let Some(admitted) = admitted else {
+                                if let Some(connections) = &connections {
+                                    connections.disconnected(peer.address());
+                                }
+                                continue;
+                            };
+                            let bitfield = peer.bitfield().clone();
+                            peer.set_throttle(admitted.throttle);
+                            let handle = PeerSession::spawn(peer, events_tx.clone());
+                            engine.on_connected(
+                                handle,
+                                bitfield,
+                                admitted.limits,
+                                admitted.class,
+                                admitted.priority,
+                            );
+                        }
+                        None => peers_open = false,
+                    },
+                    Some(SessionEvent { peer, event }) = events.recv() => {
+                        if let (PeerEvent::Disconnected(_), Some(connections)) =
+                            (&event, &connections)
+                        {
+                            connections.disconnected(peer);
+                        }
+                        engine.on_event(peer, event).await?;
+                    }
+                    Some(control) = control_rx.recv() => match control {
+                        Control::SetStrategy(strategy) => {
+                            engine.pieces.set_picker(strategy.picker())
+                        }
+                        Control::SetDeadline { pieces, deadline } => {
+                            engine.set_deadline(pieces, deadline)
+                        }
+                        Control::RaisePriority { pieces, priority } => {
+                            engine.raise_priority(pieces, priority)
+                        }
+                        Control::Read { range, reply } => engine.read(range, reply).await,
+                        Control::SetPeerLimit(rates) => engine.set_peer_limit(rates),
+                        Control::Stop if engine.is_complete() => break,
+                        Control::Stop => anyhow::bail!(
+                            "Download stopped with {} of {} pieces downloaded.",
+                            engine.pieces.n_have(),
+                            torrent.n_pieces()
+                        ),
+                    },
+                    _ = ticker.tick() => engine.on_tick(),
+                    _ = choke_ticker.tick() => engine.on_choke_round(),
+                    _ = resume_ticker.tick(), if resume.is_some() => {
+                        engine.save_resume(resume.as_deref().expect("Resume is kept.")).await?;
+                    }
+                }
+            }
+            Ok(())
+        }
+        .await;
+
+        engine.close();
+        if let Some(path) = &resume {
+            // A failed download keeps its own error, which matters more than the resume data.
+            let saved = engine.save_resume(path).await;
+            if result.is_ok() {
+                saved?;
+            }
+        }
+        result?;
+        Ok(engine.storage)
+    }
+}
+
+/// A connected peer, as seen by the engine.
+struct PeerEntry {
+    handle: PeerHandle,
+    bitfield: BitField,
+    /// Whether the peer is choking us.
+    choked: bool,
+    /// Whether we are choking the peer.
+    choking: bool,
+    /// Whether the peer is interested in our pieces.
+    interested: bool,
+    connected_at: Instant,
+    /// Bytes sent to the peer since the last round of the choker.
+    uploaded: usize,
+    /// Bytes per second sent to the peer over the last round of the choker.
+    upload_rate: f64,
+    /// The bandwidth limits of the peer alone.
+    limits: RateLimits,
+    /// Index of the peer class of the peer, if it belongs to one.
+    class: Option<usize>,
+    /// Priority of the peer in the choker, from its class.
+    priority: u8,
+}
+
+/// A piece whose blocks are being downloaded.
+struct InProgress {
+    piece: DownloadedPiece,
+    /// Peers that sent the blocks of the piece, by block index.
+    senders: HashMap<usize, SocketAddrV4>,
+}
+
+/// The state of a running download.
+struct Engine<'a, T> {
+    torrent: &'a Torrent,
+    storage: T,
+    progress: &'a watch::Sender<Progress>,
+    have: &'a watch::Sender<BitField>,
+    pieces: PieceSet,
+    peers: HashMap<SocketAddrV4, PeerEntry>,
+    requester: BlockRequester,
+    hashes: HashPool,
+    in_progress: HashMap<usize, InProgress>,
+    /// Peers that sent a piece that failed its hash check.
+    failed: HashMap<usize, HashSet<SocketAddrV4>>,
+    choker: Box<dyn Choker>,
+    super_seeder: Option<SuperSeeder>,
+    /// Start of the current round of the choker.
+    round_start: Instant,
+    /// Whether a choked peer changed its interest since the choker last ran.
+    rechoke_due: bool,
+    /// The bandwidth limits that every peer gets when it connects.
+    peer_limit: Rates,
+    /// Addresses that are disconnected from as soon as they are blocked.
+    ip_filter: IpFilter,
+    /// Finds the peers that send corrupt blocks, which are banned in `ip_filter`.
+    smart_ban: SmartBan,
+    /// Time-critical pieces, with their deadline.
+    deadlines: HashMap<usize, Instant>,
+    reads: Reads,
+}
+
+impl<'a, T: Storage> Engine<'a, T> {
+    fn new(
+        torrent: &'a Torrent,
+        pieces: PieceSet,
+        storage: T,
+        progress: &'a watch::Sender<Progress>,
+        have: &'a watch::Sender<BitField>,
+        choker: Box<dyn Choker>,
+    ) -> Self {
+        Self {
+            torrent,
+            storage,
+            progress,
+            have,
+            pieces,
+            peers: HashMap::new(),
+            requester: BlockRequester::new(),
+            hashes: HashPool::shared(),
+            in_progress: HashMap::new(),
+            failed: HashMap::new(),
+            choker,
+            super_seeder: None,
+            round_start: Instant::now(),
+            rechoke_due: false,
+            peer_limit: Rates::UNLIMITED,
+            ip_filter: IpFilter::default(),
+            smart_ban: SmartBan::default(),
+            deadlines: HashMap::new(),
+            reads: Reads::new(torrent.length(), torrent.piece_length()),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.pieces.is_complete()
+    }
+
+    fn on_connected(
+        &mut self,
+        handle: PeerHandle,
+        bitfield: BitField,
+        limits: RateLimits,
+        class: Option<usize>,
+        priority: u8,
+    ) {
+        let address = handle.address();
+        self.pieces.add_peer(address, &bitfield);
+        self.peers.insert(
+            address,
+            PeerEntry {
+                handle,
+                bitfield,
+                choked: true,
+                choking: true,
+                interested: false,
+                connected_at: Instant::now(),
+                uploaded: 0,
+                upload_rate: 0.0,
+                limits,
+                class,
+                priority,
+            },
+        );
+        self.requester.add_peer(address);
+        self.update_interest(address);
+        self.offer_piece(address);
+        self.update_progress();
+    }
+
+    async fn on_event(&mut self, peer: SocketAddrV4, event: PeerEvent) -> anyhow::Result<()> {
+        match event {
+            PeerEvent::Choked => {
+                if let Some(entry) = self.peers.get_mut(&peer) {
+                    entry.choked = true;
+                }
+                self.requester.requeue(peer);
+                self.fill_all();
+            }
+            PeerEvent::UnChoked => {
+                if let Some(entry) = self.peers.get_mut(&peer) {
+                    entry.choked = false;
+                }
+                self.fill(peer);
+            }
+            PeerEvent::Have(piece_i) => {
+                if let Some(entry) = self.peers.get_mut(&peer) {
+                    entry.bitfield.set_piece(piece_i);
+                    self.pieces.peer_has(peer, piece_i);
+                }
+                self.update_interest(peer);
+                self.fill(peer);
+                self.on_super_seed_have(peer, piece_i);
+            }
+            PeerEvent::Block(block) => {
+                self.on_block(peer, block).await?;
+                self.fill(peer);
+            }
+            PeerEvent::Interested | PeerEvent::NotInterested => {
+                let Some(entry) = self.peers.get_mut(&peer) else {
+                    return Ok(());
+                };
+                entry.interested = matches!(event, PeerEvent::Interested);
+                // An unchoked peer that loses interest frees its slot right away. Choked peers
+                // that become interested wait for the next tick, so that a burst of them only
+                // runs the choker once.
+                if entry.choking {
+                    self.rechoke_due = true;
+                } else {
+                    self.rechoke();
+                }
+            }
+            PeerEvent::Request(request) => self.on_request(peer, request).await?,
+            // The session drops the block of a cancelled request if it has not been sent yet.
+            PeerEvent::Cancel(_) => {}
+            PeerEvent::BlockSent(request) => {
+                if let Some(entry) = self.peers.get_mut(&peer) {
+                    entry.uploaded += request.length();
+                }
+                self.progress
+                    .send_modify(|progress| progress.uploaded += request.length());
+            }
+            PeerEvent::Disconnected(_) => {
+                self.peers.remove(&peer);
+                if let Some(super_seeder) = &mut self.super_seeder {
+                    super_seeder.remove_peer(peer);
+                }
+                self.pieces.remove_peer(&peer);
+                self.requester.remove_peer(peer);
+                self.fill_all();
+                self.rechoke();
+                self.update_progress();
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the number of connected peers of a peer class.
+    fn n_class_peers(&self, class_i: usize) -> usize {
+        self.peers
+            .values()
+            .filter(|entry| entry.class == Some(class_i))
+            .count()
+    }
+
+    /// Changes the bandwidth limits of every connected peer, and of the peers to come.
+    fn set_peer_limit(&mut self, rates: Rates) {
+        self.peer_limit = rates;
+        for entry in self.peers.values() {
+            entry.limits.set_rates(rates);
+        }
+    }
+
+    fn on_tick(&mut self) {
+        // The filter may have been reloaded to block peers we are connected to.
+        for (address, entry) in &self.peers {
+            if self.ip_filter.is_blocked(*address.ip()) {
+                entry.handle.send(PeerCommand::Close);
+            }
+        }
+        for (peer, request) in self.requester.expire() {
+            if let Some(entry) = self.peers.get(&peer) {
+                entry.handle.send(PeerCommand::Cancel(request));
+            }
+        }
+        self.fill_all();
+        if self.rechoke_due {
+            self.rechoke();
+        }
+    }
+
+    /// Answers a request of a peer we unchoked with a block of a verified piece. Requests
+    /// for pieces we do not have, or that fall outside their piece, are dropped.
+    async fn on_request(
+        &mut self,
+        peer: SocketAddrV4,
+        request: BlockRequest,
+    ) -> anyhow::Result<()> {
+        let Some(entry) = self.peers.get(&peer) else {
+            return Ok(());
+        };
+        let piece_i = request.piece_i();
+        let valid = !entry.choking
+            && self.pieces.has_piece(piece_i)
+            && self
+                .super_seeder
+                .as_ref()
+                .is_none_or(|super_seeder| super_seeder.is_offered(peer, piece_i))
+            && request.length() > 0
+            && request.begin() + request.length() <= self.torrent.get_piece_length(piece_i)?;
+        if !valid {
+            entry.handle.send(PeerCommand::Reject(request));
+            return Ok(());
+        }
+
+        let data = self
+            .storage
+            .read_block(piece_i, request.begin(), request.length())
+            .await
+            .context("Failed to read block from storage.")?;
+        let block = Block::new(piece_i, request.begin(), data);
+        self.peers[&peer].handle.send(PeerCommand::Block(block));
+        Ok(())
+    }
+
+    async fn on_block(&mut self, peer: SocketAddrV4, block: Block) -> anyhow::Result<()> {
+        let BlockOutcome::Accepted { cancel } = self.requester.on_block(peer, &block) else {
+            let wasted = block.data().len();
+            self.progress
+                .send_modify(|progress| progress.wasted += wasted);
+            return Ok(());
+        };
+        // In endgame, the block is no longer needed from the other peers it was requested from.
+        for other in cancel {
+            if let Some(entry) = self.peers.get(&other) {
+                entry.handle.send(PeerCommand::Cancel(block.request()));
+            }
+        }
+        let piece_i = block.piece_i();
+        let Some(in_progress) = self.in_progress.get_mut(&piece_i) else {
+            return Ok(());
+        };
+        if in_progress.piece.add_block(&block)? {
+            in_progress.senders.insert(block.begin() / BLOCK_SIZE, peer);
+        }
+        if in_progress.piece.is_complete() {
+            self.finish_piece(piece_i).await?;
+        }
+        Ok(())
+    }
+
+    /// Checks a piece whose blocks have all arrived, and writes it to the storage if it
+    /// matches its hash.
+    async fn finish_piece(&mut self, piece_i: usize) -> anyhow::Result<()> {
+        let in_progress = self
+            .in_progress
+            .remove(&piece_i)
+            .expect("Piece is in progress.");
+        for (peer, request) in self.requester.remove_piece(piece_i) {
+            if let Some(entry) = self.peers.get(&peer) {
+                entry.handle.send(PeerCommand::Cancel(request));
+            }
+        }
+
+        let hash = self.torrent.get_piece_hash(piece_i)?;
+        match in_progress.piece.verify_on(&self.hashes, hash).await {
+            Ok(data) => {
+                self.storage
+                    .write_block(piece_i, 0, &data)
+                    .await
+                    .context("Failed to write piece to storage.")?;
+                self.pieces.complete(piece_i);
+                self.have.send_modify(|have| have.set_piece(piece_i));
+                self.failed.remove(&piece_i);
+                for address in self.smart_ban.on_passed(piece_i, &data) {
+                    self.ban(address);
+                }
+                for entry in self.peers.values() {
+                    entry.handle.send(PeerCommand::Have(piece_i));
+                }
+                self.progress.send_modify(|progress| {
+                    progress.pieces += 1;
+                    progress.downloaded += data.len();
+                });
+                self.deadlines.remove(&piece_i);
+                self.reads
+                    .serve(self.pieces.have(), &mut self.storage)
+                    .await;
+                if self.is_complete() {
+                    self.storage.flush().await?;
+                }
+            }
+            Err(error) => {
+                self.pieces.abort(piece_i);
+                if let Some(mismatch) = error.downcast_ref::<HashMismatch>() {
+                    let senders = &in_progress.senders;
+                    for address in self.smart_ban.on_failed(piece_i, mismatch.data(), senders) {
+                        self.ban(address);
+                    }
+                }
+                self.failed
+                    .entry(piece_i)
+                    .or_default()
+                    .extend(in_progress.senders.into_values());
+                self.progress.send_modify(|progress| progress.failed += 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// Bans an address for sending corrupt data, and disconnects from its peers.
+    fn ban(&mut self, address: Ipv4Addr) {
+        if !self.ip_filter.ban(address) {
+            return;
+        }
+        for (peer, entry) in &self.peers {
+            if *peer.ip() == address {
+                entry.handle.send(PeerCommand::Close);
+            }
+        }
+        self.progress.send_modify(|progress| progress.banned += 1);
+    }
+
+    /// Makes a range of pieces time-critical. Pieces we have are left out, and an earlier
+    /// deadline than the one a piece already has wins.
+    fn set_deadline(&mut self, pieces: Range<usize>, deadline: Instant) {
+        for piece_i in pieces.take_while(|&i| i < self.torrent.n_pieces()) {
+            if !self.pieces.has_piece(piece_i) {
+                let current = self.deadlines.entry(piece_i).or_insert(deadline);
+                *current = (*current).min(deadline);
+            }
+        }
+        self.fill_all();
+    }
+
+    /// Raises the priority of a range of pieces, and starts downloading those that were
+    /// skipped.
+    fn raise_priority(&mut self, pieces: Range<usize>, priority: u8) {
+        for piece_i in pieces.take_while(|&i| i < self.torrent.n_pieces()) {
+            if self.pieces.priority(piece_i) < priority {
+                self.pieces.set_priority(piece_i, priority);
+            }
+        }
+        let total_pieces = self.pieces.n_wanted();
+        self.progress.send_if_modified(|progress| {
+            std::mem::replace(&mut progress.total_pieces, total_pieces) != total_pieces
+        });
+        let peers = self.peers.keys().copied().collect::<Vec<_>>();
+        for peer in peers {
+            self.update_interest(peer);
+        }
+        self.fill_all();
+    }
+
+    /// Answers a read right away if we have its pieces, or keeps it until they arrive, in
+    /// which case its pieces become time-critical.
+    async fn read(&mut self, range: Range<usize>, reply: oneshot::Sender<anyhow::Result<Vec<u8>>>) {
+        if let Err(error) = self.reads.check(&range) {
+            let _ = reply.send(Err(error));
+            return;
+        }
+        self.set_deadline(self.reads.pieces_of(&range), Instant::now());
+        self.reads.push(range, reply);
+        self.reads
+            .serve(self.pieces.have(), &mut self.storage)
+            .await;
+    }
+
+    /// Returns whether a peer is fast enough to be asked for time-critical pieces: at least
+    /// as fast as the average unchoked peer. Before any rate is measured, every peer is.
+    fn is_fast(&self, peer: SocketAddrV4) -> bool {
+        let rates = self
+            .peers
+            .iter()
+            .filter(|(_, entry)| !entry.choked)
+            .map(|(&address, _)| self.requester.rate(address))
+            .collect::<Vec<_>>();
+        if rates.is_empty() {
+            return true;
+        }
+        let average = rates.iter().sum::<f64>() / rates.len() as f64;
+        self.requester.rate(peer) >= average
+    }
+
+    /// Returns the time-critical pieces the peer can send, earliest deadline first.
+    fn critical_pieces(&self, peer: SocketAddrV4) -> Vec<usize> {
+        let mut critical = self
+            .deadlines
+            .iter()
+            .filter(|(&piece_i, _)| self.wants_from(peer, piece_i))
+            .map(|(&piece_i, &deadline)| (deadline, piece_i))
+            .collect::<Vec<_>>();
+        critical.sort_unstable();
+        critical.into_iter().map(|(_, piece_i)| piece_i).collect()
+    }
+
+    /// Returns whether we still need a piece, and it can be requested from a peer.
+    fn wants_from(&self, peer: SocketAddrV4, piece_i: usize) -> bool {
+        !self.pieces.has_piece(piece_i)
+            && self
+                .peers
+                .get(&peer)
+                .is_some_and(|entry| entry.bitfield.contains_piece(piece_i))
+            && may_retry(&self.failed, &self.peers, peer, piece_i)
+    }
+
+    /// Picks the next piece to download from the peer, among the pieces that have blocks left
+    /// to request.
+    fn pick_piece(&mut self, peer: SocketAddrV4) -> Option<usize> {
+        let (failed, peers) = (&self.failed, &self.peers);
+        let (in_progress, requester) = (&self.in_progress, &self.requester);
+        self.pieces.pick(&peer, |piece_i| {
+            may_retry(failed, peers, peer, piece_i)
+                && (!in_progress.contains_key(&piece_i) || requester.has_pending(piece_i))
+        })
+    }
+
+    /// Sends a peer as many requests as its queue holds, starting new pieces when the pieces
+    /// in progress have no blocks left for it.
+    ///
+    /// Fast peers are asked for the time-critical pieces first, and for duplicates of their
+    /// blocks once their deadline has passed.
+    fn fill(&mut self, peer: SocketAddrV4) {
+        let Some(entry) = self.peers.get(&peer) else {
+            return;
+        };
+        if entry.choked {
+            return;
+        }
+        if !self.deadlines.is_empty() && self.is_fast(peer) {
+            self.fill_critical(peer);
+        }
+
+        loop {
+            // The requester is taken out for a moment, so that it can look at the rest of the
+            // state while it fills the queue.
+            let mut requester = std::mem::take(&mut self.requester);
+            let requests = requester.fill(peer, |piece_i| self.wants_from(peer, piece_i));
+            self.requester = requester;
+            let handle = &self.peers[&peer].handle;
+            for request in requests {
+                handle.send(PeerCommand::Request(request));
+            }
+
+            let depth = self.requester.queue_depth(peer).unwrap_or_default();
+            if self.requester.in_flight(peer) >= depth {
+                break;
+            }
+            let Some(piece_i) = self.pick_piece(peer) else {
+                if self.is_endgame() {
+                    self.fill_endgame(peer);
+                }
+                break;
+            };
+            if self.in_progress.contains_key(&piece_i) {
+                continue;
+            }
+            let length = self
+                .torrent
+                .get_piece_length(piece_i)
+                .expect("Picked piece is in bounds.");
+            self.requester.add_piece(piece_i, length);
+            self.in_progress.insert(
+                piece_i,
+                InProgress {
+                    piece: DownloadedPiece::new(piece_i, length),
+                    senders: HashMap::new(),
+                },
+            );
+        }
+    }
+
+    /// Fills the queue of a fast peer with the blocks of time-critical pieces.
+    fn fill_critical(&mut self, peer: SocketAddrV4) {
+        let now = Instant::now();
+        for piece_i in self.critical_pieces(peer) {
+            let depth = self.requester.queue_depth(peer).unwrap_or_default();
+            if self.requester.in_flight(peer) >= depth {
+                return;
+            }
+            if !self.in_progress.contains_key(&piece_i) {
+                let length = self
+                    .torrent
+                    .get_piece_length(piece_i)
+                    .expect("Critical piece is in bounds.");
+                self.pieces.start(piece_i);
+                self.requester.add_piece(piece_i, length);
+                self.in_progress.insert(
+                    piece_i,
+                    InProgress {
+                        piece: DownloadedPiece::new(piece_i, length),
+                        senders: HashMap::new(),
+                    },
+                );
+            }
+            let late = self.deadlines[&piece_i] <= now;
+            let only = |i: usize| i == piece_i;
+            let requests = if late {
+                self.requester.fill_endgame(peer, only)
+            } else {
+                self.requester.fill(peer, only)
+            };
+            let handle = &self.peers[&peer].handle;
+            for request in requests {
+                handle.send(PeerCommand::Request(request));
+            }
+        }
+    }
+
+    /// Returns whether every piece we want has been started, and every block of the pieces in
+    /// progress has been requested.
+    fn is_endgame(&self) -> bool {
+        self.pieces.n_unstarted() == 0 && !self.requester.has_any_pending()
+    }
+
+    /// Fills the queue of a peer with the blocks that are in flight to other peers.
+    fn fill_endgame(&mut self, peer: SocketAddrV4) {
+        let mut requester = std::mem::take(&mut self.requester);
+        let requests = requester.fill_endgame(peer, |piece_i| self.wants_from(peer, piece_i));
+        self.requester = requester;
+        let handle = &self.peers[&peer].handle;
+        for request in requests {
+            handle.send(PeerCommand::Request(request));
+        }
+        self.progress
+            .send_if_modified(|progress| !std::mem::replace(&mut progress.endgame, true));
+    }
+
+    fn fill_all(&mut self) {
+        let peers = self.peers.keys().copied().collect::<Vec<_>>();
+        for peer in peers {
+            self.fill(peer);
+        }
+    }
+
+    /// Tells the peer whether we are interested in it, depending on whether it has pieces we
+    /// still need.
+    fn update_interest(&self, peer: SocketAddrV4) {
+        let Some(entry) = self.peers.get(&peer) else {
+            return;
+        };
+        if self.pieces.is_interesting(&peer) {
+            entry.handle.send(PeerCommand::Interested);
+        } else {
+            entry.handle.send(PeerCommand::NotInterested);
+        }
+    }
+
+    /// Tells a peer of the next piece it is offered, when super-seeding.
+    fn offer_piece(&mut self, peer: SocketAddrV4) {
+        let (Some(super_seeder), Some(entry)) = (&mut self.super_seeder, self.peers.get(&peer))
+        else {
+            return;
+        };
+        let pieces = &self.pieces;
+        let offered = super_seeder.offer(
+            peer,
+            pieces.n_pieces(),
+            |piece_i| !entry.bitfield.contains_piece(piece_i),
+            |piece_i| pieces.availability(piece_i),
+        );
+        if let Some(piece_i) = offered {
+            entry.handle.send(PeerCommand::Have(piece_i));
+        }
+    }
+
+    /// Offers the next piece to the peers whose current offer has spread, now that `peer`
+    /// announced it, when super-seeding.
+    fn on_super_seed_have(&mut self, peer: SocketAddrV4, piece_i: usize) {
+        let Some(super_seeder) = &self.super_seeder else {
+            return;
+        };
+        // The piece cannot spread any further if every other peer has it already.
+        let can_spread = self
+            .peers
+            .iter()
+            .any(|(&other, entry)| other != peer && !entry.bitfield.contains_piece(piece_i));
+        for other in super_seeder.on_have(peer, piece_i, can_spread) {
+            self.offer_piece(other);
+        }
+    }
+
+    /// Measures the upload rate of every peer over the round that ends, and starts a new
+    /// round of the choker.
+    fn on_choke_round(&mut self) {
+        let now = Instant::now();
+        for entry in self.peers.values_mut() {
+            let elapsed = now.duration_since(self.round_start.max(entry.connected_at));
+            entry.upload_rate = entry.uploaded as f64 / elapsed.as_secs_f64().max(1.0);
+            entry.uploaded = 0;
+        }
+        self.round_start = now;
+        self.rechoke();
+    }
+
+    /// Asks the choker which peers to upload to, and unchokes them while choking the others.
+    fn rechoke(&mut self) {
+        self.rechoke_due = false;
+        let now = Instant::now();
+        let stats = self
+            .peers
+            .iter()
+            .map(|(&address, entry)| PeerStats {
+                address,
+                interested: entry.interested,
+                download_rate: self.requester.rate(address),
+                upload_rate: entry.upload_rate,
+                connected: now.duration_since(entry.connected_at),
+                priority: entry.priority,
+            })
+            .collect::<Vec<_>>();
+        let unchoked = self.choker.choose(&stats, self.is_complete(), now);
+        for (address, entry) in &mut self.peers {
+            let choking = !unchoked.contains(address);
+            if choking != entry.choking {
+                entry.choking = choking;
+                entry.handle.send(if choking {
+                    PeerCommand::Choke
+                } else {
+                    PeerCommand::UnChoke
+                });
+            }
+        }
+    }
+
+    fn update_progress(&self) {
+        let peers = self.peers.len();
+        self.progress
+            .send_if_modified(|progress| std::mem::replace(&mut progress.peers, peers) != peers);
+    }
+
+    fn close(&self) {
+        for entry in self.peers.values() {
+            entry.handle.send(PeerCommand::Close);
+        }
+    }
+}
+
+/// Accepts connections on `listener`, and hands them to the connection manager, until it
+/// stops.
+async fn accept(listener: TcpListener, connections: ConnectionHandle) {
+    while let Ok((stream, address)) = listener.accept().await {
+        if connections.is_closed() {
+            return;
+        }
+        if let SocketAddr::V4(address) = address {
+            connections.incoming(stream, address);
+        }
+    }
+}
+
+/// Returns whether a piece may be requested from a peer. A peer that sent a corrupt copy of
+/// the piece only gets asked again once every other peer with the piece has failed as well.
+fn may_retry(
+    failed: &HashMap<usize, HashSet<SocketAddrV4>>,
+    peers: &HashMap<SocketAddrV4, PeerEntry>,
+    peer: SocketAddrV4,
+    piece_i: usize,
+) -> bool {
+    let Some(failed) = failed.get(&piece_i) else {
+        return true;
+    };
+    !failed.contains(&peer)
+        || peers.iter().all(|(address, entry)| {
+            !entry.bitfield.contains_piece(piece_i) || failed.contains(address)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use futures_util::{SinkExt, StreamExt};
+
+    use super::*;
+    use crate::net::block::{have_message, parse_have, BlockRequest, BLOCK_SIZE};
+    use crate::net::class::{PeerClass, PeerClasses};
+    use crate::net::message::{Message, MessageTag};
+    use crate::net::session::tests::{connect, seed};
+    use crate::storage::file::FileStorage;
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::Allocation;
+
+    #[tokio::test]
+    async fn test_download_from_many_peers() {
+        let data = (0..10 * BLOCK_SIZE + 123)
+            .map(|i| (i % 253) as u8)
+            .collect::<Vec<_>>();
+        let piece_length = 2 * BLOCK_SIZE;
+        // The pieces span the boundaries of the files.
+        let (first, second) = data.split_at(3 * BLOCK_SIZE + 7);
+        let torrent = Torrent::for_files(
+            "content",
+            &[("a.bin", first), ("b/c.bin", second)],
+            piece_length,
+        );
+        let output = tempfile::tempdir().unwrap();
+        let storage = FileStorage::create(&torrent, output.path(), Allocation::Sparse)
+            .await
+            .unwrap();
+
+        let downloader = Downloader::new(torrent, Configuration::default(), storage).unwrap();
+        let progress = downloader.progress();
+        let (peers_tx, peers_rx) = mpsc::channel(4);
+        // The first peer corrupts piece 2, which has to be downloaded again from another peer.
+        for (bitfield, corrupt) in [
+            ([0xff, 0xff], vec![2]),
+            ([0xff, 0xff], vec![]),
+            ([0b1010_1010, 0], vec![]),
+        ] {
+            let (peer, remote) = connect(&bitfield).await;
+            seed(remote, data.clone(), piece_length, &corrupt);
+            peers_tx.send(peer).await.unwrap();
+        }
+        drop(peers_tx);
+
+        downloader.download_from(peers_rx).await.unwrap();
+        let dir = output.path().join("content");
+        assert_eq!(std::fs::read(dir.join("a.bin")).unwrap(), first);
+        assert_eq!(std::fs::read(dir.join("b/c.bin")).unwrap(), second);
+        let progress = progress.borrow();
+        assert_eq!(progress.pieces, 6);
+        assert_eq!(progress.downloaded, data.len());
+    }
+
+    #[tokio::test]
+    async fn test_download_with_every_strategy() {
+        let data = (0..8 * BLOCK_SIZE + 31)
+            .map(|i| (i % 239) as u8)
+            .collect::<Vec<_>>();
+        let piece_length = 2 * BLOCK_SIZE;
+        let (first, second) = data.split_at(5 * BLOCK_SIZE);
+        for strategy in [
+            Strategy::RarestFirst,
+            Strategy::Sequential,
+            Strategy::RandomFirst(2),
+            Strategy::PriorityWeighted,
+        ] {
+            let torrent =
+                Torrent::for_files("content", &[("a", first), ("b", second)], piece_length);
+            let output = tempfile::tempdir().unwrap();
+            let storage = FileStorage::create(&torrent, output.path(), Allocation::Sparse)
+                .await
+                .unwrap();
+            // Priorities weigh the picks of `PriorityWeighted`, and order the others.
+            let downloader = Downloader::new(torrent, Configuration::default(), storage)
+                .unwrap()
+                .with_strategy(Strategy::Sequential)
+                .with_file_priorities(vec![FilePriority::Low, FilePriority::High]);
+            let progress = downloader.progress();
+            // The strategy can be switched before the download starts, or while it runs.
+            downloader.control().set_strategy(strategy);
+            let (peers_tx, peers_rx) = mpsc::channel(2);
+            for bitfield in [[0xff, 0xff], [0b0101_0101, 0]] {
+                let (peer, remote) = connect(&bitfield).await;
+                seed(remote, data.clone(), piece_length, &[]);
+                peers_tx.send(peer).await.unwrap();
+            }
+            drop(peers_tx);
+
+            downloader.download_from(peers_rx).await.unwrap();
+            let dir = output.path().join("content");
+            assert_eq!(std::fs::read(dir.join("a")).unwrap(), first, "{strategy:?}");
+            assert_eq!(
+                std::fs::read(dir.join("b")).unwrap(),
+                second,
+                "{strategy:?}"
+            );
+            assert_eq!(progress.borrow().pieces, 5, "{strategy:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_skips_unwanted_files() {
+        let data = (0..10 * BLOCK_SIZE)
+            .map(|i| (i % 241) as u8)
+            .collect::<Vec<_>>();
+        let piece_length = 2 * BLOCK_SIZE;
+        let (a, rest) = data.split_at(BLOCK_SIZE + 5);
+        let (b, c) = rest.split_at(6 * BLOCK_SIZE);
+        let torrent = Torrent::for_files("content", &[("a", a), ("b", b), ("c", c)], piece_length);
+        let priorities = vec![FilePriority::High, FilePriority::Skip, FilePriority::Low];
+        let output = tempfile::tempdir().unwrap();
+        let storage = FileStorage::create_with_priorities(
+            &torrent,
+            output.path(),
+            Allocation::Sparse,
+            &priorities,
+        )
+        .await
+        .unwrap();
+
+        let downloader = Downloader::new(torrent, Configuration::default(), storage)
+            .unwrap()
+            .with_file_priorities(priorities);
+        let progress = downloader.progress();
+        let (peers_tx, peers_rx) = mpsc::channel(1);
+        let (peer, remote) = connect(&[0xff, 0xff]).await;
+        seed(remote, data.clone(), piece_length, &[]);
+        peers_tx.send(peer).await.unwrap();
+        drop(peers_tx);
+
+        downloader.download_from(peers_rx).await.unwrap();
+        let dir = output.path().join("content");
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), a);
+        assert!(!dir.join("b").exists());
+        assert_eq!(std::fs::read(dir.join("c")).unwrap(), c);
+        // Pieces 0 and 3 hold the boundaries of the skipped file, pieces 1 and 2 only its bytes.
+        let progress = progress.borrow();
+        assert_eq!((progress.pieces, progress.total_pieces), (3, 3));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_peers_over_class_connection_limit() {
+        let data = (0..4 * BLOCK_SIZE)
+            .map(|i| (i % 239) as u8)
+            .collect::<Vec<_>>();
+        let piece_length = 2 * BLOCK_SIZE;
+        let torrent = Torrent::for_data("content.bin", &data, piece_length);
+        let storage = MemoryStorage::new(&torrent).unwrap();
+        let loopback = PeerClass::new("loopback", vec!["127.0.0.0/8".parse().unwrap()])
+            .with_max_connections(1)
+            .with_priority(1);
+        let config =
+            Configuration::default().with_peer_classes(PeerClasses::default().with_class(loopback));
+        let downloader = Downloader::new(torrent, config, storage).unwrap();
+
+        let (peers_tx, peers_rx) = mpsc::channel(3);
+        let (peer, remote) = connect(&[0b1100_0000]).await;
+        seed(remote, data.clone(), piece_length, &[]);
+        peers_tx.send(peer).await.unwrap();
+        let mut refused = Vec::new();
+        for _ in 0..2 {
+            let (peer, remote) = connect(&[0b1100_0000]).await;
+            peers_tx.send(peer).await.unwrap();
+            refused.push(remote);
+        }
+        drop(peers_tx);
+
+        let mut storage = downloader.download_from(peers_rx).await.unwrap();
+        assert_eq!(
+            storage.read_block(1, 0, 4).await.unwrap(),
+            data[piece_length..][..4]
+        );
+        // The connections over the limit are closed without a message.
+        for mut remote in refused {
+            assert!(remote.next().await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bans_peer_that_sent_corrupt_block() {
+        let data = (0..2 * BLOCK_SIZE)
+            .map(|i| (i % 241) as u8)
+            .collect::<Vec<_>>();
+        let torrent = Torrent::for_data("content.bin", &data, data.len());
+        let storage = MemoryStorage::new(&torrent).unwrap();
+        let config = Configuration::default().with_max_hash_failures(100);
+        let filter = config.ip_filter().clone();
+        let downloader = Downloader::new(torrent, config, storage).unwrap();
+        let mut progress = downloader.progress();
+
+        // The honest peer only connects once the corrupt piece failed.
+        let (peers_tx, peers_rx) = mpsc::channel(2);
+        let (liar, remote) = connect(&[0b1000_0000]).await;
+        let liar_address = liar.address();
+        seed(remote, data.clone(), data.len(), &[0]);
+        peers_tx.send(liar).await.unwrap();
+        let (honest, remote) = connect(&[0b1000_0000]).await;
+        let honest_address = honest.address();
+        seed(remote, data.clone(), data.len(), &[]);
+        let connect_honest = async move {
+            progress
+                .wait_for(|progress| progress.failed > 0)
+                .await
+                .unwrap();
+            peers_tx.send(honest).await.unwrap();
+        };
+
+        let (storage, ()) = tokio::join!(downloader.download_from(peers_rx), connect_honest);
+        let mut storage = storage.unwrap();
+        assert_eq!(storage.read_block(0, 0, 4).await.unwrap(), data[..4]);
+        assert!(filter.is_blocked(*liar_address.ip()));
+        assert!(!filter.is_blocked(*honest_address.ip()));
+    }
+
+    #[tokio::test]
+    async fn test_download_fails_without_peers() {
+        let torrent = Torrent::for_data("content.bin", &[1; 100], BLOCK_SIZE);
+        let storage = MemoryStorage::new(&torrent).unwrap();
+        let downloader = Downloader::new(torrent, Configuration::default(), storage).unwrap();
+        let (peers_tx, peers_rx) = mpsc::channel::<Peer<tokio::io::DuplexStream>>(1);
+        drop(peers_tx);
+        assert!(downloader.download_from(peers_rx).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_resumes_from_saved_state() {
+        let data = (0..8 * BLOCK_SIZE)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let piece_length = 2 * BLOCK_SIZE;
+        let torrent = Torrent::for_data("content.bin", &data, piece_length);
+        let output = tempfile::tempdir().unwrap();
+        let resume = output.path().join("content.resume");
+        let start = |bitfield: [u8; 1]| {
+            let torrent = torrent.clone();
+            let (data, resume) = (data.clone(), resume.clone());
+            let root = output.path().to_path_buf();
+            async move {
+                let storage = FileStorage::create(&torrent, root, Allocation::Sparse)
+                    .await
+                    .unwrap();
+                let downloader = Downloader::new(torrent, Configuration::default(), storage)
+                    .unwrap()
+                    .with_resume(resume);
+                let (progress, control) = (downloader.progress(), downloader.control());
+                let (peers_tx, peers_rx) = mpsc::channel(1);
+                let (peer, remote) = connect(&bitfield).await;
+                seed(remote, data, piece_length, &[]);
+                peers_tx.send(peer).await.unwrap();
+                let download = tokio::spawn(downloader.download_from(peers_rx));
+                (download, progress, control)
+            }
+        };
+
+        // The first peer only has the first half, and the download is stopped once it is done.
+        let (download, mut progress, control) = start([0b1100_0000]).await;
+        progress
+            .wait_for(|progress| progress.pieces == 2)
+            .await
+            .unwrap();
+        control.stop();
+        assert!(download.await.unwrap().is_err());
+
+        // The second peer only has the second half, so the first half must come from the disk.
+        let (download, progress, _) = start([0b0011_0000]).await;
+        download.await.unwrap().unwrap();
+        assert_eq!(
+            std::fs::read(output.path().join("content.bin")).unwrap(),
+            data
+        );
+        assert_eq!(progress.borrow().downloaded, data.len());
+
+        // A file that changed since the resume data was saved is checked again, and the
+        // corrupt piece is downloaded again.
+        let path = output.path().join("content.bin");
+        std::fs::write(&path, [b"corrupt", &data[7..]].concat()).unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(std::time::UNIX_EPOCH).unwrap();
+        let (download, progress, _) = start([0b1111_0000]).await;
+        download.await.unwrap().unwrap();
+        assert_eq!(
+            std::fs::read(output.path().join("content.bin")).unwrap(),
+            data
+        );
+        assert_eq!(progress.borrow().pieces, 4);
+    }
+
+    #[tokio::test]
+    async fn test_serves_requests_from_verified_pieces() {
+        let data = (0..4 * BLOCK_SIZE)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let piece_length = 2 * BLOCK_SIZE;
+        let torrent = Torrent::for_data("content.bin", &data, piece_length);
+        let mut storage = MemoryStorage::new(&torrent).unwrap();
+        let mut pieces = BitField::new(2);
+        for piece_i in 0..2 {
+            let piece = &data[piece_i * piece_length..(piece_i + 1) * piece_length];
+            storage.write_block(piece_i, 0, piece).await.unwrap();
+            pieces.set_piece(piece_i);
+        }
+        let downloader = Downloader::new(torrent, Configuration::default(), storage)
+            .unwrap()
+            .with_pieces(pieces)
+            .with_keep_running();
+        let control = downloader.control();
+        let mut progress = downloader.progress();
+        let (peers_tx, peers_rx) = mpsc::channel(1);
+        let (peer, mut remote) = connect(&[]).await;
+        peers_tx.send(peer).await.unwrap();
+        let download = tokio::spawn(downloader.download_from(peers_rx));
+
+        let interested = Message::without_payload(MessageTag::Interested).unwrap();
+        remote.send(interested).await.unwrap();
+        let message = remote.next().await.unwrap().unwrap();
+        assert_eq!(message.tag(), &MessageTag::UnChoke);
+
+        // A request for more than a block is dropped, and so is one that was cancelled.
+        let cancelled = BlockRequest::new(1, 0, BLOCK_SIZE);
+        remote
+            .feed(BlockRequest::new(0, 0, BLOCK_SIZE + 1).request_message())
+            .await
+            .unwrap();
+        remote.feed(cancelled.request_message()).await.unwrap();
+        remote.feed(cancelled.cancel_message()).await.unwrap();
+        remote
+            .feed(BlockRequest::new(0, BLOCK_SIZE, BLOCK_SIZE).request_message())
+            .await
+            .unwrap();
+        remote.flush().await.unwrap();
+        let message = remote.next().await.unwrap().unwrap();
+        let block = Block::from_payload(message.payload()).unwrap();
+        assert_eq!((block.piece_i(), block.begin()), (0, BLOCK_SIZE));
+        assert_eq!(block.data(), &data[BLOCK_SIZE..2 * BLOCK_SIZE]);
+
+        let request = BlockRequest::new(1, BLOCK_SIZE, BLOCK_SIZE);
+        remote.send(request.request_message()).await.unwrap();
+        let message = remote.next().await.unwrap().unwrap();
+        let block = Block::from_payload(message.payload()).unwrap();
+        assert_eq!(block.request(), request);
+        assert_eq!(block.data(), &data[3 * BLOCK_SIZE..]);
+        let uploaded = progress
+            .wait_for(|progress| progress.uploaded >= 2 * BLOCK_SIZE)
+            .await
+            .unwrap()
+            .uploaded;
+        assert_eq!(uploaded, 2 * BLOCK_SIZE);
+
+        control.stop();
+        assert!(download.await.unwrap().is_ok());
+    }
+
+    /// Unchokes every interested peer, and counts how many times it is asked.
+    #[derive(Debug)]
+    struct CountingChoker(Arc<AtomicUsize>);
+
+    impl Choker for CountingChoker {
+        fn choose(&mut self, peers: &[PeerStats], _: bool, _: Instant) -> Vec<SocketAddrV4> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            peers
+                .iter()
+                .filter(|peer| peer.interested)
+                .map(|peer| peer.address)
+                .collect()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_runs_choker_once_for_a_burst_of_interest() {
+        let data = vec![7; BLOCK_SIZE];
+        let torrent = Torrent::for_data("content.bin", &data, BLOCK_SIZE);
+        let mut storage = MemoryStorage::new(&torrent).unwrap();
+        storage.write_block(0, 0, &data).await.unwrap();
+        let mut pieces = BitField::new(1);
+        pieces.set_piece(0);
+        let calls = Arc::new(AtomicUsize::new(0));
+        let downloader = Downloader::new(torrent, Configuration::default(), storage)
+            .unwrap()
+            .with_pieces(pieces)
+            .with_choker(CountingChoker(calls.clone()))
+            .with_keep_running();
+        let control = downloader.control();
+        let (peers_tx, peers_rx) = mpsc::channel(1);
+        let (peer, mut remote) = connect(&[]).await;
+        peers_tx.send(peer).await.unwrap();
+        let download = tokio::spawn(downloader.download_from(peers_rx));
+
+        // The first round of the choker runs as the download starts.
+        for tag in [
+            MessageTag::Interested,
+            MessageTag::NotInterested,
+            MessageTag::Interested,
+        ] {
+            remote
+                .feed(Message::without_payload(tag).unwrap())
+                .await
+                .unwrap();
+        }
+        remote.flush().await.unwrap();
+        let message = remote.next().await.unwrap().unwrap();
+        assert_eq!(message.tag(), &MessageTag::UnChoke);
+        assert_eq!(calls.load(Ordering::Relaxed), 2);
+
+        // An unchoked peer that loses interest gets its slot taken away right away.
+        let not_interested = Message::without_payload(MessageTag::NotInterested).unwrap();
+        remote.send(not_interested).await.unwrap();
+        let message = remote.next().await.unwrap().unwrap();
+        assert_eq!(message.tag(), &MessageTag::Choke);
+        assert_eq!(calls.load(Ordering::Relaxed), 3);
+
+        control.stop();
+        assert!(download.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_super_seeding_offers_a_piece_at_a_time() {
+        let data = (0..4 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = Torrent::for_data("content.bin", &data, BLOCK_SIZE);
+        let mut storage = MemoryStorage::new(&torrent).unwrap();
+        let mut pieces = BitField::new(4);
+        for piece_i in 0..4 {
+            let piece = &data[piece_i * BLOCK_SIZE..(piece_i + 1) * BLOCK_SIZE];
+            storage.write_block(piece_i, 0, piece).await.unwrap();
+            pieces.set_piece(piece_i);
+        }
+        let downloader = Downloader::new(torrent, Configuration::default(), storage)
+            .unwrap()
+            .with_pieces(pieces)
+            .with_super_seeding()
+            .with_keep_running();
+        let (peers_tx, peers_rx) = mpsc::channel(2);
+        let (first, mut first_remote) = connect(&[]).await;
+        let (second, mut second_remote) = connect(&[]).await;
+        peers_tx.send(first).await.unwrap();
+        peers_tx.send(second).await.unwrap();
+        let _download = tokio::spawn(downloader.download_from(peers_rx));
+
+        let message = first_remote.next().await.unwrap().unwrap();
+        assert_eq!(message.tag(), &MessageTag::Have);
+        let first_piece = parse_have(message.payload()).unwrap();
+        let message = second_remote.next().await.unwrap().unwrap();
+        let second_piece = parse_have(message.payload()).unwrap();
+        assert_ne!(first_piece, second_piece);
+
+        // The first peer is offered another piece once its piece shows up at the second peer.
+        second_remote.send(have_message(first_piece)).await.unwrap();
+        let message = first_remote.next().await.unwrap().unwrap();
+        assert_eq!(message.tag(), &MessageTag::Have);
+        let next_piece = parse_have(message.payload()).unwrap();
+        assert!(![first_piece, second_piece].contains(&next_piece));
+
+        // Pieces that were not offered are not served.
+        let interested = Message::without_payload(MessageTag::Interested).unwrap();
+        first_remote.send(interested).await.unwrap();
+        let message = first_remote.next().await.unwrap().unwrap();
+        assert_eq!(message.tag(), &MessageTag::UnChoke);
+        let hidden = BlockRequest::new(second_piece, 0, BLOCK_SIZE);
+        first_remote.send(hidden.request_message()).await.unwrap();
+        let offered = BlockRequest::new(next_piece, 0, BLOCK_SIZE);
+        first_remote.send(offered.request_message()).await.unwrap();
+        let message = first_remote.next().await.unwrap().unwrap();
+        let block = Block::from_payload(message.payload()).unwrap();
+        assert_eq!(block.request(), offered);
+    }
+
+    #[tokio::test]
+    async fn test_requests_critical_pieces_first() {
+        let data = (0..8 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = Torrent::for_data("content.bin", &data, 2 * BLOCK_SIZE);
+        let storage = MemoryStorage::new(&torrent).unwrap();
+        let downloader = Downloader::new(torrent, Configuration::default(), storage)
+            .unwrap()
+            .with_strategy(Strategy::Sequential);
+        let control = downloader.control();
+        let (peers_tx, peers_rx) = mpsc::channel(1);
+        let (peer, mut remote) = connect(&[0b1111_0000]).await;
+        peers_tx.send(peer).await.unwrap();
+        let _download = tokio::spawn(downloader.download_from(peers_rx));
+
+        control.set_deadline(3..4, Duration::from_secs(1));
+        // Reads are answered in order, so an empty one returns once the deadline is set.
+        assert!(control.read(0..0).await.unwrap().is_empty());
+        let unchoke = Message::without_payload(MessageTag::UnChoke).unwrap();
+        remote.send(unchoke).await.unwrap();
+        let mut requests = Vec::new();
+        while requests.len() < 2 {
+            let message = remote.next().await.unwrap().unwrap();
+            if message.tag() == &MessageTag::Request {
+                requests.push(BlockRequest::from_payload(message.payload()).unwrap());
+            }
+        }
+        assert!(requests.iter().all(|request| request.piece_i() == 3));
+    }
+
+    #[tokio::test]
+    async fn test_streams_content_as_it_downloads() {
+        let data = (0..12 * BLOCK_SIZE)
+            .map(|i| (i % 239) as u8)
+            .collect::<Vec<_>>();
+        let piece_length = 2 * BLOCK_SIZE;
+        let torrent = Torrent::for_data("content.bin", &data, piece_length);
+        let storage = MemoryStorage::new(&torrent).unwrap();
+        let downloader = Downloader::new(torrent, Configuration::default(), storage).unwrap();
+        let control = downloader.control();
+        // Nobody has the last piece, so the download keeps running while the stream is read.
+        let (peers_tx, peers_rx) = mpsc::channel(1);
+        let (peer, remote) = connect(&[0b1111_1000]).await;
+        seed(remote, data.clone(), piece_length, &[]);
+        peers_tx.send(peer).await.unwrap();
+        let download = tokio::spawn(downloader.download_from(peers_rx));
+
+        let range = 100..5 * piece_length - 10;
+        let mut stream = control.stream(range.clone());
+        let mut streamed = Vec::new();
+        while let Some(chunk) = stream.next_chunk().await.unwrap() {
+            assert_eq!(
+                stream.position(),
+                range.start + streamed.len() + chunk.len()
+            );
+            streamed.extend(chunk);
+        }
+        assert_eq!(streamed, data[range]);
+        assert!(control.read(0..data.len() + 1).await.is_err());
+
+        control.stop();
+        assert!(download.await.unwrap().is_err());
+        assert!(control.read(0..1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_endgame_requests_stalled_blocks_from_other_peers() {
+        let data = (0..2 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let torrent = Torrent::for_data("content.bin", &data, 2 * BLOCK_SIZE);
+        let storage = MemoryStorage::new(&torrent).unwrap();
+        let downloader = Downloader::new(torrent, Configuration::default(), storage).unwrap();
+        let progress = downloader.progress();
+        let (peers_tx, peers_rx) = mpsc::channel(2);
+
+        // The first peer unchokes us, but never answers its requests.
+        let (peer, mut stalled) = connect(&[0b1000_0000]).await;
+        peers_tx.send(peer).await.unwrap();
+        let download = tokio::spawn(downloader.download_from(peers_rx));
+        let mut requests = Vec::new();
+        while requests.len() < 2 {
+            let message = stalled.next().await.unwrap().unwrap();
+            match message.tag() {
+                MessageTag::Interested => {
+                    let unchoke = Message::without_payload(MessageTag::UnChoke).unwrap();
+                    stalled.send(unchoke).await.unwrap();
+                }
+                MessageTag::Request => {
+                    requests.push(BlockRequest::from_payload(message.payload()).unwrap())
+                }
+                _ => {}
+            }
+        }
+
+        // Every block is in flight, so the second peer is asked for the same blocks.
+        let (peer, remote) = connect(&[0b1000_0000]).await;
+        seed(remote, data.clone(), 2 * BLOCK_SIZE, &[]);
+        peers_tx.send(peer).await.unwrap();
+        let mut cancelled = Vec::new();
+        while cancelled.len() < 2 {
+            let message = stalled.next().await.unwrap().unwrap();
+            if message.tag() == &MessageTag::Cancel {
+                cancelled.push(BlockRequest::from_payload(message.payload()).unwrap());
+            }
+        }
+        cancelled.sort();
+        assert_eq!(cancelled, requests);
+
+        let storage = download.await.unwrap().unwrap();
+        assert_eq!(storage.data(), data);
+        assert!(progress.borrow().endgame);
+    }
+}
@@ -0,0 +1,93 @@
+use std::ops::Range;
+
+use tokio::sync::oneshot;
+
+use crate::net::bitfield::BitField;
+use crate::storage::Storage;
+
+/// A read of the content that waits for its pieces.
+struct PendingRead {
+    range: Range<usize>,
+    reply: oneshot::Sender<anyhow::Result<Vec<u8>>>,
+}
+
+/// The reads of the content made through a [`DownloadControl`], which are answered once
+/// every piece they overlap has been verified.
+///
+/// [`DownloadControl`]: super::DownloadControl
+pub(super) struct Reads {
+    length: usize,
+    piece_length: usize,
+    pending: Vec<PendingRead>,
+}
+
+impl Reads {
+    /// Creates the reads of a content of `length` bytes, split in pieces of `piece_length`.
+    pub(super) fn new(length: usize, piece_length: usize) -> Self {
+        Self {
+            length,
+            piece_length,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Checks that a range lies within the content.
+    pub(super) fn check(&self, range: &Range<usize>) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            range.start <= range.end && range.end <= self.length,
+            "Range {}..{} is out of the content of {} bytes.",
+            range.start,
+            range.end,
+            self.length
+        );
+        Ok(())
+    }
+
+    /// Returns the pieces that a range of the content overlaps.
+    pub(super) fn pieces_of(&self, range: &Range<usize>) -> Range<usize> {
+        range.start / self.piece_length..range.end.div_ceil(self.piece_length)
+    }
+
+    /// Keeps a read until [`Reads::serve`] finds its pieces.
+    pub(super) fn push(
+        &mut self,
+        range: Range<usize>,
+        reply: oneshot::Sender<anyhow::Result<Vec<u8>>>,
+    ) {
+        self.pending.push(PendingRead { range, reply });
+    }
+
+    /// Answers the pending reads whose pieces are all in `have`, from `storage`.
+    pub(super) async fn serve(&mut self, have: &BitField, storage: &mut impl Storage) {
+        let mut i = 0;
+        while i < self.pending.len() {
+            let range = self.pending[i].range.clone();
+            if !self
+                .pieces_of(&range)
+                .all(|piece_i| have.contains_piece(piece_i))
+            {
+                i += 1;
+                continue;
+            }
+            let read = self.pending.swap_remove(i);
+            let _ = read.reply.send(self.read_range(storage, range).await);
+        }
+    }
+
+    /// Reads a range of the content from the storage, piece by piece.
+    async fn read_range(
+        &self,
+        storage: &mut impl Storage,
+        range: Range<usize>,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(range.len());
+        let mut offset = range.start;
+        while offset < range.end {
+            let (piece_i, begin) = (offset / self.piece_length, offset % self.piece_length);
+            let length = (self.piece_length - begin).min(range.end - offset);
+            data.extend(storage.read_block(piece_i, begin, length).await?);
+            offset += length;
+        }
+        Ok(data)
+    }
+}
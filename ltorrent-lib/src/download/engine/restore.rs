@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::download::downloaded::DownloadedPiece;
+use crate::download::resume::{PartialPiece, ResumeData};
+use crate::net::bitfield::BitField;
+use crate::net::block::{Block, BLOCK_SIZE};
+use crate::piece::hasher::PieceHashes;
+use crate::storage::Storage;
+
+use super::{Engine, InProgress};
+
+/// Restoring the state of a download from its resume data and its storage, and saving it.
+impl<T: Storage> Engine<'_, T> {
+    /// Loads the resume data at `path`, and marks the pieces it lists as downloaded if the
+    /// files have not changed since it was saved. The finished blocks of partial pieces are
+    /// read back from the storage, so that only the missing blocks are requested.
+    ///
+    /// If the files have changed, every piece in the storage is hashed again instead.
+    pub(super) async fn restore(&mut self, path: &Path) -> anyhow::Result<()> {
+        let Some(data) = ResumeData::load(path).await? else {
+            return Ok(());
+        };
+        let info_hash = self
+            .torrent
+            .info_hash()
+            .context("Failed to hash info dictionary.")?;
+        let files = self.storage.file_stats().await?;
+        if !data.matches(&info_hash, files.as_deref()) {
+            return self.recheck().await;
+        }
+
+        for piece_i in &data.pieces(self.torrent.n_pieces()) {
+            self.pieces.complete(piece_i);
+        }
+        let n_have = self.pieces.n_have();
+        self.progress.send_modify(|progress| {
+            progress.pieces = n_have;
+            progress.downloaded = data.downloaded() as usize;
+            progress.uploaded = data.uploaded() as usize;
+        });
+        for partial in data.partial() {
+            self.restore_piece(partial).await?;
+        }
+        Ok(())
+    }
+
+    /// Puts the finished blocks of a partial piece back in progress.
+    async fn restore_piece(&mut self, partial: &PartialPiece) -> anyhow::Result<()> {
+        let piece_i = partial.piece_i();
+        if self.pieces.has_piece(piece_i) {
+            return Ok(());
+        }
+        let length = self.torrent.get_piece_length(piece_i)?;
+        let mut piece = DownloadedPiece::new(piece_i, length);
+        self.requester.add_piece(piece_i, length);
+        for block_i in (0..length.div_ceil(BLOCK_SIZE)).filter(|&i| partial.has_block(i)) {
+            let begin = block_i * BLOCK_SIZE;
+            let data = self
+                .storage
+                .read_block(piece_i, begin, BLOCK_SIZE.min(length - begin))
+                .await?;
+            piece.add_block(&Block::new(piece_i, begin, data))?;
+            self.requester.mark_received(piece_i, block_i);
+        }
+        let complete = piece.is_complete();
+        self.pieces.start(piece_i);
+        self.in_progress.insert(
+            piece_i,
+            InProgress {
+                piece,
+                senders: HashMap::new(),
+            },
+        );
+        if complete {
+            self.finish_piece(piece_i).await?;
+        }
+        Ok(())
+    }
+
+    /// Marks pieces that were verified before the download started as downloaded.
+    pub(super) fn add_verified(&mut self, pieces: &BitField) -> anyhow::Result<()> {
+        for piece_i in pieces {
+            if piece_i >= self.torrent.n_pieces() || self.pieces.has_piece(piece_i) {
+                continue;
+            }
+            let length = self.torrent.get_piece_length(piece_i)?;
+            self.pieces.complete(piece_i);
+            self.progress.send_modify(|progress| {
+                progress.pieces += 1;
+                progress.downloaded += length;
+            });
+        }
+        Ok(())
+    }
+
+    /// Hashes every piece in the storage, and marks those that match as downloaded.
+    ///
+    /// The pieces are read one after the other, and hashed in parallel on the hash pool.
+    async fn recheck(&mut self) -> anyhow::Result<()> {
+        let mut hashes = PieceHashes::new(self.hashes.clone());
+        let mut to_read = 0..self.torrent.n_pieces();
+        loop {
+            while !hashes.is_full() {
+                let Some(piece_i) = to_read.next() else {
+                    break;
+                };
+                let length = self.torrent.get_piece_length(piece_i)?;
+                let data = self.storage.read_block(piece_i, 0, length).await?;
+                hashes.push(piece_i, length, Some(data));
+            }
+            let Some((piece_i, length, actual)) = hashes.next().await else {
+                break;
+            };
+            if actual.as_ref() == Some(self.torrent.get_piece_hash(piece_i)?) {
+                self.pieces.complete(piece_i);
+                self.progress.send_modify(|progress| {
+                    progress.pieces += 1;
+                    progress.downloaded += length;
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the resume data of the download to `path`.
+    ///
+    /// The blocks of the pieces in progress are only held in memory until their piece is
+    /// complete, so they are written to the storage first, for the resume data to point at.
+    pub(super) async fn save_resume(&mut self, path: &Path) -> anyhow::Result<()> {
+        let mut partial = Vec::new();
+        for (&piece_i, in_progress) in &self.in_progress {
+            let mut blocks = Vec::new();
+            for (begin, data) in in_progress.piece.received_blocks() {
+                self.storage.write_block(piece_i, begin, data).await?;
+                blocks.push(begin / BLOCK_SIZE);
+            }
+            if !blocks.is_empty() {
+                partial.push(PartialPiece::new(piece_i, blocks));
+            }
+        }
+        self.storage.flush().await?;
+
+        let info_hash = self
+            .torrent
+            .info_hash()
+            .context("Failed to hash info dictionary.")?;
+        let files = self.storage.file_stats().await?.unwrap_or_default();
+        let (downloaded, uploaded) = {
+            let progress = self.progress.borrow();
+            (progress.downloaded as u64, progress.uploaded as u64)
+        };
+        ResumeData::new(
+            info_hash,
+            self.pieces.have(),
+            partial,
+            files,
+            uploaded,
+            downloaded,
+        )
+        .save(path)
+        .await
+    }
+}
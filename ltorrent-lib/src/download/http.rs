@@ -0,0 +1,313 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::storage::{FileEntry, FileLayout};
+
+use super::engine::DownloadControl;
+use super::priority::FilePriority;
+
+/// Maximum size of the request line and headers of a request.
+const MAX_HEAD_SIZE: usize = 8 * 1024;
+
+/// A running download whose files are served.
+#[derive(Debug, Clone)]
+struct Served {
+    layout: FileLayout,
+    control: DownloadControl,
+}
+
+/// Serves the files of running downloads over HTTP while they download.
+///
+/// A file is served at `/<info hash>/<path>`, with the info hash in hex and the path as
+/// accepted by [`FileLayout::find`]. `GET` and `HEAD` requests are supported, and a request
+/// for a single byte range with a `Range` header is answered with `206 Partial Content`, so
+/// that media players can seek.
+///
+/// The pieces a response overlaps get the priority of [`FilePriority::High`], and the
+/// response is streamed with a [`StreamReader`](super::stream::StreamReader), so every part
+/// of it is sent as soon as its piece is verified. The downloads should be kept running with
+/// [`Downloader::with_keep_running`](super::engine::Downloader::with_keep_running), to serve
+/// their files once they complete.
+#[derive(Debug, Clone, Default)]
+pub struct StreamServer {
+    torrents: HashMap<String, Served>,
+}
+
+impl StreamServer {
+    /// Creates a server that serves no download yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves the files of a running download, laid out as in `layout`.
+    pub fn add(&mut self, info_hash: &[u8; 20], layout: FileLayout, control: DownloadControl) {
+        self.torrents
+            .insert(hex::encode(info_hash), Served { layout, control });
+    }
+
+    /// Accepts connections on `listener` and answers their requests, until accepting fails.
+    ///
+    /// Every connection is handled on a task of its own, and closed after one response.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a connection cannot be accepted.
+    pub async fn serve(self, listener: TcpListener) -> anyhow::Result<()> {
+        let server = Arc::new(self);
+        loop {
+            let (stream, _) = listener
+                .accept()
+                .await
+                .context("Failed to accept connection.")?;
+            let server = Arc::clone(&server);
+            tokio::spawn(async move {
+                let _ = server.handle(stream).await;
+            });
+        }
+    }
+
+    async fn handle(&self, mut stream: TcpStream) -> anyhow::Result<()> {
+        let head = read_head(&mut stream).await?;
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        let (Ok(httparse::Status::Complete(_)), Some(method), Some(target)) =
+            (request.parse(&head), request.method, request.path)
+        else {
+            return write_head(&mut stream, "400 Bad Request", &[]).await;
+        };
+        if method != "GET" && method != "HEAD" {
+            return write_head(&mut stream, "405 Method Not Allowed", &[("Allow", "GET, HEAD")])
+                .await;
+        }
+        let Some((served, entry)) = self.find(target) else {
+            return write_head(&mut stream, "404 Not Found", &[]).await;
+        };
+
+        let length = entry.length();
+        let range = request
+            .headers
+            .iter()
+            .find(|header| header.name.eq_ignore_ascii_case("range"))
+            .and_then(|header| std::str::from_utf8(header.value).ok());
+        let (status, range) = match range {
+            // Several ranges would need a multipart response, so the whole file is sent.
+            Some(range) if !range.contains(',') => match parse_range(range, length) {
+                Some(range) => ("206 Partial Content", range),
+                None => {
+                    let content_range = format!("bytes */{length}");
+                    return write_head(
+                        &mut stream,
+                        "416 Range Not Satisfiable",
+                        &[("Content-Range", &content_range)],
+                    )
+                    .await;
+                }
+            },
+            _ => ("200 OK", 0..length),
+        };
+
+        let content_length = range.len().to_string();
+        let content_range = format!("bytes {}-{}/{}", range.start, range.end.max(1) - 1, length);
+        let mut headers = vec![
+            ("Content-Type", content_type(entry.path())),
+            ("Content-Length", content_length.as_str()),
+            ("Accept-Ranges", "bytes"),
+        ];
+        if status.starts_with("206") {
+            headers.push(("Content-Range", &content_range));
+        }
+        write_head(&mut stream, status, &headers).await?;
+        if method == "HEAD" || range.is_empty() {
+            return Ok(());
+        }
+
+        let range = entry.offset() + range.start..entry.offset() + range.end;
+        let control = &served.control;
+        let piece_length = control.piece_length();
+        control.raise_priority(
+            range.start / piece_length..range.end.div_ceil(piece_length),
+            FilePriority::High.piece_priority(),
+        );
+        let mut reader = control.stream(range);
+        while let Some(chunk) = reader.next_chunk().await? {
+            stream.write_all(&chunk).await?;
+        }
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// Returns the download and the file a request target points at.
+    fn find(&self, target: &str) -> Option<(&Served, &FileEntry)> {
+        let path = target.split(['?', '#']).next()?.strip_prefix('/')?;
+        let (info_hash, path) = path.split_once('/')?;
+        let served = self.torrents.get(&info_hash.to_ascii_lowercase())?;
+        let path = percent_encoding::percent_decode_str(path)
+            .decode_utf8()
+            .ok()?;
+        let entry = served.layout.find(path.as_ref())?;
+        Some((served, entry))
+    }
+}
+
+/// Reads the request line and headers of a request, up to the empty line that ends them.
+async fn read_head(stream: &mut TcpStream) -> anyhow::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        anyhow::ensure!(head.len() < MAX_HEAD_SIZE, "Request head is too large.");
+        let n = stream.read(&mut buf).await?;
+        anyhow::ensure!(n > 0, "Connection closed before the end of the request head.");
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(head)
+}
+
+/// Writes the status line and headers of a response, which closes the connection.
+async fn write_head(
+    stream: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+) -> anyhow::Result<()> {
+    let mut head = format!("HTTP/1.1 {status}\r\nConnection: close\r\n");
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    if !headers.iter().any(|(name, _)| *name == "Content-Length") {
+        head.push_str("Content-Length: 0\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    Ok(())
+}
+
+/// Parses the value of a `Range` header for a single byte range into a range of a file of
+/// `length` bytes, or returns `None` if it is malformed or cannot be satisfied.
+fn parse_range(value: &str, length: usize) -> Option<Range<usize>> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => length.saturating_sub(suffix.parse().ok()?)..length,
+        (start, "") => start.parse().ok()?..length,
+        (start, end) => {
+            let end = end.parse::<usize>().ok()?.saturating_add(1).min(length);
+            start.parse().ok()?..end
+        }
+    };
+    (range.start < range.end).then_some(range)
+}
+
+/// Returns the media type of a file from its extension.
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+        Some("mp4" | "m4v") => "video/mp4",
+        Some("mkv") => "video/x-matroska",
+        Some("webm") => "video/webm",
+        Some("avi") => "video/x-msvideo",
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("ogg") => "audio/ogg",
+        Some("txt" | "log") => "text/plain",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::config::Configuration;
+    use crate::download::engine::Downloader;
+    use crate::net::block::BLOCK_SIZE;
+    use crate::net::session::tests::{connect, seed};
+    use crate::storage::memory::MemoryStorage;
+    use crate::torrent::Torrent;
+
+    #[test]
+    fn test_parses_ranges() {
+        assert_eq!(parse_range("bytes=10-19", 100), Some(10..20));
+        assert_eq!(parse_range("bytes=90-", 100), Some(90..100));
+        assert_eq!(parse_range("bytes=-30", 100), Some(70..100));
+        assert_eq!(parse_range("bytes=50-500", 100), Some(50..100));
+        assert_eq!(parse_range("bytes=100-", 100), None);
+        assert_eq!(parse_range("bytes=20-10", 100), None);
+        assert_eq!(parse_range("items=0-10", 100), None);
+    }
+
+    async fn get(address: std::net::SocketAddr, request: &str) -> (String, Vec<u8>) {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let end = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+        let body = response.split_off(end);
+        (String::from_utf8(response).unwrap(), body)
+    }
+
+    #[tokio::test]
+    async fn test_serves_ranges_of_files() {
+        let data = (0..6 * BLOCK_SIZE)
+            .map(|i| (i % 233) as u8)
+            .collect::<Vec<_>>();
+        let piece_length = 2 * BLOCK_SIZE;
+        let (first, second) = data.split_at(BLOCK_SIZE + 3);
+        let torrent = Torrent::for_files(
+            "media",
+            &[("a.bin", first), ("clip one.mp4", second)],
+            piece_length,
+        );
+        let info_hash = torrent.info_hash().unwrap();
+        let layout = FileLayout::new(&torrent).unwrap();
+        let storage = MemoryStorage::new(&torrent).unwrap();
+        let downloader = Downloader::new(torrent, Configuration::default(), storage)
+            .unwrap()
+            .with_keep_running();
+        let control = downloader.control();
+        let (peers_tx, peers_rx) = mpsc::channel(1);
+        let (peer, remote) = connect(&[0b1110_0000]).await;
+        seed(remote, data.clone(), piece_length, &[]);
+        peers_tx.send(peer).await.unwrap();
+        let download = tokio::spawn(downloader.download_from(peers_rx));
+
+        let mut server = StreamServer::new();
+        server.add(&info_hash, layout, control.clone());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(server.serve(listener));
+
+        let path = format!("/{}/clip%20one.mp4", hex::encode(info_hash));
+        let (head, body) = get(
+            address,
+            &format!("GET {path} HTTP/1.1\r\nRange: bytes=10-1009\r\n\r\n"),
+        )
+        .await;
+        assert!(head.starts_with("HTTP/1.1 206 Partial Content\r\n"));
+        let content_range = format!("Content-Range: bytes 10-1009/{}\r\n", second.len());
+        assert!(head.contains(&content_range));
+        assert!(head.contains("Content-Type: video/mp4\r\n"));
+        assert_eq!(body, second[10..1010]);
+
+        let (head, body) = get(address, &format!("GET {path} HTTP/1.1\r\n\r\n")).await;
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert_eq!(body, second);
+
+        let request = format!("GET {path} HTTP/1.1\r\nRange: bytes=999999-\r\n\r\n");
+        let (head, _) = get(address, &request).await;
+        assert!(head.starts_with("HTTP/1.1 416"));
+        let missing = format!("GET /{}/b.bin HTTP/1.1\r\n\r\n", hex::encode(info_hash));
+        let (head, _) = get(address, &missing).await;
+        assert!(head.starts_with("HTTP/1.1 404"));
+
+        control.stop();
+        assert!(download.await.unwrap().is_ok());
+    }
+}
@@ -0,0 +1,167 @@
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::time::Instant;
+
+use crate::net::block::{parse_have, Block};
+use crate::net::message::{Message, MessageTag};
+use crate::net::peers::Peer;
+use crate::torrent::Torrent;
+
+use self::downloaded::DownloadedPiece;
+use self::requester::{BlockOutcome, BlockRequester, REQUEST_TIMEOUT};
+
+pub mod choker;
+pub mod connections;
+pub mod downloaded;
+pub mod engine;
+pub mod http;
+pub mod priority;
+pub mod requester;
+pub mod resume;
+pub mod smartban;
+pub mod stream;
+pub mod superseed;
+
+/// Downloads a single piece from a connected peer, and checks it against its hash.
+///
+/// It tells the peer we are interested, waits to be unchoked, and requests all the blocks of
+/// the piece, keeping several requests in flight. If the peer chokes us halfway through, the
+/// dropped requests are sent again once it unchokes us.
+///
+/// # Errors
+///
+/// This function will return an error if:
+/// - The piece index is out of bounds, or the peer does not have the piece.
+/// - The peer closes the connection, or does not send a block of the piece for
+///   [`REQUEST_TIMEOUT`], whatever else it sends in the meantime.
+/// - The peer sends a malformed message.
+/// - The downloaded piece does not match its hash.
+pub async fn download_piece<S>(
+    torrent: &Torrent,
+    piece_i: usize,
+    peer: &mut Peer<S>,
+) -> anyhow::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hash = torrent.get_piece_hash(piece_i)?;
+    let length = torrent.get_piece_length(piece_i)?;
+    anyhow::ensure!(
+        peer.has_piece(piece_i),
+        "Peer does not have piece {}.",
+        piece_i
+    );
+
+    let address = peer.address();
+    let mut requester = BlockRequester::new();
+    requester.add_peer(address);
+    requester.add_piece(piece_i, length);
+    let mut piece = DownloadedPiece::new(piece_i, length);
+
+    peer.send(Message::without_payload(MessageTag::Interested)?)
+        .await
+        .context("Failed to send Interested message.")?;
+    let mut choked = true;
+    // Only blocks of the piece push the deadline back, so that a peer that keeps the
+    // connection alive without ever unchoking us is given up on.
+    let mut deadline = Instant::now() + REQUEST_TIMEOUT;
+
+    while !piece.is_complete() {
+        if !choked {
+            for request in requester.fill(address, |_| true) {
+                peer.send(request.request_message())
+                    .await
+                    .context("Failed to send Request message.")?;
+            }
+        }
+
+        let message = tokio::time::timeout_at(deadline, peer.next())
+            .await
+            .context("Timed out waiting for the peer.")?
+            .context("Peer closed the connection.")??;
+        match message.tag() {
+            MessageTag::Choke => {
+                choked = true;
+                requester.requeue(address);
+            }
+            MessageTag::UnChoke => choked = false,
+            MessageTag::Have => {
+                let have = parse_have(message.payload())?;
+                peer.bitfield_mut().set_piece(have);
+            }
+            MessageTag::Piece => {
+                let block = Block::from_payload(message.payload())?;
+                if let BlockOutcome::Accepted { .. } = requester.on_block(address, &block) {
+                    piece.add_block(&block)?;
+                    deadline = Instant::now() + REQUEST_TIMEOUT;
+                }
+            }
+            // We are not uploading, so the rest of the messages are of no interest.
+            _ => {}
+        }
+    }
+
+    piece.verify(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures_util::SinkExt;
+
+    use super::*;
+    use crate::net::block::{have_message, BLOCK_SIZE};
+    use crate::net::session::tests::{connect, seed};
+
+    #[tokio::test]
+    async fn test_download_piece() {
+        let data = (0..3 * BLOCK_SIZE + 5)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+        let piece_length = 2 * BLOCK_SIZE;
+        let torrent = Torrent::for_data("test", &data, piece_length);
+        let (mut peer, remote) = connect(&[0b1100_0000]).await;
+        seed(remote, data.clone(), piece_length, &[]);
+
+        // The last piece is truncated to the end of the content.
+        let piece = download_piece(&torrent, 1, &mut peer).await.unwrap();
+        assert_eq!(piece, data[piece_length..]);
+        let piece = download_piece(&torrent, 0, &mut peer).await.unwrap();
+        assert_eq!(piece, data[..piece_length]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_gives_up_on_peer_that_never_unchokes() {
+        let data = vec![7; BLOCK_SIZE];
+        let torrent = Torrent::for_data("test", &data, BLOCK_SIZE);
+        let (mut peer, mut remote) = connect(&[0b1000_0000]).await;
+        // The peer keeps the connection alive, and announces pieces, but never unchokes us.
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                let keep_alive = Message::without_payload(MessageTag::KeepAlive).unwrap();
+                if remote.send(keep_alive).await.is_err() {
+                    return;
+                }
+                if remote.send(have_message(0)).await.is_err() {
+                    return;
+                }
+            }
+        });
+
+        let start = Instant::now();
+        assert!(download_piece(&torrent, 0, &mut peer).await.is_err());
+        assert_eq!(start.elapsed(), REQUEST_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn test_download_corrupt_piece() {
+        let data = vec![7; BLOCK_SIZE];
+        let torrent = Torrent::for_data("test", &data, BLOCK_SIZE);
+        let (mut peer, remote) = connect(&[0b1000_0000]).await;
+        seed(remote, data, BLOCK_SIZE, &[0]);
+
+        assert!(download_piece(&torrent, 0, &mut peer).await.is_err());
+    }
+}
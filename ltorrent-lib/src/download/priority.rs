@@ -0,0 +1,107 @@
+use std::str::FromStr;
+
+use crate::piece::DEFAULT_PRIORITY;
+use crate::storage::FileLayout;
+
+/// How much a file of the torrent is wanted.
+///
+/// Priorities are parsed from their names: `skip`, `low`, `normal` and `high`.
+///
+/// # Examples
+///
+/// ```
+/// use ltorrent::download::priority::FilePriority;
+///
+/// let priority: FilePriority = "skip".parse().unwrap();
+/// assert_eq!(priority, FilePriority::Skip);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum FilePriority {
+    /// The file is not downloaded, and not even created.
+    Skip,
+    /// The file is downloaded after the others, as far as the strategy allows.
+    Low,
+    /// The file is downloaded along with the others.
+    #[default]
+    Normal,
+    /// The file is downloaded before the others, as far as the strategy allows.
+    High,
+}
+
+impl FilePriority {
+    /// Returns the priority of the pieces that overlap the file, as used by the piece pickers.
+    pub fn piece_priority(&self) -> u8 {
+        match self {
+            Self::Skip => 0,
+            Self::Low => 1,
+            Self::Normal => DEFAULT_PRIORITY,
+            Self::High => 2 * DEFAULT_PRIORITY,
+        }
+    }
+}
+
+impl FromStr for FilePriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => Err(anyhow::anyhow!(
+                "Unknown priority {s}. Expected skip, low, normal or high."
+            )),
+        }
+    }
+}
+
+/// Returns the priority of every piece, given the priorities of the files in `layout`.
+///
+/// A piece takes the highest priority of the files it overlaps, so the pieces at the
+/// boundary between a wanted file and a skipped one are still downloaded.
+///
+/// # Errors
+///
+/// This function will return an error if there is not one priority per file.
+pub fn piece_priorities(layout: &FileLayout, files: &[FilePriority]) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(
+        files.len() == layout.files().len(),
+        "Expected {} file priorities, got {}.",
+        layout.files().len(),
+        files.len()
+    );
+    let mut priorities = Vec::with_capacity(layout.n_pieces());
+    for piece_i in 0..layout.n_pieces() {
+        let slices = layout.map_block(piece_i, 0, layout.piece_length(piece_i)?)?;
+        let priority = slices
+            .iter()
+            .map(|slice| files[slice.file_i()])
+            .max()
+            .unwrap_or_default();
+        priorities.push(priority.piece_priority());
+    }
+    Ok(priorities)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Torrent;
+
+    #[test]
+    fn test_boundary_pieces_take_highest_priority() {
+        let torrent = Torrent::for_files(
+            "dir",
+            &[("a", &[0; 6][..]), ("b", &[0; 6][..]), ("c", &[0; 4][..])],
+            4,
+        );
+        let layout = FileLayout::new(&torrent).unwrap();
+        let files = [FilePriority::High, FilePriority::Skip, FilePriority::Low];
+        assert_eq!(
+            piece_priorities(&layout, &files).unwrap(),
+            [2 * DEFAULT_PRIORITY, 2 * DEFAULT_PRIORITY, 0, 1]
+        );
+        assert!(piece_priorities(&layout, &files[..2]).is_err());
+    }
+}
@@ -0,0 +1,652 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::net::block::{Block, BlockRequest, BLOCK_SIZE};
+
+/// Number of requests kept in flight to a peer before its throughput has been measured.
+pub const INITIAL_QUEUE_DEPTH: usize = 4;
+
+/// Lower bound of the queue depth, so that a peer always has the next block to send.
+pub const MIN_QUEUE_DEPTH: usize = 2;
+
+/// Upper bound of the queue depth, used when the peer does not advertise a `reqq`.
+pub const MAX_QUEUE_DEPTH: usize = 250;
+
+/// Time after which an unanswered request is re-requested from another peer.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Amount of data the queue of a peer should hold, expressed as seconds of its throughput.
+///
+/// Keeping this many seconds of requests in flight hides the round trip time of the link, so
+/// that the peer never waits for our next request before it can send the next block.
+const QUEUE_TIME: Duration = Duration::from_secs(3);
+
+/// Minimum time over which the throughput of a peer is measured.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// What happened to a block that arrived from a peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BlockOutcome {
+    /// The block was requested and had not been received yet. In endgame, the same block may
+    /// also have been requested from other peers, whose requests should be cancelled.
+    Accepted { cancel: Vec<SocketAddrV4> },
+    /// The block had already been received, or its piece is no longer being downloaded.
+    Discarded,
+}
+
+/// The download state of a single block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum BlockState {
+    /// The block has to be requested. A block that timed out remembers the peer it timed out
+    /// on, so that it is requested from another peer first.
+    Pending { avoid: Option<SocketAddrV4> },
+    /// The block has been requested from one peer, or from several peers in endgame, with the
+    /// time of each request.
+    Requested { peers: Vec<(SocketAddrV4, Instant)> },
+    /// The block has been received.
+    Received,
+}
+
+/// The blocks of a piece that is being downloaded.
+#[derive(Debug)]
+struct PieceBlocks {
+    length: usize,
+    blocks: Vec<BlockState>,
+}
+
+impl PieceBlocks {
+    fn new(length: usize) -> Self {
+        Self {
+            length,
+            blocks: vec![BlockState::Pending { avoid: None }; length.div_ceil(BLOCK_SIZE)],
+        }
+    }
+
+    /// Returns the request for a block. Only the last block of a piece may be shorter than
+    /// [`BLOCK_SIZE`].
+    fn request(&self, piece_i: usize, block_i: usize) -> BlockRequest {
+        let begin = block_i * BLOCK_SIZE;
+        BlockRequest::new(piece_i, begin, BLOCK_SIZE.min(self.length - begin))
+    }
+}
+
+/// Pipeline of requests to a single peer.
+#[derive(Debug)]
+struct Pipeline {
+    in_flight: usize,
+    depth: usize,
+    reqq: Option<usize>,
+    /// Bytes per second received from the peer, smoothed over the measurement windows.
+    rate: f64,
+    window_start: Instant,
+    window_bytes: usize,
+}
+
+impl Pipeline {
+    fn new(now: Instant) -> Self {
+        Self {
+            in_flight: 0,
+            depth: INITIAL_QUEUE_DEPTH,
+            reqq: None,
+            rate: 0.0,
+            window_start: now,
+            window_bytes: 0,
+        }
+    }
+
+    fn max_depth(&self) -> usize {
+        self.reqq
+            .unwrap_or(MAX_QUEUE_DEPTH)
+            .clamp(1, MAX_QUEUE_DEPTH)
+    }
+
+    /// Records received bytes, and adapts the queue depth once a measurement window is over.
+    fn record(&mut self, bytes: usize, now: Instant) {
+        self.window_bytes += bytes;
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+
+        let rate = self.window_bytes as f64 / elapsed.as_secs_f64();
+        self.rate = if self.rate == 0.0 {
+            rate
+        } else {
+            0.7 * self.rate + 0.3 * rate
+        };
+        self.window_start = now;
+        self.window_bytes = 0;
+
+        let depth = (self.rate * QUEUE_TIME.as_secs_f64() / BLOCK_SIZE as f64).ceil() as usize;
+        self.depth = depth.clamp(MIN_QUEUE_DEPTH, self.max_depth().max(MIN_QUEUE_DEPTH));
+    }
+
+    fn free_slots(&self) -> usize {
+        self.depth
+            .min(self.max_depth())
+            .saturating_sub(self.in_flight)
+    }
+}
+
+/// Keeps many block requests in flight per peer.
+///
+/// Sending one request at a time leaves the link idle for a full round trip between blocks,
+/// which is painfully slow on high-latency links. The requester instead keeps a queue of
+/// requests per peer, whose depth follows the measured throughput of the peer, so that there
+/// are always enough requests in flight to cover the latency of the link.
+///
+/// Pieces are added with [`BlockRequester::add_piece`] and split into blocks of
+/// [`BLOCK_SIZE`] bytes. [`BlockRequester::fill`] hands out the requests to send to a peer,
+/// [`BlockRequester::on_block`] accounts for arrived blocks and discards late or duplicate
+/// ones, and [`BlockRequester::expire`] returns requests that timed out, so that they are
+/// requested from other peers.
+///
+/// Once every block has been requested, the last blocks are only as fast as the slowest peer
+/// that was asked for them. [`BlockRequester::fill_endgame`] then requests the blocks that are
+/// still in flight from other peers as well, and the first copy to arrive wins.
+#[derive(Debug)]
+pub struct BlockRequester {
+    pieces: BTreeMap<usize, PieceBlocks>,
+    peers: HashMap<SocketAddrV4, Pipeline>,
+    timeout: Duration,
+}
+
+impl BlockRequester {
+    /// Creates a new requester, in which requests time out after [`REQUEST_TIMEOUT`].
+    pub fn new() -> Self {
+        Self::with_timeout(REQUEST_TIMEOUT)
+    }
+
+    /// Creates a new requester, in which requests time out after `timeout`.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            pieces: BTreeMap::new(),
+            peers: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Starts tracking a peer.
+    pub fn add_peer(&mut self, peer: SocketAddrV4) {
+        self.peers
+            .entry(peer)
+            .or_insert_with(|| Pipeline::new(Instant::now()));
+    }
+
+    /// Stops tracking a peer. Its outstanding requests are returned to the pending blocks.
+    pub fn remove_peer(&mut self, peer: SocketAddrV4) {
+        self.requeue(peer);
+        self.peers.remove(&peer);
+    }
+
+    /// Sets the maximum number of outstanding requests the peer accepts, as advertised with
+    /// the `reqq` key of its extension handshake.
+    pub fn set_reqq(&mut self, peer: SocketAddrV4, reqq: usize) {
+        if let Some(pipeline) = self.peers.get_mut(&peer) {
+            pipeline.reqq = Some(reqq);
+        }
+    }
+
+    /// Returns the current queue depth of a peer.
+    pub fn queue_depth(&self, peer: SocketAddrV4) -> Option<usize> {
+        self.peers.get(&peer).map(|p| p.depth.min(p.max_depth()))
+    }
+
+    /// Returns the number of requests in flight to a peer.
+    pub fn in_flight(&self, peer: SocketAddrV4) -> usize {
+        self.peers.get(&peer).map_or(0, |p| p.in_flight)
+    }
+
+    /// Returns the smoothed download rate of a peer in bytes per second.
+    pub fn rate(&self, peer: SocketAddrV4) -> f64 {
+        self.peers.get(&peer).map_or(0.0, |p| p.rate)
+    }
+
+    /// Starts downloading a piece of `length` bytes.
+    ///
+    /// Does nothing if the piece is already being downloaded.
+    pub fn add_piece(&mut self, piece_i: usize, length: usize) {
+        self.pieces
+            .entry(piece_i)
+            .or_insert_with(|| PieceBlocks::new(length));
+    }
+
+    /// Marks a block of a piece being downloaded as received without requesting it, for
+    /// instance because it was restored from resume data.
+    pub fn mark_received(&mut self, piece_i: usize, block_i: usize) {
+        if let Some(state) = self
+            .pieces
+            .get_mut(&piece_i)
+            .and_then(|piece| piece.blocks.get_mut(block_i))
+        {
+            *state = BlockState::Received;
+        }
+    }
+
+    /// Stops downloading a piece, forgetting its blocks. Blocks of the piece that arrive
+    /// afterwards are discarded.
+    ///
+    /// Returns the requests that are still outstanding, so that they can be cancelled.
+    pub fn remove_piece(&mut self, piece_i: usize) -> Vec<(SocketAddrV4, BlockRequest)> {
+        let mut outstanding = Vec::new();
+        let Some(piece) = self.pieces.remove(&piece_i) else {
+            return outstanding;
+        };
+        for (block_i, state) in piece.blocks.iter().enumerate() {
+            let BlockState::Requested { peers } = state else {
+                continue;
+            };
+            for &(peer, _) in peers {
+                if let Some(pipeline) = self.peers.get_mut(&peer) {
+                    pipeline.in_flight -= 1;
+                }
+                outstanding.push((peer, piece.request(piece_i, block_i)));
+            }
+        }
+        outstanding
+    }
+
+    /// Returns whether a piece is being downloaded.
+    pub fn contains_piece(&self, piece_i: usize) -> bool {
+        self.pieces.contains_key(&piece_i)
+    }
+
+    /// Returns whether every block of a piece has been received.
+    pub fn is_piece_complete(&self, piece_i: usize) -> bool {
+        self.pieces
+            .get(&piece_i)
+            .is_some_and(|piece| piece.blocks.iter().all(|b| *b == BlockState::Received))
+    }
+
+    /// Returns whether a piece has blocks that have not been requested yet.
+    pub fn has_pending(&self, piece_i: usize) -> bool {
+        self.pieces.get(&piece_i).is_some_and(|piece| {
+            piece
+                .blocks
+                .iter()
+                .any(|b| matches!(b, BlockState::Pending { .. }))
+        })
+    }
+
+    /// Returns whether any piece has blocks that have not been requested yet.
+    pub fn has_any_pending(&self) -> bool {
+        self.pieces.keys().any(|&piece_i| self.has_pending(piece_i))
+    }
+
+    /// Returns the requests to send to a peer to fill its queue.
+    ///
+    /// Only blocks of pieces for which `has_piece` returns `true` are requested. Pieces are
+    /// served in ascending order, so that pieces that have been started are finished first.
+    /// Blocks that timed out on this peer are only requested from it if no other block is left.
+    pub fn fill(
+        &mut self,
+        peer: SocketAddrV4,
+        has_piece: impl Fn(usize) -> bool,
+    ) -> Vec<BlockRequest> {
+        let now = Instant::now();
+        let Some(pipeline) = self.peers.get_mut(&peer) else {
+            return Vec::new();
+        };
+        let mut slots = pipeline.free_slots();
+        let mut requests = Vec::new();
+
+        // The first pass skips the blocks that timed out on this peer, the second takes them.
+        for allow_avoided in [false, true] {
+            for (&piece_i, piece) in self.pieces.iter_mut() {
+                if slots == 0 {
+                    break;
+                }
+                if !has_piece(piece_i) {
+                    continue;
+                }
+                for block_i in 0..piece.blocks.len() {
+                    if slots == 0 {
+                        break;
+                    }
+                    let BlockState::Pending { avoid } = piece.blocks[block_i] else {
+                        continue;
+                    };
+                    if avoid == Some(peer) && !allow_avoided {
+                        continue;
+                    }
+                    piece.blocks[block_i] = BlockState::Requested {
+                        peers: vec![(peer, now)],
+                    };
+                    slots -= 1;
+                    requests.push(piece.request(piece_i, block_i));
+                }
+            }
+        }
+
+        pipeline.in_flight += requests.len();
+        requests
+    }
+
+    /// Returns the requests to send to a peer to fill its queue in endgame.
+    ///
+    /// Pending blocks are requested first, as with [`BlockRequester::fill`]. The rest of the
+    /// queue is filled with blocks that are in flight to other peers, so that the last blocks
+    /// come from whichever peer answers first.
+    pub fn fill_endgame(
+        &mut self,
+        peer: SocketAddrV4,
+        has_piece: impl Fn(usize) -> bool,
+    ) -> Vec<BlockRequest> {
+        let now = Instant::now();
+        let mut requests = self.fill(peer, &has_piece);
+        let Some(pipeline) = self.peers.get_mut(&peer) else {
+            return requests;
+        };
+        let mut slots = pipeline.free_slots();
+
+        for (&piece_i, piece) in self.pieces.iter_mut() {
+            if slots == 0 {
+                break;
+            }
+            if !has_piece(piece_i) {
+                continue;
+            }
+            for block_i in 0..piece.blocks.len() {
+                if slots == 0 {
+                    break;
+                }
+                let BlockState::Requested { peers } = &mut piece.blocks[block_i] else {
+                    continue;
+                };
+                if peers.iter().any(|&(p, _)| p == peer) {
+                    continue;
+                }
+                peers.push((peer, now));
+                slots -= 1;
+                pipeline.in_flight += 1;
+                requests.push(piece.request(piece_i, block_i));
+            }
+        }
+        requests
+    }
+
+    /// Accounts for a block that arrived from a peer.
+    ///
+    /// Blocks that were not requested from this peer, that have already been received, or
+    /// whose piece is no longer being downloaded are discarded. A block that timed out is
+    /// only accepted from the peer it timed out on, as long as nobody else sent it.
+    pub fn on_block(&mut self, peer: SocketAddrV4, block: &Block) -> BlockOutcome {
+        let now = Instant::now();
+        let block_i = block.begin() / BLOCK_SIZE;
+        let Some(state) = self
+            .pieces
+            .get_mut(&block.piece_i())
+            .and_then(|piece| piece.blocks.get_mut(block_i))
+        else {
+            return BlockOutcome::Discarded;
+        };
+
+        let outcome = match state {
+            BlockState::Requested { peers } if peers.iter().any(|&(p, _)| p == peer) => {
+                let mut cancel = Vec::new();
+                for (requested_from, _) in std::mem::take(peers) {
+                    if let Some(pipeline) = self.peers.get_mut(&requested_from) {
+                        pipeline.in_flight -= 1;
+                    }
+                    if requested_from != peer {
+                        cancel.push(requested_from);
+                    }
+                }
+                *state = BlockState::Received;
+                BlockOutcome::Accepted { cancel }
+            }
+            // A block that timed out may still arrive from the slow peer before anyone else
+            // sent it, in which case it is as good as any.
+            BlockState::Pending {
+                avoid: Some(timed_out),
+            } if *timed_out == peer => {
+                *state = BlockState::Received;
+                BlockOutcome::Accepted { cancel: Vec::new() }
+            }
+            _ => BlockOutcome::Discarded,
+        };
+
+        if let Some(pipeline) = self.peers.get_mut(&peer) {
+            pipeline.record(block.data().len(), now);
+        }
+        outcome
+    }
+
+    /// Returns the requests of a peer to the pending blocks, because the peer choked us and
+    /// dropped them.
+    pub fn requeue(&mut self, peer: SocketAddrV4) {
+        for piece in self.pieces.values_mut() {
+            for state in piece.blocks.iter_mut() {
+                let BlockState::Requested { peers } = state else {
+                    continue;
+                };
+                peers.retain(|&(p, _)| p != peer);
+                if peers.is_empty() {
+                    *state = BlockState::Pending { avoid: None };
+                }
+            }
+        }
+        if let Some(pipeline) = self.peers.get_mut(&peer) {
+            pipeline.in_flight = 0;
+        }
+    }
+
+    /// Returns the requests that have been in flight for longer than the timeout.
+    ///
+    /// The blocks become pending again, to be requested from other peers. The returned
+    /// requests should be cancelled on the peers they were sent to. The queue depth of a peer
+    /// whose request timed out is reset, since its throughput has dropped.
+    pub fn expire(&mut self) -> Vec<(SocketAddrV4, BlockRequest)> {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        for (&piece_i, piece) in self.pieces.iter_mut() {
+            for block_i in 0..piece.blocks.len() {
+                let request = piece.request(piece_i, block_i);
+                let BlockState::Requested { peers } = &mut piece.blocks[block_i] else {
+                    continue;
+                };
+                let mut last = None;
+                peers.retain(|&(peer, at)| {
+                    if now.duration_since(at) < self.timeout {
+                        return true;
+                    }
+                    expired.push((peer, request));
+                    last = Some(peer);
+                    false
+                });
+                if peers.is_empty() {
+                    piece.blocks[block_i] = BlockState::Pending { avoid: last };
+                }
+            }
+        }
+
+        for (peer, _) in &expired {
+            if let Some(pipeline) = self.peers.get_mut(peer) {
+                pipeline.in_flight -= 1;
+                pipeline.depth = MIN_QUEUE_DEPTH;
+            }
+        }
+        expired
+    }
+}
+
+impl Default for BlockRequester {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)
+    }
+
+    #[tokio::test]
+    async fn test_fill_truncates_last_block() {
+        let mut requester = BlockRequester::new();
+        requester.add_peer(peer(1));
+        requester.add_piece(0, 2 * BLOCK_SIZE + 10);
+
+        let requests = requester.fill(peer(1), |_| true);
+        assert_eq!(
+            requests,
+            vec![
+                BlockRequest::new(0, 0, BLOCK_SIZE),
+                BlockRequest::new(0, BLOCK_SIZE, BLOCK_SIZE),
+                BlockRequest::new(0, 2 * BLOCK_SIZE, 10),
+            ]
+        );
+        assert!(!requester.has_pending(0));
+        assert!(requester.fill(peer(1), |_| true).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_discards_duplicate_and_late_blocks() {
+        let mut requester = BlockRequester::new();
+        requester.add_peer(peer(1));
+        requester.add_piece(0, BLOCK_SIZE);
+        requester.fill(peer(1), |_| true);
+
+        let block = Block::new(0, 0, vec![0; BLOCK_SIZE]);
+        assert_eq!(
+            requester.on_block(peer(1), &block),
+            BlockOutcome::Accepted { cancel: vec![] }
+        );
+        assert_eq!(requester.on_block(peer(1), &block), BlockOutcome::Discarded);
+        assert!(requester.is_piece_complete(0));
+
+        requester.remove_piece(0);
+        assert_eq!(requester.on_block(peer(1), &block), BlockOutcome::Discarded);
+        assert_eq!(requester.in_flight(peer(1)), 0);
+    }
+
+    #[tokio::test]
+    async fn test_discards_unsolicited_blocks() {
+        let mut requester = BlockRequester::new();
+        requester.add_peer(peer(1));
+        requester.add_peer(peer(2));
+        requester.add_piece(0, 2 * BLOCK_SIZE);
+        assert_eq!(requester.fill(peer(1), |_| true).len(), 2);
+
+        // A block requested from another peer, or not requested at all, is not taken.
+        let block = Block::new(0, 0, vec![0; BLOCK_SIZE]);
+        assert_eq!(requester.on_block(peer(2), &block), BlockOutcome::Discarded);
+        assert_eq!(requester.on_block(peer(3), &block), BlockOutcome::Discarded);
+        assert_eq!(requester.in_flight(peer(1)), 2);
+        assert!(!requester.is_piece_complete(0));
+        assert_eq!(
+            requester.on_block(peer(1), &block),
+            BlockOutcome::Accepted { cancel: vec![] }
+        );
+
+        // Nor is a block that became pending again because its peer choked us.
+        requester.requeue(peer(1));
+        let block = Block::new(0, BLOCK_SIZE, vec![0; BLOCK_SIZE]);
+        assert_eq!(requester.on_block(peer(1), &block), BlockOutcome::Discarded);
+        assert!(requester.has_pending(0));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_accepts_late_block_only_from_timed_out_peer() {
+        let mut requester = BlockRequester::new();
+        requester.add_peer(peer(1));
+        requester.add_peer(peer(2));
+        requester.add_piece(0, BLOCK_SIZE);
+        requester.fill(peer(1), |_| true);
+        tokio::time::advance(REQUEST_TIMEOUT).await;
+        requester.expire();
+
+        let block = Block::new(0, 0, vec![0; BLOCK_SIZE]);
+        assert_eq!(requester.on_block(peer(2), &block), BlockOutcome::Discarded);
+        assert_eq!(
+            requester.on_block(peer(1), &block),
+            BlockOutcome::Accepted { cancel: vec![] }
+        );
+        assert!(requester.is_piece_complete(0));
+        assert_eq!(requester.in_flight(peer(1)), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expired_blocks_go_to_other_peers() {
+        let mut requester = BlockRequester::new();
+        requester.add_peer(peer(1));
+        requester.add_peer(peer(2));
+        requester.add_piece(0, BLOCK_SIZE);
+        assert_eq!(requester.fill(peer(1), |_| true).len(), 1);
+
+        tokio::time::advance(REQUEST_TIMEOUT).await;
+        let expired = requester.expire();
+        assert_eq!(
+            expired,
+            vec![(peer(1), BlockRequest::new(0, 0, BLOCK_SIZE))]
+        );
+        assert_eq!(requester.queue_depth(peer(1)), Some(MIN_QUEUE_DEPTH));
+
+        // The slow peer only gets the block back when nobody else took it.
+        assert_eq!(requester.fill(peer(2), |_| true).len(), 1);
+        assert!(requester.fill(peer(1), |_| true).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_queue_depth_follows_throughput() {
+        let mut requester = BlockRequester::new();
+        requester.add_peer(peer(1));
+        requester.add_piece(0, 1 << 22);
+
+        // 64 blocks per second should keep 3 seconds worth of blocks in flight.
+        for _ in 0..40 {
+            for request in requester.fill(peer(1), |_| true) {
+                tokio::time::advance(Duration::from_millis(1000 / 64)).await;
+                let block = Block::new(0, request.begin(), vec![0; request.length()]);
+                requester.on_block(peer(1), &block);
+            }
+        }
+        assert!(requester.queue_depth(peer(1)).unwrap() > INITIAL_QUEUE_DEPTH);
+
+        requester.set_reqq(peer(1), 8);
+        assert_eq!(requester.queue_depth(peer(1)), Some(8));
+        assert!(requester.fill(peer(1), |_| true).len() <= 8);
+    }
+
+    #[tokio::test]
+    async fn test_endgame_requests_blocks_from_every_peer() {
+        let mut requester = BlockRequester::new();
+        requester.add_peer(peer(1));
+        requester.add_peer(peer(2));
+        requester.add_piece(0, 2 * BLOCK_SIZE);
+        assert_eq!(requester.fill(peer(1), |_| true).len(), 2);
+        assert!(!requester.has_any_pending());
+
+        // Outside of endgame the second peer gets nothing, in endgame it gets duplicates.
+        assert!(requester.fill(peer(2), |_| true).is_empty());
+        assert_eq!(requester.fill_endgame(peer(2), |_| true).len(), 2);
+        assert!(requester.fill_endgame(peer(2), |_| true).is_empty());
+        assert_eq!(requester.in_flight(peer(2)), 2);
+
+        // The first copy wins, and the request to the other peer has to be cancelled.
+        let block = Block::new(0, 0, vec![0; BLOCK_SIZE]);
+        assert_eq!(
+            requester.on_block(peer(2), &block),
+            BlockOutcome::Accepted {
+                cancel: vec![peer(1)]
+            }
+        );
+        assert_eq!(requester.on_block(peer(1), &block), BlockOutcome::Discarded);
+        assert_eq!(requester.in_flight(peer(1)), 1);
+        assert_eq!(requester.in_flight(peer(2)), 1);
+
+        // A peer choking us leaves the block in flight to the other one.
+        requester.requeue(peer(1));
+        assert!(!requester.has_any_pending());
+        requester.requeue(peer(2));
+        assert!(requester.has_any_pending());
+    }
+}
@@ -0,0 +1,194 @@
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::net::bitfield::BitField;
+use crate::storage::FileStat;
+
+/// A piece that was partly downloaded when the resume data was saved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartialPiece {
+    piece: usize,
+    /// Bitfield of the blocks of the piece that were written to the storage.
+    #[serde(with = "serde_bytes")]
+    blocks: Vec<u8>,
+}
+
+impl PartialPiece {
+    /// Creates a partial piece from the indices of its finished blocks.
+    pub fn new(piece_i: usize, blocks: impl IntoIterator<Item = usize>) -> Self {
+        let mut bitfield = BitField::from_payload(&[]);
+        for block_i in blocks {
+            bitfield.set_piece(block_i);
+        }
+        Self {
+            piece: piece_i,
+            blocks: bitfield.payload().to_vec(),
+        }
+    }
+
+    /// Returns the index of the piece.
+    pub fn piece_i(&self) -> usize {
+        self.piece
+    }
+
+    /// Returns whether a block of the piece was finished.
+    pub fn has_block(&self, block_i: usize) -> bool {
+        BitField::from_payload(&self.blocks).contains_piece(block_i)
+    }
+}
+
+/// The state of a download, saved so that it can pick up where it left off after a restart.
+///
+/// Resume data is stored as a bencoded dictionary, one file per torrent. It holds the pieces
+/// that were verified, the finished blocks of the pieces that were not, and the length and
+/// modification time of every file at the time it was saved. Checking those against the
+/// files on the disk is cheap, and if they still match, the pieces do not have to be hashed
+/// again.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResumeData {
+    #[serde(rename = "info-hash", with = "serde_bytes")]
+    info_hash: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    pieces: Vec<u8>,
+    partial: Vec<PartialPiece>,
+    files: Vec<FileStat>,
+    uploaded: u64,
+    downloaded: u64,
+}
+
+impl ResumeData {
+    /// Creates the resume data of a torrent.
+    pub fn new(
+        info_hash: [u8; 20],
+        pieces: &BitField,
+        partial: Vec<PartialPiece>,
+        files: Vec<FileStat>,
+        uploaded: u64,
+        downloaded: u64,
+    ) -> Self {
+        Self {
+            info_hash: info_hash.to_vec(),
+            pieces: pieces.payload().to_vec(),
+            partial,
+            files,
+            uploaded,
+            downloaded,
+        }
+    }
+
+    /// Loads resume data from a file, or returns `None` if the file does not exist.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be read or is malformed.
+    pub async fn load(path: impl AsRef<Path>) -> anyhow::Result<Option<Self>> {
+        let path = path.as_ref();
+        let bytes = match tokio::fs::read(path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}.", path.display())),
+        };
+        let data = serde_bencode::from_bytes(&bytes)
+            .with_context(|| format!("Failed to parse resume data {}.", path.display()))?;
+        Ok(Some(data))
+    }
+
+    /// Saves the resume data to a file.
+    ///
+    /// The data is written to a temporary file first, and moved over `path` once it is
+    /// complete, so that a crash never leaves a truncated file behind.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file cannot be written.
+    pub async fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let bytes = serde_bencode::to_bytes(self).context("Failed to serialise resume data.")?;
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".part");
+        tokio::fs::write(&partial, bytes)
+            .await
+            .with_context(|| format!("Failed to write {}.", path.display()))?;
+        tokio::fs::rename(&partial, path)
+            .await
+            .with_context(|| format!("Failed to write {}.", path.display()))
+    }
+
+    /// Returns whether the data belongs to the torrent, and the files have not changed since
+    /// it was saved.
+    ///
+    /// Storage that does not keep files, and reports no stats, never matches.
+    pub fn matches(&self, info_hash: &[u8; 20], files: Option<&[FileStat]>) -> bool {
+        self.info_hash == info_hash && files == Some(self.files.as_slice())
+    }
+
+    /// Returns the pieces that were verified, for a torrent of `n_pieces` pieces.
+    pub fn pieces(&self, n_pieces: usize) -> BitField {
+        let mut pieces = BitField::new(n_pieces);
+        let saved = BitField::from_payload(&self.pieces);
+        for piece_i in 0..n_pieces {
+            if saved.contains_piece(piece_i) {
+                pieces.set_piece(piece_i);
+            }
+        }
+        pieces
+    }
+
+    /// Returns the pieces that were partly downloaded.
+    pub fn partial(&self) -> &[PartialPiece] {
+        &self.partial
+    }
+
+    /// Returns the number of bytes uploaded, over every session.
+    pub fn uploaded(&self) -> u64 {
+        self.uploaded
+    }
+
+    /// Returns the number of bytes downloaded and verified, over every session.
+    pub fn downloaded(&self) -> u64 {
+        self.downloaded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_saves_and_loads() {
+        let mut pieces = BitField::new(10);
+        pieces.set_piece(0);
+        pieces.set_piece(9);
+        let files = vec![FileStat::new(100, 1_700_000_000_000_000_000)];
+        let data = ResumeData::new(
+            [7; 20],
+            &pieces,
+            vec![PartialPiece::new(3, [0, 2])],
+            files.clone(),
+            10,
+            20,
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("content.resume");
+        assert_eq!(ResumeData::load(&path).await.unwrap(), None);
+        data.save(&path).await.unwrap();
+        let loaded = ResumeData::load(&path).await.unwrap().unwrap();
+        assert_eq!(loaded, data);
+
+        let loaded_pieces = loaded.pieces(10);
+        assert!((0..10).all(|i| loaded_pieces.contains_piece(i) == (i == 0 || i == 9)));
+        let partial = &loaded.partial()[0];
+        assert_eq!(partial.piece_i(), 3);
+        assert!(partial.has_block(0) && !partial.has_block(1) && partial.has_block(2));
+        assert!(!partial.has_block(100));
+
+        assert!(loaded.matches(&[7; 20], Some(&files)));
+        assert!(!loaded.matches(&[8; 20], Some(&files)));
+        assert!(!loaded.matches(&[7; 20], Some(&[FileStat::new(100, 1)])));
+        assert!(!loaded.matches(&[7; 20], None));
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use sha1::{Digest, Sha1};
+
+use crate::net::block::BLOCK_SIZE;
+
+/// The number of failed downloads of pieces a peer may send blocks of before it is banned,
+/// unless configured otherwise.
+pub const DEFAULT_MAX_HASH_FAILURES: usize = 5;
+
+/// The blocks of a failed download of a piece, by block index, with the peer that sent each
+/// of them and its hash.
+type Attempt = HashMap<usize, (Ipv4Addr, [u8; 20])>;
+
+/// Finds out which peers send corrupt data, from the pieces that fail their hash check.
+///
+/// When a piece fails, the hash of every block is recorded along with the peer that sent it,
+/// and the piece is downloaded again. Once it passes, the blocks that differ from the correct
+/// ones point at the peers that sent bad data, which are banned right away. Peers also get a
+/// hash failure for every failed download they sent blocks of, and are banned once they have
+/// too many of them, which catches peers whose pieces never pass. A failure is forgiven when
+/// every block the peer sent in that download turns out to be correct.
+#[derive(Debug)]
+pub struct SmartBan {
+    max_failures: usize,
+    /// The failed downloads of the pieces that have not passed yet.
+    suspects: HashMap<usize, Vec<Attempt>>,
+    /// Number of failed downloads each peer sent blocks of.
+    failures: HashMap<Ipv4Addr, usize>,
+}
+
+impl SmartBan {
+    /// Creates a smart-ban that bans peers after `max_failures` failed downloads. With a
+    /// `max_failures` of 0, peers are only banned once they are found to have sent a corrupt
+    /// block.
+    pub fn new(max_failures: usize) -> Self {
+        Self {
+            max_failures,
+            suspects: HashMap::new(),
+            failures: HashMap::new(),
+        }
+    }
+
+    /// Returns the number of failed downloads a peer sent blocks of, that were not forgiven.
+    pub fn failures(&self, address: Ipv4Addr) -> usize {
+        self.failures.get(&address).copied().unwrap_or_default()
+    }
+
+    /// Records the `data` of a piece that failed its hash check, with the peer that sent each
+    /// of its blocks, by block index.
+    ///
+    /// Returns the peers that reached the maximum number of hash failures.
+    pub fn on_failed(
+        &mut self,
+        piece_i: usize,
+        data: &[u8],
+        senders: &HashMap<usize, SocketAddrV4>,
+    ) -> Vec<Ipv4Addr> {
+        let attempt = senders
+            .iter()
+            .map(|(&block_i, sender)| (block_i, (*sender.ip(), block_hash(data, block_i))))
+            .collect::<Attempt>();
+        let peers = attempt
+            .values()
+            .map(|&(peer, _)| peer)
+            .collect::<HashSet<_>>();
+        self.suspects.entry(piece_i).or_default().push(attempt);
+        peers
+            .into_iter()
+            .filter(|peer| {
+                let failures = self.failures.entry(*peer).or_default();
+                *failures += 1;
+                self.max_failures > 0 && *failures == self.max_failures
+            })
+            .collect()
+    }
+
+    /// Compares the blocks recorded from the failed downloads of a piece with its correct
+    /// `data`, once it passes its hash check.
+    ///
+    /// Returns the peers that sent a corrupt block of the piece.
+    pub fn on_passed(&mut self, piece_i: usize, data: &[u8]) -> Vec<Ipv4Addr> {
+        let Some(attempts) = self.suspects.remove(&piece_i) else {
+            return Vec::new();
+        };
+        let mut correct = HashMap::new();
+        let mut is_correct = |block_i: usize, hash: &[u8; 20]| {
+            correct
+                .entry(block_i)
+                .or_insert_with(|| block_hash(data, block_i))
+                == hash
+        };
+        // The peers that sent a corrupt block in each download.
+        let corrupt = attempts
+            .iter()
+            .map(|attempt| {
+                attempt
+                    .iter()
+                    .filter(|(&block_i, (_, hash))| !is_correct(block_i, hash))
+                    .map(|(_, &(peer, _))| peer)
+                    .collect::<HashSet<_>>()
+            })
+            .collect::<Vec<_>>();
+        let banned = corrupt.iter().flatten().copied().collect::<HashSet<_>>();
+        for (attempt, corrupt) in attempts.iter().zip(&corrupt) {
+            let innocent = attempt
+                .values()
+                .map(|&(peer, _)| peer)
+                .filter(|peer| !corrupt.contains(peer) && !banned.contains(peer))
+                .collect::<HashSet<_>>();
+            for peer in innocent {
+                if let Some(failures) = self.failures.get_mut(&peer) {
+                    *failures = failures.saturating_sub(1);
+                }
+            }
+        }
+        banned.into_iter().collect()
+    }
+}
+
+impl Default for SmartBan {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_HASH_FAILURES)
+    }
+}
+
+/// Returns the SHA1 hash of a block of the data of a piece.
+fn block_hash(data: &[u8], block_i: usize) -> [u8; 20] {
+    let begin = (block_i * BLOCK_SIZE).min(data.len());
+    let end = (begin + BLOCK_SIZE).min(data.len());
+    Sha1::digest(&data[begin..end]).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(n: u8) -> SocketAddrV4 {
+        SocketAddrV4::new([10, 0, 0, n].into(), 6881)
+    }
+
+    /// Returns the data of a piece of three blocks, and a copy with a corrupt block.
+    fn piece(corrupt_block_i: usize) -> (Vec<u8>, Vec<u8>) {
+        let data = (0..3 * BLOCK_SIZE).map(|i| i as u8).collect::<Vec<_>>();
+        let mut corrupt = data.clone();
+        corrupt[corrupt_block_i * BLOCK_SIZE + 7] ^= 1;
+        (data, corrupt)
+    }
+
+    #[test]
+    fn test_bans_sender_of_corrupt_block() {
+        let (honest, liar) = (peer(1), peer(2));
+        let (data, corrupt) = piece(1);
+        let senders = HashMap::from([(0, honest), (1, liar), (2, honest)]);
+        let mut smart_ban = SmartBan::new(2);
+
+        assert!(smart_ban.on_failed(0, &corrupt, &senders).is_empty());
+        assert_eq!(smart_ban.failures(*honest.ip()), 1);
+        assert_eq!(smart_ban.on_passed(0, &data), vec![*liar.ip()]);
+        assert_eq!(smart_ban.failures(*honest.ip()), 0);
+        assert_eq!(smart_ban.failures(*liar.ip()), 1);
+        assert!(smart_ban.on_passed(0, &data).is_empty());
+    }
+
+    #[test]
+    fn test_bans_after_too_many_failures() {
+        let liar = peer(2);
+        let (_, corrupt) = piece(0);
+        let senders = HashMap::from([(0, liar), (1, liar)]);
+        let mut smart_ban = SmartBan::new(3);
+
+        assert!(smart_ban.on_failed(0, &corrupt, &senders).is_empty());
+        assert!(smart_ban.on_failed(1, &corrupt, &senders).is_empty());
+        assert_eq!(smart_ban.on_failed(2, &corrupt, &senders), vec![*liar.ip()]);
+        // The peer is only reported once, when it reaches the maximum.
+        assert!(smart_ban.on_failed(3, &corrupt, &senders).is_empty());
+        assert_eq!(smart_ban.failures(*liar.ip()), 4);
+    }
+
+    #[test]
+    fn test_keeps_blocks_of_every_failed_download() {
+        let (honest, liar) = (peer(1), peer(2));
+        let (data, corrupt) = piece(1);
+        let mut smart_ban = SmartBan::new(10);
+
+        // The liar sends a corrupt block first, then a correct one of the same piece.
+        let senders = HashMap::from([(0, honest), (1, liar), (2, honest)]);
+        smart_ban.on_failed(0, &corrupt, &senders);
+        let (_, other) = piece(2);
+        let senders = HashMap::from([(0, honest), (1, liar), (2, peer(3))]);
+        smart_ban.on_failed(0, &other, &senders);
+
+        let mut banned = smart_ban.on_passed(0, &data);
+        banned.sort();
+        assert_eq!(banned, vec![*liar.ip(), *peer(3).ip()]);
+    }
+
+    #[test]
+    fn test_forgives_every_failed_download() {
+        let (honest, liar) = (peer(1), peer(2));
+        let (data, corrupt) = piece(2);
+        let senders = HashMap::from([(0, honest), (1, honest), (2, liar)]);
+        let mut smart_ban = SmartBan::new(10);
+
+        smart_ban.on_failed(0, &corrupt, &senders);
+        smart_ban.on_failed(0, &corrupt, &senders);
+        assert_eq!(smart_ban.failures(*honest.ip()), 2);
+        assert_eq!(smart_ban.on_passed(0, &data), vec![*liar.ip()]);
+        assert_eq!(smart_ban.failures(*honest.ip()), 0);
+        // The failures of a banned peer are not forgiven.
+        assert_eq!(smart_ban.failures(*liar.ip()), 2);
+    }
+
+    #[test]
+    fn test_zero_max_failures_only_bans_senders_of_corrupt_blocks() {
+        let liar = peer(2);
+        let (data, corrupt) = piece(0);
+        let senders = HashMap::from([(0, liar)]);
+        let mut smart_ban = SmartBan::new(0);
+
+        for _ in 0..10 {
+            assert!(smart_ban.on_failed(0, &corrupt, &senders).is_empty());
+        }
+        assert_eq!(smart_ban.on_passed(0, &data), vec![*liar.ip()]);
+        assert!(smart_ban.on_passed(1, &data).is_empty());
+    }
+}
@@ -0,0 +1,77 @@
+use std::ops::Range;
+use std::time::Duration;
+
+use super::engine::DownloadControl;
+
+/// Number of pieces after the one being read that are made time-critical, so that they are
+/// ready by the time the reader gets to them.
+pub const READ_AHEAD: usize = 4;
+
+/// Deadline given to every further piece of the read-ahead.
+const READ_AHEAD_STEP: Duration = Duration::from_secs(2);
+
+/// Reads a range of the content of a torrent in order, while it downloads.
+///
+/// Every call to [`StreamReader::next_chunk`] waits until the next piece of the range is
+/// downloaded and verified, and returns the part of the range it holds. The piece being
+/// read gets a deadline of now, and the [`READ_AHEAD`] pieces after it get deadlines a few
+/// seconds apart, so that a steady reader rarely waits.
+///
+/// Readers are created with [`DownloadControl::stream`].
+#[derive(Debug, Clone)]
+pub struct StreamReader {
+    control: DownloadControl,
+    position: usize,
+    end: usize,
+}
+
+impl StreamReader {
+    pub(crate) fn new(control: DownloadControl, range: Range<usize>) -> Self {
+        let end = range.end.min(control.length());
+        Self {
+            control,
+            position: range.start.min(end),
+            end,
+        }
+    }
+
+    /// Returns the offset in the content of the next byte to read.
+    pub fn position(&self) -> usize {
+        self.position
+    }
+
+    /// Returns the number of bytes left to read.
+    pub fn remaining(&self) -> usize {
+        self.end - self.position
+    }
+
+    /// Moves the reader to another offset in the content, at most the end of its range.
+    pub fn seek(&mut self, position: usize) {
+        self.position = position.min(self.end);
+    }
+
+    /// Waits until the next piece of the range is verified, and returns its bytes that fall
+    /// in the range, or `None` once the whole range has been read.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the storage cannot be read, or the download
+    /// ends before the piece arrives.
+    pub async fn next_chunk(&mut self) -> anyhow::Result<Option<Vec<u8>>> {
+        if self.position >= self.end {
+            return Ok(None);
+        }
+        let piece_length = self.control.piece_length();
+        let piece_i = self.position / piece_length;
+        let last_piece = (self.end - 1) / piece_length;
+        for (i, ahead) in (piece_i + 1..=last_piece).take(READ_AHEAD).enumerate() {
+            self.control
+                .set_deadline(ahead..ahead + 1, READ_AHEAD_STEP * (i as u32 + 1));
+        }
+
+        let chunk_end = ((piece_i + 1) * piece_length).min(self.end);
+        let data = self.control.read(self.position..chunk_end).await?;
+        self.position = chunk_end;
+        Ok(Some(data))
+    }
+}
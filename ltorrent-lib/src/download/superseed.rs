@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::net::SocketAddrV4;
+
+/// Hands out the pieces of a seed one peer at a time, as in BEP 16.
+///
+/// A super-seed hides that it has every piece. Each peer is told of a single piece with a
+/// `Have` message, preferably one that nobody has and that was not offered to anyone else,
+/// and is only told of the next one once the piece shows up in the `Have` messages of other
+/// peers. That way, the seed uploads every piece about once, and the peers do the rest.
+#[derive(Debug, Default)]
+pub struct SuperSeeder {
+    /// The pieces offered to each peer, the current one last.
+    offered: HashMap<SocketAddrV4, Vec<usize>>,
+}
+
+impl SuperSeeder {
+    /// Creates a super-seeder that has not offered any piece yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Picks the next piece to offer a peer, among the `n_pieces` pieces, and records it.
+    ///
+    /// The piece is one the peer lacks, with the lowest availability. Pieces that are the
+    /// current offer of another peer are only picked when there is nothing else. Returns
+    /// `None` if the peer lacks no piece.
+    pub fn offer(
+        &mut self,
+        peer: SocketAddrV4,
+        n_pieces: usize,
+        lacks: impl Fn(usize) -> bool,
+        availability: impl Fn(usize) -> usize,
+    ) -> Option<usize> {
+        let current = |piece_i: usize| {
+            self.offered
+                .iter()
+                .any(|(other, offered)| *other != peer && offered.last() == Some(&piece_i))
+        };
+        let piece_i = (0..n_pieces)
+            .filter(|&piece_i| lacks(piece_i))
+            .min_by_key(|&piece_i| (current(piece_i), availability(piece_i)))?;
+        self.offered.entry(peer).or_default().push(piece_i);
+        Some(piece_i)
+    }
+
+    /// Returns whether a piece was offered to a peer, so that it may request it.
+    pub fn is_offered(&self, peer: SocketAddrV4, piece_i: usize) -> bool {
+        self.offered
+            .get(&peer)
+            .is_some_and(|offered| offered.contains(&piece_i))
+    }
+
+    /// Records that `peer` announced a piece, and returns the peers whose current offer it
+    /// was, since the piece has spread and they may be offered the next one.
+    ///
+    /// A peer that announces its own offer is only returned if `can_spread` is false, which is
+    /// when no other peer could get the piece from it.
+    pub fn on_have(
+        &self,
+        peer: SocketAddrV4,
+        piece_i: usize,
+        can_spread: bool,
+    ) -> Vec<SocketAddrV4> {
+        self.offered
+            .iter()
+            .filter(|(&other, offered)| {
+                offered.last() == Some(&piece_i) && (other != peer || !can_spread)
+            })
+            .map(|(&other, _)| other)
+            .collect()
+    }
+
+    /// Forgets the pieces offered to a peer that disconnected.
+    pub fn remove_peer(&mut self, peer: SocketAddrV4) {
+        self.offered.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new([127, 0, 0, 1].into(), port)
+    }
+
+    fn availability(piece_i: usize) -> usize {
+        [2, 0, 1, 0][piece_i]
+    }
+
+    #[test]
+    fn test_offers_rarest_pieces_to_each_peer() {
+        let mut seeder = SuperSeeder::new();
+        let (a, b) = (peer(1), peer(2));
+
+        // Pieces 1 and 3 are the rarest, and each peer gets its own.
+        assert_eq!(seeder.offer(a, 4, |_| true, availability), Some(1));
+        assert_eq!(seeder.offer(b, 4, |_| true, availability), Some(3));
+        assert!(seeder.is_offered(a, 1) && !seeder.is_offered(a, 3));
+        assert!(seeder.is_offered(b, 3) && !seeder.is_offered(b, 1));
+    }
+
+    #[test]
+    fn test_shares_current_offer_only_when_nothing_else() {
+        let mut seeder = SuperSeeder::new();
+        let (a, b) = (peer(1), peer(2));
+
+        assert_eq!(seeder.offer(a, 4, |_| true, availability), Some(1));
+        // The current offer of `a` is rarer, but `b` gets the next rarest piece instead.
+        assert_eq!(seeder.offer(b, 4, |i| i != 3, availability), Some(2));
+        // Once `b` only lacks the piece offered to `a`, it gets it too.
+        assert_eq!(seeder.offer(b, 4, |i| i == 1, availability), Some(1));
+    }
+
+    #[test]
+    fn test_offers_nothing_when_peer_lacks_no_piece() {
+        let mut seeder = SuperSeeder::new();
+        assert_eq!(seeder.offer(peer(1), 4, |_| false, availability), None);
+        assert_eq!(seeder.offer(peer(1), 0, |_| true, availability), None);
+        assert!((0..4).all(|piece_i| !seeder.is_offered(peer(1), piece_i)));
+    }
+
+    #[test]
+    fn test_offers_next_piece_once_offer_spreads() {
+        let mut seeder = SuperSeeder::new();
+        let (a, b) = (peer(1), peer(2));
+        seeder.offer(a, 4, |_| true, availability);
+        seeder.offer(b, 4, |_| true, availability);
+
+        // A peer announcing its own offer does not earn it another piece, unless nobody
+        // else could get it. Another peer announcing it does.
+        assert!(seeder.on_have(a, 1, true).is_empty());
+        assert_eq!(seeder.on_have(a, 1, false), [a]);
+        assert_eq!(seeder.on_have(b, 1, true), [a]);
+        assert_eq!(seeder.offer(a, 4, |i| i != 1, availability), Some(2));
+        assert!(seeder.is_offered(a, 1) && seeder.is_offered(a, 2));
+
+        // Only the current offer counts once it has moved on.
+        assert!(seeder.on_have(b, 1, true).is_empty());
+        assert!(seeder.on_have(b, 0, true).is_empty());
+    }
+
+    #[test]
+    fn test_forgets_offers_of_removed_peer() {
+        let mut seeder = SuperSeeder::new();
+        let (a, b) = (peer(1), peer(2));
+        seeder.offer(a, 4, |_| true, availability);
+        seeder.offer(b, 4, |i| i == 0, availability);
+
+        seeder.remove_peer(a);
+        assert!(!seeder.is_offered(a, 1));
+        assert!(seeder.on_have(b, 1, true).is_empty());
+        // The piece offered to the removed peer is free for the others again.
+        assert_eq!(
+            seeder.offer(b, 4, |i| i == 1 || i == 2, availability),
+            Some(1)
+        );
+        assert!(seeder.is_offered(b, 0));
+    }
+}
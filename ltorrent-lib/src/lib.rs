@@ -1,5 +1,7 @@
+pub mod download;
 pub mod net;
 pub mod torrent;
 pub mod piece;
+pub mod storage;
 pub mod config;
 pub mod tracker;
@@ -15,6 +15,7 @@
 /// assert!(!bitfield.contains_piece(7)); // The peer does not have the eighth piece.
 /// assert!(bitfield.contains_piece(9)); // The peer has the ninth piece.
 /// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BitField {
     payload: Vec<u8>,
 }
@@ -40,6 +41,52 @@ impl BitField {
         Self { payload: payload.to_vec() }
     }
 
+    /// Creates an empty `BitField` with room for `n_pieces` pieces.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ltorrent::net::bitfield::BitField;
+    ///
+    /// let bitfield = BitField::new(10);
+    /// assert!(!bitfield.contains_piece(0));
+    /// assert_eq!(bitfield.payload().len(), 2);
+    /// ```
+    pub fn new(n_pieces: usize) -> Self {
+        Self {
+            payload: vec![0; n_pieces.div_ceil(8)],
+        }
+    }
+
+    /// Returns the bitfield encoded as the payload of a `Bitfield` message.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload
+    }
+
+    /// Marks a piece as present in the bitfield.
+    ///
+    /// The payload grows if the piece index falls after its end, which happens when a peer
+    /// skipped the `Bitfield` message and announces its pieces with `Have` messages.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ltorrent::net::bitfield::BitField;
+    ///
+    /// let mut bitfield = BitField::from_payload(&[]);
+    /// bitfield.set_piece(9);
+    /// assert!(bitfield.contains_piece(9));
+    /// assert!(!bitfield.contains_piece(8));
+    /// ```
+    pub fn set_piece(&mut self, piece_i: usize) {
+        let byte_i = piece_i / 8;
+        let bit_i = piece_i % 8;
+        if self.payload.len() <= byte_i {
+            self.payload.resize(byte_i + 1, 0);
+        }
+        self.payload[byte_i] |= 1_u8.rotate_right((bit_i + 1) as u32);
+    }
+
     /// Checks if a specific piece is present in the bitfield.
     ///
     /// This method determines whether a particular piece, identified by its index (`piece_i`),
@@ -0,0 +1,219 @@
+use anyhow::Context;
+
+use super::message::{Message, MessageTag};
+
+/// The size of a block in bytes.
+///
+/// Pieces are transferred in blocks of 2^14 (16 kiB) bytes. All current implementations use
+/// this size, and close connections which request an amount greater than that.
+pub const BLOCK_SIZE: usize = 1 << 14;
+
+/// Identifies a block of a piece, as it is carried by `Request` and `Cancel` messages.
+///
+/// The payload of both messages contains an index, begin, and length, each encoded as a
+/// big-endian 4 byte integer. The last two are byte offsets within the piece.
+///
+/// # Examples
+///
+/// ```
+/// use ltorrent::net::block::BlockRequest;
+///
+/// let request = BlockRequest::new(1, 16384, 16384);
+/// let payload = request.to_payload();
+/// assert_eq!(BlockRequest::from_payload(&payload).unwrap(), request);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct BlockRequest {
+    piece_i: usize,
+    begin: usize,
+    length: usize,
+}
+
+impl BlockRequest {
+    /// Creates a new request for `length` bytes at offset `begin` of the piece `piece_i`.
+    pub fn new(piece_i: usize, begin: usize, length: usize) -> Self {
+        Self {
+            piece_i,
+            begin,
+            length,
+        }
+    }
+
+    /// Returns the index of the piece the block belongs to.
+    pub fn piece_i(&self) -> usize {
+        self.piece_i
+    }
+
+    /// Returns the byte offset of the block within the piece.
+    pub fn begin(&self) -> usize {
+        self.begin
+    }
+
+    /// Returns the length of the block in bytes.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Returns the index of the block within the piece.
+    pub fn block_i(&self) -> usize {
+        self.begin / BLOCK_SIZE
+    }
+
+    /// Parses a request from the payload of a `Request` or `Cancel` message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the payload is not exactly 12 bytes long.
+    pub fn from_payload(payload: &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            payload.len() == 12,
+            "Request payload must be 12 bytes, got {}.",
+            payload.len()
+        );
+        Ok(Self {
+            piece_i: read_u32(&payload[0..4])?,
+            begin: read_u32(&payload[4..8])?,
+            length: read_u32(&payload[8..12])?,
+        })
+    }
+
+    /// Returns the request encoded as the payload of a `Request` or `Cancel` message.
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(12);
+        payload.extend_from_slice(&(self.piece_i as u32).to_be_bytes());
+        payload.extend_from_slice(&(self.begin as u32).to_be_bytes());
+        payload.extend_from_slice(&(self.length as u32).to_be_bytes());
+        payload
+    }
+
+    /// Returns the `Request` message for the block.
+    pub fn request_message(&self) -> Message {
+        Message::new(MessageTag::Request, self.to_payload()).expect("Request carries a payload.")
+    }
+
+    /// Returns the `Cancel` message for the block.
+    pub fn cancel_message(&self) -> Message {
+        Message::new(MessageTag::Cancel, self.to_payload()).expect("Cancel carries a payload.")
+    }
+}
+
+/// A block of piece data, as it is carried by `Piece` messages.
+///
+/// The payload contains an index and a begin, each encoded as a big-endian 4 byte integer,
+/// followed by the raw data of the block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    piece_i: usize,
+    begin: usize,
+    data: Vec<u8>,
+}
+
+impl Block {
+    /// Creates a new block with the data found at offset `begin` of the piece `piece_i`.
+    pub fn new(piece_i: usize, begin: usize, data: Vec<u8>) -> Self {
+        Self {
+            piece_i,
+            begin,
+            data,
+        }
+    }
+
+    /// Returns the index of the piece the block belongs to.
+    pub fn piece_i(&self) -> usize {
+        self.piece_i
+    }
+
+    /// Returns the byte offset of the block within the piece.
+    pub fn begin(&self) -> usize {
+        self.begin
+    }
+
+    /// Returns the data of the block.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Consumes the block and returns its data.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Returns the request that this block answers.
+    pub fn request(&self) -> BlockRequest {
+        BlockRequest::new(self.piece_i, self.begin, self.data.len())
+    }
+
+    /// Parses a block from the payload of a `Piece` message.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the payload is shorter than 8 bytes.
+    pub fn from_payload(payload: &[u8]) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            payload.len() >= 8,
+            "Piece payload must be at least 8 bytes, got {}.",
+            payload.len()
+        );
+        Ok(Self {
+            piece_i: read_u32(&payload[0..4])?,
+            begin: read_u32(&payload[4..8])?,
+            data: payload[8..].to_vec(),
+        })
+    }
+
+    /// Returns the `Piece` message carrying the block.
+    pub fn to_message(&self) -> Message {
+        let mut payload = Vec::with_capacity(8 + self.data.len());
+        payload.extend_from_slice(&(self.piece_i as u32).to_be_bytes());
+        payload.extend_from_slice(&(self.begin as u32).to_be_bytes());
+        payload.extend_from_slice(&self.data);
+        Message::new(MessageTag::Piece, payload).expect("Piece carries a payload.")
+    }
+}
+
+/// Returns the `Have` message announcing that the piece `piece_i` has been completed.
+pub fn have_message(piece_i: usize) -> Message {
+    Message::new(MessageTag::Have, (piece_i as u32).to_be_bytes().to_vec())
+        .expect("Have carries a payload.")
+}
+
+/// Parses the piece index from the payload of a `Have` message.
+///
+/// # Errors
+///
+/// Returns an error if the payload is not exactly 4 bytes long.
+pub fn parse_have(payload: &[u8]) -> anyhow::Result<usize> {
+    anyhow::ensure!(
+        payload.len() == 4,
+        "Have payload must be 4 bytes, got {}.",
+        payload.len()
+    );
+    read_u32(payload)
+}
+
+fn read_u32(bytes: &[u8]) -> anyhow::Result<usize> {
+    let bytes: [u8; 4] = bytes.try_into().context("Expected 4 bytes.")?;
+    Ok(u32::from_be_bytes(bytes) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_roundtrip() {
+        let block = Block::new(3, BLOCK_SIZE, vec![1, 2, 3]);
+        let message = block.to_message();
+        assert_eq!(message.tag(), &MessageTag::Piece);
+        let parsed = Block::from_payload(message.payload()).unwrap();
+        assert_eq!(parsed, block);
+        assert_eq!(parsed.request(), BlockRequest::new(3, BLOCK_SIZE, 3));
+    }
+
+    #[test]
+    fn test_have_roundtrip() {
+        let message = have_message(258);
+        assert_eq!(parse_have(message.payload()).unwrap(), 258);
+        assert!(parse_have(&[0, 1]).is_err());
+    }
+}
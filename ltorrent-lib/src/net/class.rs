@@ -0,0 +1,232 @@
+use std::net::Ipv4Addr;
+
+use super::iprange::IpRange;
+use super::limit::{RateLimits, Rates};
+
+/// A class of peers selected by their IP address, with rate limits, a connection limit and a
+/// priority of its own.
+///
+/// The rate limits of a class are shared by all its peers, on top of the global, torrent and
+/// peer limits, unless the class is unthrottled, in which case its limits are the only ones
+/// its peers go through. Peers of a class with a higher priority win the regular slots of
+/// the choker over the others.
+#[derive(Debug, Clone)]
+pub struct PeerClass {
+    name: String,
+    ranges: Vec<IpRange>,
+    limits: RateLimits,
+    unthrottled: bool,
+    max_connections: Option<usize>,
+    priority: u8,
+}
+
+impl PeerClass {
+    /// Creates a class of the peers in `ranges`, without limits and with a priority of 0.
+    pub fn new(name: impl Into<String>, ranges: Vec<IpRange>) -> Self {
+        Self {
+            name: name.into(),
+            ranges,
+            limits: RateLimits::default(),
+            unthrottled: false,
+            max_connections: None,
+            priority: 0,
+        }
+    }
+
+    /// The built-in class of the peers on loopback, private and link-local networks, which
+    /// are not throttled.
+    pub fn local() -> Self {
+        let ranges = [
+            "127.0.0.0/8",
+            "10.0.0.0/8",
+            "172.16.0.0/12",
+            "192.168.0.0/16",
+            "169.254.0.0/16",
+        ]
+        .iter()
+        .map(|range| range.parse().expect("Built-in ranges are valid."))
+        .collect();
+        Self::new("local", ranges).unthrottled()
+    }
+
+    /// Sets the upload and download rates shared by the peers of the class, in bytes per
+    /// second.
+    pub fn with_limit(self, rates: Rates) -> Self {
+        self.limits.set_rates(rates);
+        self
+    }
+
+    /// Exempts the peers of the class from the global, torrent and peer limits.
+    pub fn unthrottled(mut self) -> Self {
+        self.unthrottled = true;
+        self
+    }
+
+    /// Sets the number of peers of the class a download connects to at most.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = Some(max_connections);
+        self
+    }
+
+    /// Sets the priority of the peers of the class in the choker.
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Returns the name of the class.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the IP ranges of the peers of the class.
+    pub fn ranges(&self) -> &[IpRange] {
+        &self.ranges
+    }
+
+    /// Returns the rate limits shared by the peers of the class, which can be changed while
+    /// they are in use.
+    pub fn limits(&self) -> &RateLimits {
+        &self.limits
+    }
+
+    /// Returns whether the peers of the class are exempt from the other limits.
+    pub fn is_unthrottled(&self) -> bool {
+        self.unthrottled
+    }
+
+    /// Returns the number of peers of the class a download connects to at most.
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+
+    /// Returns the priority of the peers of the class in the choker.
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// Returns whether an address belongs to the class.
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        self.ranges.iter().any(|range| range.contains(address))
+    }
+}
+
+/// The peer classes of a configuration. A peer belongs to the first class whose ranges
+/// contain its address, or to none, in which case only the usual limits apply to it.
+#[derive(Debug, Clone)]
+pub struct PeerClasses {
+    classes: Vec<PeerClass>,
+}
+
+impl PeerClasses {
+    /// Creates a set of classes, matched in order.
+    pub fn new(classes: Vec<PeerClass>) -> Self {
+        Self { classes }
+    }
+
+    /// Adds a class, matched before every class already added, so that it can carve a
+    /// subnet out of them.
+    pub fn with_class(mut self, class: PeerClass) -> Self {
+        self.classes.insert(0, class);
+        self
+    }
+
+    /// Returns the classes, in the order they are matched.
+    pub fn classes(&self) -> &[PeerClass] {
+        &self.classes
+    }
+
+    /// Returns the class with a name.
+    pub fn get(&self, name: &str) -> Option<&PeerClass> {
+        self.classes.iter().find(|class| class.name == name)
+    }
+
+    /// Returns the index and the class an address belongs to.
+    pub fn classify(&self, address: Ipv4Addr) -> Option<(usize, &PeerClass)> {
+        self.classes
+            .iter()
+            .enumerate()
+            .find(|(_, class)| class.contains(address))
+    }
+}
+
+impl Default for PeerClasses {
+    /// The built-in [`PeerClass::local`] class alone.
+    fn default() -> Self {
+        Self::new(vec![PeerClass::local()])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classifies_by_first_matching_range() {
+        let office = PeerClass::new("office", vec!["10.1.0.0/16".parse().unwrap()]);
+        let classes = PeerClasses::default().with_class(office);
+
+        // The office class carves its subnet out of the local class added before it.
+        let (index, class) = classes.classify([10, 1, 2, 3].into()).unwrap();
+        assert_eq!((index, class.name()), (0, "office"));
+        let (index, class) = classes.classify([10, 2, 0, 1].into()).unwrap();
+        assert_eq!((index, class.name()), (1, "local"));
+        assert!(classes.classify([8, 8, 8, 8].into()).is_none());
+    }
+
+    #[test]
+    fn test_local_class_covers_private_networks() {
+        let local = PeerClass::local();
+        assert!(local.is_unthrottled());
+        for address in [
+            [127, 0, 0, 1],
+            [10, 255, 255, 255],
+            [172, 16, 0, 0],
+            [172, 31, 255, 255],
+            [192, 168, 0, 10],
+            [169, 254, 1, 1],
+        ] {
+            assert!(local.contains(address.into()), "{address:?}");
+        }
+        for address in [[172, 32, 0, 0], [192, 169, 0, 1], [8, 8, 8, 8]] {
+            assert!(!local.contains(address.into()), "{address:?}");
+        }
+    }
+
+    #[test]
+    fn test_builds_class_with_limits() {
+        let office = PeerClass::new("office", vec!["10.1.0.0/16".parse().unwrap()])
+            .with_limit(Rates::new(Some(1024), None))
+            .with_max_connections(2)
+            .with_priority(1);
+        assert!(!office.is_unthrottled());
+        assert_eq!(office.limits().rates(), Rates::new(Some(1024), None));
+        assert_eq!((office.max_connections(), office.priority()), (Some(2), 1));
+
+        // The limits are shared with the peers already using them.
+        let limits = office.limits().clone();
+        office.limits().set_rates(Rates::UNLIMITED);
+        assert_eq!(limits.rates(), Rates::UNLIMITED);
+
+        let plain = PeerClass::new("plain", Vec::new());
+        assert_eq!((plain.max_connections(), plain.priority()), (None, 0));
+    }
+
+    #[test]
+    fn test_finds_classes_by_name() {
+        let classes = PeerClasses::default()
+            .with_class(PeerClass::new("office", Vec::new()).with_max_connections(2));
+        assert_eq!(classes.get("office").unwrap().max_connections(), Some(2));
+        assert!(classes.get("local").is_some());
+        assert!(classes.get("Office").is_none());
+    }
+
+    #[test]
+    fn test_classes_without_ranges_match_nothing() {
+        let classes = PeerClasses::new(vec![PeerClass::new("empty", Vec::new())]);
+        assert!(classes.classify([127, 0, 0, 1].into()).is_none());
+        assert!(PeerClasses::new(Vec::new())
+            .classify([127, 0, 0, 1].into())
+            .is_none());
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::HashSet;
+use std::net::Ipv4Addr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+
+use anyhow::Context;
+use tokio::sync::Mutex;
+
+use super::iprange::IpRange;
+
+/// Access level from which the ranges of an `ipfilter.dat` file are allowed, as in eMule.
+const ALLOWED_LEVEL: u32 = 128;
+
+#[derive(Debug, Default)]
+struct Blocklist {
+    /// Blocked ranges as pairs of addresses, sorted and without overlaps.
+    ranges: Vec<(u32, u32)>,
+    /// Files the ranges were loaded from.
+    paths: Vec<PathBuf>,
+    /// Addresses banned while running, which outlast reloads.
+    banned: HashSet<Ipv4Addr>,
+}
+
+/// A filter of the IP addresses that must never be connected to, or accepted connections
+/// from.
+///
+/// The filter is shared by its clones, so that it can be reloaded while downloads use it, and
+/// counts the attempts it blocks. Blocklists are loaded from files in any of these formats,
+/// which can be mixed line by line:
+/// - eMule `ipfilter.dat`: `1.2.3.0 - 1.2.3.255 , 000 , Description`, where ranges with an
+///   access level of 128 or more are allowed.
+/// - PeerGuardian P2P: `Description:1.2.3.0-1.2.3.255`.
+/// - CIDR lists: `1.2.3.0/24`, or single addresses.
+///
+/// Empty lines and lines starting with `#` or `//` are skipped. Single addresses can also be
+/// banned while the filter is in use, such as peers that send corrupt data.
+#[derive(Debug, Clone, Default)]
+pub struct IpFilter {
+    blocklist: Arc<RwLock<Blocklist>>,
+    blocked: Arc<AtomicUsize>,
+    /// Held while blocklists are loaded, so that a reload does not overwrite a load that
+    /// started after it with the files loaded before.
+    loading: Arc<Mutex<()>>,
+}
+
+impl IpFilter {
+    /// Creates a filter that blocks nothing.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the blocked ranges.
+    pub fn set_ranges(&self, ranges: impl IntoIterator<Item = IpRange>) {
+        self.blocklist
+            .write()
+            .expect("Blocklist lock is not poisoned.")
+            .ranges = merge(ranges);
+    }
+
+    /// Loads the blocklists in `paths`, which replace the blocked ranges, and returns the
+    /// number of ranges blocked. The paths are kept for [`IpFilter::reload`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a file cannot be read or has a malformed line,
+    /// in which case the blocked ranges are left as they were.
+    pub async fn load(&self, paths: &[PathBuf]) -> anyhow::Result<usize> {
+        let _loading = self.loading.lock().await;
+        self.load_locked(paths).await
+    }
+
+    /// Loads the blocklists from the files they were last loaded from again, and returns the
+    /// number of ranges blocked.
+    ///
+    /// A reload that overlaps a [`IpFilter::load`] runs before or after it, never in the
+    /// middle, so the files last loaded are the ones that stay in use.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a file cannot be read or has a malformed line.
+    pub async fn reload(&self) -> anyhow::Result<usize> {
+        let _loading = self.loading.lock().await;
+        let paths = self.paths();
+        self.load_locked(&paths).await
+    }
+
+    /// Loads the blocklists in `paths`, once the loading lock is held.
+    async fn load_locked(&self, paths: &[PathBuf]) -> anyhow::Result<usize> {
+        let mut ranges = Vec::new();
+        for path in paths {
+            let text = tokio::fs::read_to_string(path)
+                .await
+                .with_context(|| format!("Failed to read blocklist {}.", path.display()))?;
+            ranges.extend(
+                parse_blocklist(&text)
+                    .with_context(|| format!("Failed to parse blocklist {}.", path.display()))?,
+            );
+        }
+        let mut blocklist = self
+            .blocklist
+            .write()
+            .expect("Blocklist lock is not poisoned.");
+        blocklist.ranges = merge(ranges);
+        blocklist.paths = paths.to_vec();
+        Ok(blocklist.ranges.len())
+    }
+
+    /// Returns the files the blocklists were loaded from.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.blocklist
+            .read()
+            .expect("Blocklist lock is not poisoned.")
+            .paths
+            .clone()
+    }
+
+    /// Blocks an address on top of the blocklists, until the filter is dropped. Returns
+    /// whether the address was not banned already.
+    pub fn ban(&self, address: Ipv4Addr) -> bool {
+        self.blocklist
+            .write()
+            .expect("Blocklist lock is not poisoned.")
+            .banned
+            .insert(address)
+    }
+
+    /// Returns whether an address is blocked, by the blocklists or a ban.
+    pub fn is_blocked(&self, address: Ipv4Addr) -> bool {
+        let blocklist = self
+            .blocklist
+            .read()
+            .expect("Blocklist lock is not poisoned.");
+        if blocklist.banned.contains(&address) {
+            return true;
+        }
+        let address = u32::from(address);
+        let after = blocklist
+            .ranges
+            .partition_point(|&(first, _)| first <= address);
+        after > 0 && blocklist.ranges[after - 1].1 >= address
+    }
+
+    /// Returns whether an address may be connected to, or accepted, and counts the attempt
+    /// if it is blocked.
+    pub fn allows(&self, address: Ipv4Addr) -> bool {
+        let blocked = self.is_blocked(address);
+        if blocked {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+        }
+        !blocked
+    }
+
+    /// Returns the number of attempts that were blocked.
+    pub fn blocked_attempts(&self) -> usize {
+        self.blocked.load(Ordering::Relaxed)
+    }
+}
+
+/// Sorts ranges into pairs of addresses, merging the ranges that overlap or touch.
+fn merge(ranges: impl IntoIterator<Item = IpRange>) -> Vec<(u32, u32)> {
+    let mut ranges = ranges
+        .into_iter()
+        .map(|range| (u32::from(range.first()), u32::from(range.last())))
+        .collect::<Vec<_>>();
+    ranges.sort_unstable();
+    let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+    for (first, last) in ranges {
+        match merged.last_mut() {
+            Some((_, end)) if first <= end.saturating_add(1) => *end = (*end).max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+    merged
+}
+
+/// Parses the blocked ranges of a blocklist, in any of the formats of [`IpFilter`].
+///
+/// # Errors
+///
+/// This function will return an error if a line is malformed.
+pub fn parse_blocklist(text: &str) -> anyhow::Result<Vec<IpRange>> {
+    let mut ranges = Vec::new();
+    for (line_i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with("//") {
+            continue;
+        }
+        let range = parse_line(line).with_context(|| format!("Line {}: {line}", line_i + 1))?;
+        ranges.extend(range);
+    }
+    Ok(ranges)
+}
+
+/// Parses a line of a blocklist into the range it blocks, if any.
+fn parse_line(line: &str) -> anyhow::Result<Option<IpRange>> {
+    // eMule: range , access level , description. The descriptions of P2P lines may have
+    // commas too, so the line is only eMule if it starts with a range.
+    let emule = line
+        .split_once(',')
+        .and_then(|(range, rest)| Some((parse_range(range).ok()?, rest)));
+    if let Some((range, rest)) = emule {
+        let level = rest.split(',').next().unwrap_or_default().trim();
+        let level = level
+            .parse::<u32>()
+            .with_context(|| format!("Invalid access level {level}."))?;
+        return Ok((level < ALLOWED_LEVEL).then_some(range));
+    }
+    if let Some((_, range)) = line.rsplit_once(':') {
+        // PeerGuardian: description:range
+        return parse_range(range).map(Some);
+    }
+    line.parse().map(Some)
+}
+
+/// Parses a range of the form `first - last`, whose addresses may have leading zeros.
+fn parse_range(s: &str) -> anyhow::Result<IpRange> {
+    let (first, last) = s
+        .split_once('-')
+        .with_context(|| format!("Range {s} is not of the form <first>-<last>."))?;
+    IpRange::new(parse_address(first)?, parse_address(last)?)
+}
+
+/// Parses an IPv4 address whose octets may have leading zeros, as in `001.002.003.004`.
+fn parse_address(s: &str) -> anyhow::Result<Ipv4Addr> {
+    let invalid = || format!("Invalid IPv4 address {}.", s.trim());
+    let octets = s
+        .trim()
+        .split('.')
+        .map(|octet| octet.parse::<u8>().ok())
+        .collect::<Option<Vec<_>>>()
+        .with_context(invalid)?;
+    let octets: [u8; 4] = octets.try_into().ok().with_context(invalid)?;
+    Ok(octets.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocked(filter: &IpFilter, address: [u8; 4]) -> bool {
+        filter.is_blocked(address.into())
+    }
+
+    /// Writes a blocklist into `dir`, and returns its path.
+    fn write_blocklist(dir: &tempfile::TempDir, name: &str, text: &str) -> PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_parses_emule_blocklists() {
+        let text = "\
+            # Comments and empty lines are skipped.\n\
+            \n\
+            001.002.003.000 - 001.002.003.255 , 000 , eMule range\n\
+            // So are comments of this kind.\n\
+            004.000.000.000 - 004.000.000.255 , 127 , Last blocked level\n\
+            005.000.000.000 - 005.255.255.255 , 128 , Allowed eMule range\n";
+        let ranges = parse_blocklist(text).unwrap();
+        assert_eq!(
+            ranges,
+            [
+                "1.2.3.0-1.2.3.255".parse().unwrap(),
+                "4.0.0.0-4.0.0.255".parse().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parses_p2p_and_cidr_blocklists() {
+        let text = "\
+            Some: organisation:10.0.0.10-10.0.0.20\n\
+            Acme, Inc:10.0.1.0-10.0.1.255\n\
+            10.0.0.21-10.0.0.30\n\
+            192.168.7.0/24\n\
+            8.8.8.8\n";
+        let ranges = parse_blocklist(text).unwrap();
+        assert_eq!(
+            ranges,
+            [
+                "10.0.0.10-10.0.0.20".parse().unwrap(),
+                "10.0.1.0-10.0.1.255".parse().unwrap(),
+                "10.0.0.21-10.0.0.30".parse().unwrap(),
+                "192.168.7.0/24".parse().unwrap(),
+                "8.8.8.8".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_rejects_malformed_blocklists() {
+        assert!(parse_blocklist("1.2.3.4 - 1.2.3.999 , 0 , Bad").is_err());
+        assert!(parse_blocklist("1.2.3.4 - 1.2.3.9 , high , Bad").is_err());
+        assert!(parse_blocklist("1.2.3.9 - 1.2.3.4 , 0 , Reversed").is_err());
+        assert!(parse_blocklist("Description:1.2.3.4").is_err());
+        assert!(parse_blocklist("1.2.3.0/24\ngarbage").is_err());
+    }
+
+    #[test]
+    fn test_blocks_ranges_to_their_ends() {
+        let filter = IpFilter::new();
+        filter.set_ranges([
+            "10.0.0.10-10.0.0.20".parse().unwrap(),
+            "10.0.0.15-10.0.0.30".parse().unwrap(),
+            "10.0.0.31-10.0.0.40".parse().unwrap(),
+            "255.255.255.0/24".parse().unwrap(),
+            "0.0.0.0".parse().unwrap(),
+        ]);
+        // Overlapping and touching ranges are merged.
+        assert_eq!(filter.blocklist.read().unwrap().ranges.len(), 3);
+        assert!(blocked(&filter, [10, 0, 0, 10]) && blocked(&filter, [10, 0, 0, 40]));
+        assert!(!blocked(&filter, [10, 0, 0, 9]) && !blocked(&filter, [10, 0, 0, 41]));
+        assert!(blocked(&filter, [0, 0, 0, 0]) && !blocked(&filter, [0, 0, 0, 1]));
+        assert!(blocked(&filter, [255, 255, 255, 255]));
+        assert!(!blocked(&filter, [255, 255, 254, 255]));
+    }
+
+    #[test]
+    fn test_clones_share_ranges_and_count() {
+        let filter = IpFilter::new();
+        filter.set_ranges(["10.0.0.10-10.0.0.20".parse().unwrap()]);
+        let clone = filter.clone();
+        assert!(!clone.allows([10, 0, 0, 10].into()));
+        assert!(clone.allows([10, 0, 0, 21].into()));
+        // Only blocked attempts are counted, and checking an address is not an attempt.
+        assert!(blocked(&filter, [10, 0, 0, 11]));
+        assert_eq!(filter.blocked_attempts(), 1);
+
+        clone.set_ranges([]);
+        assert!(!blocked(&filter, [10, 0, 0, 10]));
+    }
+
+    #[test]
+    fn test_bans_outlast_ranges() {
+        let filter = IpFilter::new();
+        assert!(filter.ban([10, 0, 0, 10].into()));
+        assert!(!filter.ban([10, 0, 0, 10].into()));
+        filter.set_ranges(["10.0.0.11".parse().unwrap()]);
+        filter.set_ranges([]);
+        assert!(blocked(&filter, [10, 0, 0, 10]) && !blocked(&filter, [10, 0, 0, 11]));
+    }
+
+    #[tokio::test]
+    async fn test_loads_and_reloads_blocklists() {
+        let dir = tempfile::tempdir().unwrap();
+        let emule = write_blocklist(&dir, "ipfilter.dat", "1.2.3.0 - 1.2.3.255 , 0 , A\n");
+        let cidr = write_blocklist(&dir, "cidr.txt", "8.8.8.8\n1.2.4.0/24\n");
+
+        let filter = IpFilter::new();
+        let paths = vec![emule.clone(), cidr];
+        assert_eq!(filter.load(&paths).await.unwrap(), 2);
+        assert_eq!(filter.paths(), paths);
+        assert!(blocked(&filter, [1, 2, 4, 1]) && blocked(&filter, [8, 8, 8, 8]));
+
+        std::fs::write(&emule, "9.9.9.9\n").unwrap();
+        assert_eq!(filter.reload().await.unwrap(), 3);
+        assert!(blocked(&filter, [9, 9, 9, 9]) && !blocked(&filter, [1, 2, 3, 1]));
+    }
+
+    #[tokio::test]
+    async fn test_failed_load_leaves_filter_as_it_was() {
+        let dir = tempfile::tempdir().unwrap();
+        let good = [write_blocklist(&dir, "good.txt", "8.8.8.8\n")];
+        let bad = write_blocklist(&dir, "bad.txt", "garbage\n");
+        let filter = IpFilter::new();
+        filter.load(&good).await.unwrap();
+
+        assert!(filter.load(&[bad]).await.is_err());
+        assert!(filter
+            .load(&[dir.path().join("missing.txt")])
+            .await
+            .is_err());
+        assert_eq!(filter.paths(), good);
+        assert!(blocked(&filter, [8, 8, 8, 8]));
+    }
+
+    #[tokio::test]
+    async fn test_reload_racing_load_keeps_latest_blocklists() {
+        let dir = tempfile::tempdir().unwrap();
+        let old = [write_blocklist(&dir, "old.txt", "1.1.1.1\n")];
+        let new = [write_blocklist(&dir, "new.txt", "2.2.2.2\n")];
+
+        // Whichever starts first, the blocklists loaded last are the ones in use.
+        for reload_first in [true, false] {
+            let filter = IpFilter::new();
+            filter.load(&old).await.unwrap();
+            let (reloaded, loaded) = if reload_first {
+                tokio::join!(filter.reload(), filter.load(&new))
+            } else {
+                let (loaded, reloaded) = tokio::join!(filter.load(&new), filter.reload());
+                (reloaded, loaded)
+            };
+            reloaded.unwrap();
+            loaded.unwrap();
+            assert_eq!(filter.paths(), new);
+            assert!(blocked(&filter, [2, 2, 2, 2]) && !blocked(&filter, [1, 1, 1, 1]));
+        }
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::str::FromStr;
+
+use anyhow::Context;
+
+/// An inclusive range of IPv4 addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IpRange {
+    first: Ipv4Addr,
+    last: Ipv4Addr,
+}
+
+impl IpRange {
+    /// Creates the range of addresses from `first` to `last`, both included.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `last` comes before `first`.
+    pub fn new(first: Ipv4Addr, last: Ipv4Addr) -> anyhow::Result<Self> {
+        anyhow::ensure!(first <= last, "IP range {first}-{last} is empty.");
+        Ok(Self { first, last })
+    }
+
+    /// Creates the range of the addresses of a network, such as `10.0.0.0/8`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the prefix is longer than 32 bits.
+    pub fn cidr(address: Ipv4Addr, prefix: u8) -> anyhow::Result<Self> {
+        anyhow::ensure!(prefix <= 32, "Prefix /{prefix} is longer than 32 bits.");
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        let first = u32::from(address) & mask;
+        Ok(Self {
+            first: first.into(),
+            last: (first | !mask).into(),
+        })
+    }
+
+    /// Returns the first address of the range.
+    pub fn first(&self) -> Ipv4Addr {
+        self.first
+    }
+
+    /// Returns the last address of the range.
+    pub fn last(&self) -> Ipv4Addr {
+        self.last
+    }
+
+    /// Returns whether the range contains an address.
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        (self.first..=self.last).contains(&address)
+    }
+}
+
+impl FromStr for IpRange {
+    type Err = anyhow::Error;
+
+    /// Parses a network such as `10.0.0.0/8`, a range such as `10.0.0.1-10.0.0.9`, or a
+    /// single address.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let address = |s: &str| {
+            s.trim()
+                .parse::<Ipv4Addr>()
+                .with_context(|| format!("Invalid IPv4 address {s}."))
+        };
+        if let Some((network, prefix)) = s.split_once('/') {
+            let prefix = prefix
+                .trim()
+                .parse()
+                .with_context(|| format!("Invalid prefix length {prefix}."))?;
+            Self::cidr(address(network)?, prefix)
+        } else if let Some((first, last)) = s.split_once('-') {
+            Self::new(address(first)?, address(last)?)
+        } else {
+            let address = address(s)?;
+            Self::new(address, address)
+        }
+    }
+}
+
+impl fmt::Display for IpRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.first, self.last)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_networks() {
+        let network = "192.168.1.77/24".parse::<IpRange>().unwrap();
+        assert_eq!(network.first(), Ipv4Addr::new(192, 168, 1, 0));
+        assert_eq!(network.last(), Ipv4Addr::new(192, 168, 1, 255));
+
+        let everything = "0.0.0.0/0".parse::<IpRange>().unwrap();
+        assert_eq!(everything.first(), Ipv4Addr::UNSPECIFIED);
+        assert_eq!(everything.last(), Ipv4Addr::BROADCAST);
+        let host = "10.0.0.7/32".parse::<IpRange>().unwrap();
+        assert_eq!(
+            (host.first(), host.last()),
+            ([10, 0, 0, 7].into(), [10, 0, 0, 7].into())
+        );
+        assert_eq!(
+            " 10.0.0.0 / 8 ".parse::<IpRange>().unwrap().to_string(),
+            "10.0.0.0-10.255.255.255"
+        );
+    }
+
+    #[test]
+    fn test_parses_ranges_and_addresses() {
+        let range = "10.0.0.1 - 10.0.0.9".parse::<IpRange>().unwrap();
+        assert_eq!(range.to_string(), "10.0.0.1-10.0.0.9");
+        assert_eq!(range, "10.0.0.1-10.0.0.9".parse().unwrap());
+
+        let address = "10.0.0.1".parse::<IpRange>().unwrap();
+        assert_eq!(address.first(), address.last());
+        assert_eq!(address, "10.0.0.1-10.0.0.1".parse().unwrap());
+    }
+
+    #[test]
+    fn test_rejects_malformed_ranges() {
+        for s in [
+            "10.0.0.9-10.0.0.1",
+            "10.0.0.0/33",
+            "10.0.0/8",
+            "10.0.0.0/x",
+            "10.0.0.0/",
+            "10.0.0.1-",
+            "-10.0.0.1",
+            "",
+        ] {
+            assert!(s.parse::<IpRange>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn test_contains_both_ends() {
+        let range = "10.0.0.1-10.0.0.9".parse::<IpRange>().unwrap();
+        assert!(range.contains([10, 0, 0, 1].into()));
+        assert!(range.contains([10, 0, 0, 9].into()));
+        assert!(!range.contains([10, 0, 0, 0].into()));
+        assert!(!range.contains([10, 0, 0, 10].into()));
+        assert!(!range.contains([10, 0, 1, 1].into()));
+
+        let everything = "0.0.0.0/0".parse::<IpRange>().unwrap();
+        assert!(everything.contains(Ipv4Addr::UNSPECIFIED));
+        assert!(everything.contains(Ipv4Addr::BROADCAST));
+    }
+}
@@ -0,0 +1,364 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Upload and download rates in bytes per second. `None` means unlimited.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rates {
+    pub upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+impl Rates {
+    /// Rates without any limit.
+    pub const UNLIMITED: Self = Self {
+        upload: None,
+        download: None,
+    };
+
+    /// Creates rates from an upload and a download limit.
+    pub fn new(upload: Option<u64>, download: Option<u64>) -> Self {
+        Self { upload, download }
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    /// Bytes that may be transferred right away. It goes below zero when a message larger than
+    /// the bucket is let through, and the debt is paid back before anything else passes.
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        if let Some(rate) = self.rate {
+            let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+            // The bucket holds a second worth of bytes, which is the largest burst allowed.
+            self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64);
+        }
+        self.refilled_at = now;
+    }
+}
+
+/// A token bucket that limits the rate at which bytes are transferred.
+///
+/// The limiter is shared by its clones, so that every connection holding one draws from the
+/// same bucket. Its rate can be changed at any time, and applies to the next transfer.
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter that lets `rate` bytes per second through, or everything if `rate`
+    /// is `None`. It starts full, with a second worth of bytes.
+    pub fn new(rate: Option<u64>) -> Self {
+        let bucket = Bucket {
+            rate,
+            tokens: rate.unwrap_or_default() as f64,
+            refilled_at: Instant::now(),
+        };
+        Self {
+            bucket: Arc::new(Mutex::new(bucket)),
+        }
+    }
+
+    /// Returns the rate in bytes per second, or `None` if it is unlimited.
+    pub fn rate(&self) -> Option<u64> {
+        self.bucket
+            .lock()
+            .expect("Bucket lock is not poisoned.")
+            .rate
+    }
+
+    /// Changes the rate in bytes per second. `None` lifts the limit.
+    pub fn set_rate(&self, rate: Option<u64>) {
+        let mut bucket = self.bucket.lock().expect("Bucket lock is not poisoned.");
+        bucket.refill(Instant::now());
+        bucket.rate = rate;
+        if let Some(rate) = rate {
+            bucket.tokens = bucket.tokens.min(rate as f64);
+        }
+    }
+
+    /// Waits until the bucket is not in debt, and takes `bytes` from it.
+    ///
+    /// The bytes are only taken once the wait is over, so the future can be dropped before
+    /// it completes without taking anything.
+    pub async fn acquire(&self, bytes: usize) {
+        acquire_all([self], bytes).await
+    }
+
+    /// Returns how long until the bucket is out of debt, or `None` if it is not in debt.
+    fn debt_time(&self) -> Option<Duration> {
+        let mut bucket = self.bucket.lock().expect("Bucket lock is not poisoned.");
+        let rate = bucket.rate.filter(|&rate| rate > 0)?;
+        bucket.refill(Instant::now());
+        (bucket.tokens < 0.0).then(|| Duration::from_secs_f64(-bucket.tokens / rate as f64))
+    }
+
+    fn take(&self, bytes: usize) {
+        let mut bucket = self.bucket.lock().expect("Bucket lock is not poisoned.");
+        if bucket.rate.is_some_and(|rate| rate > 0) {
+            bucket.tokens -= bytes as f64;
+        }
+    }
+}
+
+/// Waits until none of `limiters` is in debt, and takes `bytes` from every one of them at once.
+async fn acquire_all<'a>(
+    limiters: impl IntoIterator<Item = &'a RateLimiter> + Clone,
+    bytes: usize,
+) {
+    loop {
+        let wait = limiters
+            .clone()
+            .into_iter()
+            .filter_map(RateLimiter::debt_time)
+            .max();
+        match wait {
+            Some(wait) => tokio::time::sleep(wait).await,
+            None => break,
+        }
+    }
+    for limiter in limiters {
+        limiter.take(bytes);
+    }
+}
+
+/// A pair of limiters, for the bytes we upload and the bytes we download.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    upload: RateLimiter,
+    download: RateLimiter,
+}
+
+impl RateLimits {
+    /// Creates a pair of limiters with the given rates.
+    pub fn new(rates: Rates) -> Self {
+        Self {
+            upload: RateLimiter::new(rates.upload),
+            download: RateLimiter::new(rates.download),
+        }
+    }
+
+    /// Returns the current rates of the limiters.
+    pub fn rates(&self) -> Rates {
+        Rates::new(self.upload.rate(), self.download.rate())
+    }
+
+    /// Changes the rates of both limiters.
+    pub fn set_rates(&self, rates: Rates) {
+        self.upload.set_rate(rates.upload);
+        self.download.set_rate(rates.download);
+    }
+
+    /// Returns the limiter of the bytes we upload.
+    pub fn upload(&self) -> &RateLimiter {
+        &self.upload
+    }
+
+    /// Returns the limiter of the bytes we download.
+    pub fn download(&self) -> &RateLimiter {
+        &self.download
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self::new(Rates::UNLIMITED)
+    }
+}
+
+/// The limits a connection goes through, such as the global, torrent and peer limits. A
+/// transfer waits until none of them is in debt, and is then charged to all of them.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    limits: Vec<RateLimits>,
+}
+
+impl Throttle {
+    /// Creates a throttle that goes through every one of `limits`.
+    pub fn new(limits: impl IntoIterator<Item = RateLimits>) -> Self {
+        Self {
+            limits: limits.into_iter().collect(),
+        }
+    }
+
+    /// Waits until `bytes` may be uploaded, and charges every upload limit for them.
+    ///
+    /// Like [`RateLimiter::acquire`], nothing is charged if the future is dropped early.
+    pub async fn upload(&self, bytes: usize) {
+        acquire_all(self.limits.iter().map(|limits| &limits.upload), bytes).await
+    }
+
+    /// Waits until `bytes` may be downloaded, and charges every download limit for them.
+    pub async fn download(&self, bytes: usize) {
+        acquire_all(self.limits.iter().map(|limits| &limits.download), bytes).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_about(elapsed: Duration, expected: Duration) {
+        let tolerance = Duration::from_millis(50);
+        assert!(
+            elapsed >= expected && elapsed <= expected + tolerance,
+            "{elapsed:?} is not about {expected:?}"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lets_a_burst_through_then_limits_rate() {
+        let limiter = RateLimiter::new(Some(1000));
+        let start = Instant::now();
+
+        // A second worth of bytes goes through at once, and a message more while the bucket
+        // goes into debt. The other 1900 bytes go at the rate.
+        for _ in 0..30 {
+            limiter.acquire(100).await;
+        }
+        assert_about(start.elapsed(), Duration::from_millis(1900));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lets_a_message_larger_than_the_bucket_through() {
+        let limiter = RateLimiter::new(Some(1000));
+        let start = Instant::now();
+
+        // The message goes at once, and the next one waits until its debt is paid back.
+        limiter.acquire(3000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        limiter.acquire(1).await;
+        assert_about(start.elapsed(), Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_applies_rate_change_to_next_transfer() {
+        let limiter = RateLimiter::new(Some(1000));
+        limiter.acquire(1000).await;
+        limiter.set_rate(Some(10_000));
+        assert_eq!(limiter.rate(), Some(10_000));
+
+        // The bucket is empty, so the first message goes at once and every other one ten
+        // milliseconds after the one before it.
+        let start = Instant::now();
+        for _ in 0..10 {
+            limiter.acquire(100).await;
+        }
+        assert_about(start.elapsed(), Duration::from_millis(90));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lowering_rate_shrinks_burst() {
+        let limiter = RateLimiter::new(Some(10_000));
+        limiter.set_rate(Some(100));
+        let start = Instant::now();
+
+        // Only a second worth of bytes at the new rate is left in the bucket.
+        limiter.acquire(200).await;
+        limiter.acquire(1).await;
+        assert_about(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_unlimited_never_waits() {
+        let limiter = RateLimiter::new(Some(1000));
+        limiter.acquire(1 << 20).await;
+        limiter.set_rate(None);
+        assert_eq!(limiter.rate(), None);
+
+        // The debt is forgotten along with the limit.
+        let start = Instant::now();
+        limiter.acquire(1 << 30).await;
+        limiter.acquire(1 << 30).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dropped_acquire_takes_nothing() {
+        let limiter = RateLimiter::new(Some(1000));
+        limiter.acquire(2000).await;
+        let start = Instant::now();
+
+        // The wait is cut short, so the 5000 bytes are never charged.
+        let acquire = limiter.acquire(5000);
+        assert!(tokio::time::timeout(Duration::from_millis(500), acquire)
+            .await
+            .is_err());
+        limiter.acquire(1).await;
+        assert_about(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_clones_share_the_bucket() {
+        let limiter = RateLimiter::new(Some(1000));
+        let clone = limiter.clone();
+        let start = Instant::now();
+
+        limiter.acquire(2000).await;
+        clone.acquire(1).await;
+        assert_about(start.elapsed(), Duration::from_secs(1));
+
+        clone.set_rate(Some(50));
+        assert_eq!(limiter.rate(), Some(50));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_waits_for_slowest_limit() {
+        let global = RateLimits::new(Rates::new(Some(1000), None));
+        let peer = RateLimits::new(Rates::new(Some(500), None));
+        let throttle = Throttle::new([global, peer]);
+        let start = Instant::now();
+
+        // The peer bucket is in debt after 1000 bytes, and waits for a second before the
+        // next 500 bytes.
+        for _ in 0..3 {
+            throttle.upload(500).await;
+        }
+        assert_about(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_charges_every_limit() {
+        let global = RateLimits::new(Rates::new(Some(1000), None));
+        let peer = RateLimits::new(Rates::new(Some(1000), None));
+        Throttle::new([global.clone(), peer]).upload(2000).await;
+        let start = Instant::now();
+
+        // Another connection through the global limit alone waits for the debt of the first.
+        Throttle::new([global]).upload(1).await;
+        assert_about(start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_throttle_directions_are_independent() {
+        let limits = RateLimits::new(Rates::new(Some(1000), Some(10)));
+        let throttle = Throttle::new([limits.clone()]);
+        throttle.download(20).await;
+        let start = Instant::now();
+
+        // The download debt does not hold back uploads.
+        throttle.upload(1000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        throttle.download(1).await;
+        assert_about(start.elapsed(), Duration::from_secs(1));
+
+        limits.set_rates(Rates::new(Some(2000), None));
+        assert_eq!(limits.rates(), Rates::new(Some(2000), None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_empty_throttle_never_waits() {
+        let throttle = Throttle::default();
+        let start = Instant::now();
+        throttle.upload(1 << 30).await;
+        throttle.download(1 << 30).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}
@@ -17,8 +17,8 @@ impl Message {
     /// # Errors
     ///
     /// If the message tag does not require a payload, and it is provided, an error is returned.
-    /// The messages that do not require a payload are: `Choke`, `UnChoke`, `Interested`, and
-    /// `NotInterested`.
+    /// The messages that do not require a payload are: `Choke`, `UnChoke`, `Interested`,
+    /// `NotInterested` and `KeepAlive`.
     ///
     /// # Examples
     ///
@@ -35,7 +35,8 @@ impl Message {
             MessageTag::Choke
             | MessageTag::UnChoke
             | MessageTag::Interested
-            | MessageTag::NotInterested => {
+            | MessageTag::NotInterested
+            | MessageTag::KeepAlive => {
                 if !payload.is_empty() {
                     return Err(anyhow::anyhow!(
                         "Message tag {:?} does not require a payload.",
@@ -53,8 +54,8 @@ impl Message {
     }
     /// Creates a new message from message tag, without payload.
     ///
-    /// The messages that do not require a payload are: `Choke`, `UnChoke`, `Interested`, and
-    /// `NotInterested`.
+    /// The messages that do not require a payload are: `Choke`, `UnChoke`, `Interested`,
+    /// `NotInterested` and `KeepAlive`.
     ///
     /// # Errors
     ///
@@ -74,7 +75,8 @@ impl Message {
             MessageTag::Choke
             | MessageTag::UnChoke
             | MessageTag::Interested
-            | MessageTag::NotInterested => Ok(Self {
+            | MessageTag::NotInterested
+            | MessageTag::KeepAlive => Ok(Self {
                 tag,
                 payload: Vec::new(),
             }),
@@ -91,6 +93,23 @@ impl Message {
     pub fn payload(&self) -> &[u8] {
         self.payload.as_slice()
     }
+
+    /// Returns the number of bytes the message takes on the wire, with its length prefix.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ltorrent::net::message::{Message, MessageTag};
+    ///
+    /// let message = Message::new(MessageTag::Have, vec![0, 0, 0, 1]).unwrap();
+    /// assert_eq!(message.wire_length(), 9);
+    /// ```
+    pub fn wire_length(&self) -> usize {
+        match self.tag {
+            MessageTag::KeepAlive => 4,
+            _ => 4 + 1 + self.payload.len(),
+        }
+    }
 }
 
 /// Represents the different types of messages exchanged between peers.
@@ -134,6 +153,38 @@ pub enum MessageTag {
     /// from becoming horribly inefficient, it sends cancels to everyone else every time a piece
     /// arrives.
     Cancel = 8,
+    /// No payload and no message id. It is sent as a zero-length frame, and peers send it to
+    /// keep the connection open when no other message has been sent for a while. Connections
+    /// that stay silent for two minutes are generally closed. Its value lies outside the range
+    /// of message ids, so that it can never be mistaken for one.
+    KeepAlive = -1,
+}
+
+impl MessageTag {
+    /// Returns the id of the message on the wire, which a `KeepAlive` does not have.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ltorrent::net::message::MessageTag;
+    ///
+    /// assert_eq!(MessageTag::Cancel.id(), Some(8));
+    /// assert_eq!(MessageTag::KeepAlive.id(), None);
+    /// ```
+    pub fn id(&self) -> Option<u8> {
+        match self {
+            Self::Choke => Some(0),
+            Self::UnChoke => Some(1),
+            Self::Interested => Some(2),
+            Self::NotInterested => Some(3),
+            Self::Have => Some(4),
+            Self::Bitfield => Some(5),
+            Self::Request => Some(6),
+            Self::Piece => Some(7),
+            Self::Cancel => Some(8),
+            Self::KeepAlive => None,
+        }
+    }
 }
 
 impl TryFrom<u8> for MessageTag {
@@ -164,63 +215,70 @@ impl Decoder for MessageFramer {
     type Item = Message;
     type Error = std::io::Error;
 
+    /// Decodes the next message. Messages with an id we do not know, such as the `Port`
+    /// message of the DHT or the messages of the extension protocol, are skipped, so that
+    /// peers that support more than we do can still talk to us.
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        // The peer message consists of a message length prefix (4 bytes), a message id (1 byte),
-        // and a message payload (variable length).
-        if src.len() < 4 {
-            // Not enough bytes to read the length marker.
-            return Ok(None);
-        }
+        loop {
+            // The peer message consists of a message length prefix (4 bytes), a message id
+            // (1 byte), and a message payload (variable length).
+            if src.len() < 4 {
+                // Not enough bytes to read the length marker.
+                return Ok(None);
+            }
 
-        // Read length marker
-        let mut length_bytes = [0u8; 4];
-        length_bytes.copy_from_slice(&src[..4]);
-        let length = u32::from_be_bytes(length_bytes) as usize;
+            // Read length marker
+            let mut length_bytes = [0u8; 4];
+            length_bytes.copy_from_slice(&src[..4]);
+            let length = u32::from_be_bytes(length_bytes) as usize;
 
-        if length == 0 {
-            // This is a heartbeat message. Discard it (for now).
-            // We advance till after the heartbeat message and try to decode again.
-            src.advance(4);
-            return self.decode(src);
-        }
+            if length == 0 {
+                // This is a heartbeat message. It carries no data, but the peer session needs
+                // to see it to know that the connection is still alive.
+                src.advance(4);
+                return Ok(Some(Message {
+                    tag: MessageTag::KeepAlive,
+                    payload: Vec::new(),
+                }));
+            }
 
-        if src.len() < 5 {
-            // Not enough bytes to read the message tag.
-            return Ok(None);
-        }
+            if src.len() < 5 {
+                // Not enough bytes to read the message tag.
+                return Ok(None);
+            }
 
-        // Check that the length is not too large to avoid a denial of service attack where the
-        // server runs out of memory.
-        if length > MAX_MESSAGE_LENGTH {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Decoding: Frame of length {} is too large.", length),
-            ));
-        }
+            // Check that the length is not too large to avoid a denial of service attack where
+            // the server runs out of memory.
+            if length > MAX_MESSAGE_LENGTH {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("Decoding: Frame of length {} is too large.", length),
+                ));
+            }
 
-        if src.len() < 4 + length {
-            // The full string has not yet arrived.
-            //
-            // We reserve more space in the buffer. This is not strictly
-            // necessary, but is a good idea performance-wise.
-            src.reserve(4 + length - src.len());
+            if src.len() < 4 + length {
+                // The full string has not yet arrived.
+                //
+                // We reserve more space in the buffer. This is not strictly
+                // necessary, but is a good idea performance-wise.
+                src.reserve(4 + length - src.len());
 
-            // We inform the Framed that we need more bytes to form the next
-            // frame.
-            return Ok(None);
-        }
+                // We inform the Framed that we need more bytes to form the next
+                // frame.
+                return Ok(None);
+            }
 
-        // Use advance to modify src such that it no longer contains
-        // this frame.
-        let tag = MessageTag::try_from(src[4])?;
-        let data = if src.len() > 5 {
-            src[5..4 + length].to_vec()
-        } else {
-            Vec::new()
-        };
-        src.advance(4 + length);
+            // Use advance to modify src such that it no longer contains
+            // this frame.
+            let Ok(tag) = MessageTag::try_from(src[4]) else {
+                src.advance(4 + length);
+                continue;
+            };
+            let data = src[5..4 + length].to_vec();
+            src.advance(4 + length);
 
-        Ok(Some(Message { tag, payload: data }))
+            return Ok(Some(Message { tag, payload: data }));
+        }
     }
 }
 
@@ -239,6 +297,12 @@ impl Encoder<Message> for MessageFramer {
             ));
         }
 
+        // A heartbeat is a bare zero length prefix, without message id.
+        let Some(id) = item.tag.id() else {
+            dst.extend_from_slice(&[0; 4]);
+            return Ok(());
+        };
+
         // Convert the length into a byte array.
         // The cast to u32 cannot overflow due to the length check above.
         let length_bytes = u32::to_be_bytes(item.payload.len() as u32 + 1);
@@ -250,8 +314,47 @@ impl Encoder<Message> for MessageFramer {
 
         // Write the length and string to the buffer.
         dst.extend_from_slice(&length_bytes);
-        dst.put_u8(item.tag as u8);
+        dst.put_u8(id);
         dst.extend_from_slice(item.payload.as_slice());
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoder_skips_unknown_messages() {
+        let mut src = BytesMut::new();
+        // A `Port` message of the DHT, an extended message, a keep-alive and an `Interested`.
+        src.extend_from_slice(&[0, 0, 0, 3, 9, 0x1a, 0xe1]);
+        src.extend_from_slice(&[0, 0, 0, 3, 20, 0, b'd']);
+        src.extend_from_slice(&[0, 0, 0, 0]);
+        src.extend_from_slice(&[0, 0, 0, 1, 2]);
+
+        let mut framer = MessageFramer;
+        let keep_alive = framer.decode(&mut src).unwrap().unwrap();
+        assert_eq!(keep_alive.tag(), &MessageTag::KeepAlive);
+        let interested = framer.decode(&mut src).unwrap().unwrap();
+        assert_eq!(interested.tag(), &MessageTag::Interested);
+        assert!(src.is_empty());
+        assert!(framer.decode(&mut src).unwrap().is_none());
+
+        // An unknown message that has not fully arrived is waited for.
+        src.extend_from_slice(&[0, 0, 0, 3, 9, 0x1a]);
+        assert!(framer.decode(&mut src).unwrap().is_none());
+        assert_eq!(src.len(), 6);
+    }
+
+    #[test]
+    fn test_encodes_keep_alive_without_id() {
+        let mut dst = BytesMut::new();
+        let mut framer = MessageFramer;
+        let keep_alive = Message::without_payload(MessageTag::KeepAlive).unwrap();
+        framer.encode(keep_alive, &mut dst).unwrap();
+        let cancel = Message::new(MessageTag::Cancel, vec![0; 12]).unwrap();
+        framer.encode(cancel, &mut dst).unwrap();
+        assert_eq!(&dst[..9], &[0, 0, 0, 0, 0, 0, 0, 13, 8]);
+    }
+}
@@ -1,3 +1,10 @@
 pub mod bitfield;
+pub mod block;
+pub mod class;
+pub mod filter;
+pub mod iprange;
+pub mod limit;
 pub mod message;
 pub mod peers;
+pub mod schedule;
+pub mod session;
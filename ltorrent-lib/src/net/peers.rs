@@ -1,4 +1,5 @@
 use std::net::SocketAddrV4;
+use std::time::Duration;
 
 use anyhow::Context;
 use futures_util::sink::SinkExt;
@@ -8,8 +9,28 @@ use tokio::net::TcpStream;
 use tokio_util::codec::Framed;
 
 use super::bitfield::BitField;
+use super::limit::Throttle;
 use super::message::*;
 
+/// Time the first message of a peer is waited for after the handshake, in case it is the
+/// `Bitfield`. A peer that has no pieces may skip it, and wait for us to speak first.
+const BITFIELD_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Where the address of a peer came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PeerSource {
+    /// The response of a tracker.
+    Tracker,
+    /// The distributed hash table.
+    Dht,
+    /// Peer exchange with another peer.
+    Pex,
+    /// Local service discovery.
+    Lsd,
+    /// The peer connected to us.
+    Incoming,
+}
+
 /// Represents a peer connection in the BitTorrent network.
 ///
 /// This struct holds the state of the connection with a specific peer, including:
@@ -24,6 +45,13 @@ pub struct Peer<S> {
     peer_id: [u8; 20],
     stream: Framed<S, MessageFramer>,
     bitfield: BitField,
+    /// A message received during the handshake, which has not been returned yet.
+    pending: Option<Message>,
+    /// The bandwidth limits that the messages go through.
+    throttle: Throttle,
+    /// Bytes received that the download limits have not been charged for yet.
+    unpaid: usize,
+    source: PeerSource,
 }
 
 impl<S> Peer<S>
@@ -33,9 +61,8 @@ where
     /// Creates a new peer connection.
     ///
     /// First, it connects to the peer with a TCP stream. Subsequently, it performs
-    /// the handshake with the peer. If the handshake is successful, it receives the
-    /// bitfield message from the peer, which contains the pieces that the peer has.
-    /// Finally, it returns a new peer connection.
+    /// the handshake with the peer, and exchanges bitfields with it. Finally, it returns
+    /// a new peer connection.
     ///
     /// # Errors
     ///
@@ -46,13 +73,43 @@ where
     /// - The handshake message cannot be sent.
     /// - The handshake message cannot be received.
     /// - The received handshake message does not follow the BitTorrent protocol.
-    pub async fn new(address: SocketAddrV4, peer_id: [u8; 20], info_hash: [u8; 20]) -> anyhow::Result<Peer<TcpStream>> {
+    pub async fn new(
+        address: SocketAddrV4,
+        peer_id: [u8; 20],
+        info_hash: [u8; 20],
+        bitfield: &BitField,
+    ) -> anyhow::Result<Peer<TcpStream>> {
 
         // Connect to peer with TCP stream.
-        let mut stream = TcpStream::connect(address)
+        let stream = TcpStream::connect(address)
             .await
             .context("Failed to connect to peer via TCP stream.")?;
+        Peer::handshake(address, stream, peer_id, info_hash, bitfield).await
+    }
 
+    /// Performs the handshake over an already established stream, in either direction.
+    ///
+    /// It sends our handshake, receives the handshake of the peer, and sends `bitfield` with
+    /// the pieces we have, unless we have none. Then it receives the bitfield message with the
+    /// pieces that the peer has. A peer that has no pieces may skip it, in which case its
+    /// bitfield starts empty, and the first message it sent, if it sent one within a couple of
+    /// seconds, is returned by [`Peer::next`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The handshake message cannot be sent.
+    /// - The handshake message cannot be received.
+    /// - The received handshake message does not follow the BitTorrent protocol.
+    /// - The received handshake message is for another torrent.
+    /// - The peer closes the connection before sending a message.
+    pub async fn handshake(
+        address: SocketAddrV4,
+        mut stream: S,
+        peer_id: [u8; 20],
+        info_hash: [u8; 20],
+        bitfield: &BitField,
+    ) -> anyhow::Result<Self> {
         // Perform handshake with peer.
         let handshake = HandShakeMessage::new(info_hash, peer_id);
         let mut handshake_bytes = [0u8; size_of::<HandShakeMessage>()];
@@ -62,27 +119,57 @@ where
             handshake_bytes[1..20] == *b"BitTorrent protocol",
             "Peer did not send BitTorrent protocol."
         );
+        anyhow::ensure!(
+            handshake_bytes[28..48] == info_hash,
+            "Peer sent the handshake of another torrent."
+        );
         let peer_id: [u8; 20] = handshake_bytes[48..].try_into()?;
 
         // Frame stream so that messages can be sent and received in a structured manner.
         let mut framed_stream = Framed::new(stream, MessageFramer);
-        let bitfield = framed_stream
-            .next()
-            .await
-            .expect("Peer always sends Bitfield message.")?;
-        anyhow::ensure!(
-            *bitfield.tag() == MessageTag::Bitfield,
-            "Peer did not send Bitfield message."
-        );
+        if bitfield.into_iter().next().is_some() {
+            let message = Message::new(MessageTag::Bitfield, bitfield.payload().to_vec())?;
+            framed_stream.send(message).await.context("Failed to send bitfield.")?;
+        }
+        let first = tokio::time::timeout(BITFIELD_TIMEOUT, framed_stream.next()).await;
+        let (bitfield, pending) = match first {
+            Ok(message) => {
+                let message = message.context("Peer closed the connection after the handshake.")??;
+                match message.tag() {
+                    MessageTag::Bitfield => (BitField::from_payload(message.payload()), None),
+                    _ => (BitField::from_payload(&[]), Some(message)),
+                }
+            }
+            // The peer is waiting for us, and has no pieces to tell us about.
+            Err(_) => (BitField::from_payload(&[]), None),
+        };
 
         Ok(Peer {
             address,
             peer_id,
             stream: framed_stream,
-            bitfield: BitField::from_payload(bitfield.payload()),
+            bitfield,
+            pending,
+            throttle: Throttle::default(),
+            unpaid: 0,
+            source: PeerSource::Tracker,
         })
     }
 
+    /// Returns the socket address of the peer.
+    pub fn address(&self) -> SocketAddrV4 {
+        self.address
+    }
+
+    /// Returns the bitfield with the pieces that the peer has.
+    pub fn bitfield(&self) -> &BitField {
+        &self.bitfield
+    }
+
+    pub(crate) fn bitfield_mut(&mut self) -> &mut BitField {
+        &mut self.bitfield
+    }
+
     pub fn peer_id(&self) -> &[u8; 20] {
         &self.peer_id
     }
@@ -91,12 +178,46 @@ where
         self.bitfield.contains_piece(piece_i)
     }
 
+    /// Returns where the address of the peer came from.
+    pub fn source(&self) -> PeerSource {
+        self.source
+    }
+
+    /// Records where the address of the peer came from, which is assumed to be a tracker
+    /// unless set otherwise.
+    pub fn set_source(&mut self, source: PeerSource) {
+        self.source = source;
+    }
+
+    /// Sets the bandwidth limits that the messages sent and received go through.
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+    }
+
+    /// Sends a message, once the upload limits let its bytes through.
     pub async fn send(&mut self, message: Message) -> std::io::Result<()> {
+        self.throttle.upload(message.wire_length()).await;
         self.stream.send(message).await
     }
 
+    /// Receives the next message, once the download limits are paid for the previous one.
+    ///
+    /// The bytes of a message are charged after it is read, so that the connection stops
+    /// reading from the socket until the limits allow it. The future can be dropped at any
+    /// point without losing a message.
     pub async fn next(&mut self) -> Option<std::io::Result<Message>> {
-        self.stream.next().await
+        if let Some(message) = self.pending.take() {
+            return Some(Ok(message));
+        }
+        if self.unpaid > 0 {
+            self.throttle.download(self.unpaid).await;
+            self.unpaid = 0;
+        }
+        let message = self.stream.next().await;
+        if let Some(Ok(message)) = &message {
+            self.unpaid = message.wire_length();
+        }
+        message
     }
 }
 
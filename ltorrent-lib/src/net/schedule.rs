@@ -0,0 +1,482 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use tokio::sync::{mpsc, watch};
+
+use crate::config::Configuration;
+
+use super::limit::{RateLimits, Rates};
+
+const MINUTES_PER_DAY: u32 = 24 * 60;
+const MINUTES_PER_WEEK: u32 = 7 * MINUTES_PER_DAY;
+const DAY_NAMES: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// The set of global rate limits in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpeedProfile {
+    /// The global limits of the configuration.
+    Normal,
+    /// The alternative limits of the configuration, in use during the windows of its schedule.
+    Alternative,
+}
+
+/// A time window that repeats on some days of the week, such as `mon-fri 09:00-17:00`.
+///
+/// The days are a range like `mon-fri`, a list like `sat,sun`, or `daily`. A window whose
+/// end is not after its start, such as `22:00-06:00`, runs past midnight into the next day.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TimeWindow {
+    /// Whether the window starts on each day, from Monday to Sunday.
+    days: [bool; 7],
+    /// Minute of the day the window starts at.
+    start: u32,
+    /// Minute of the day the window ends at.
+    end: u32,
+}
+
+impl TimeWindow {
+    /// Returns whether the window covers a minute of the week, counted from Monday 00:00.
+    fn contains(&self, minute: u32) -> bool {
+        let length = if self.end > self.start {
+            self.end - self.start
+        } else {
+            self.end + MINUTES_PER_DAY - self.start
+        };
+        (0..7).filter(|&day| self.days[day]).any(|day| {
+            let start = day as u32 * MINUTES_PER_DAY + self.start;
+            // A window late on Sunday covers the start of the next week as well.
+            [minute, minute + MINUTES_PER_WEEK]
+                .iter()
+                .any(|&minute| (start..start + length).contains(&minute))
+        })
+    }
+}
+
+impl FromStr for TimeWindow {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (days, times) = s
+            .trim()
+            .split_once(' ')
+            .with_context(|| format!("Time window {s} is not of the form <days> <HH:MM-HH:MM>."))?;
+        let (start, end) = times
+            .trim()
+            .split_once('-')
+            .with_context(|| format!("Times {times} are not of the form HH:MM-HH:MM."))?;
+        Ok(Self {
+            days: parse_days(days)?,
+            start: parse_time(start)?,
+            end: parse_time(end)?,
+        })
+    }
+}
+
+impl fmt::Display for TimeWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let days = (0..7)
+            .filter(|&day| self.days[day])
+            .map(|day| DAY_NAMES[day])
+            .collect::<Vec<_>>()
+            .join(",");
+        write!(
+            f,
+            "{days} {:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Parses days such as `mon-fri`, `sat,sun` or `daily` into whether each day is included.
+fn parse_days(s: &str) -> anyhow::Result<[bool; 7]> {
+    let day = |name: &str| {
+        DAY_NAMES
+            .iter()
+            .position(|day| name.eq_ignore_ascii_case(day))
+            .with_context(|| format!("Unknown day {name}, expected one of {DAY_NAMES:?}."))
+    };
+    let mut days = [false; 7];
+    if s.eq_ignore_ascii_case("daily") {
+        return Ok([true; 7]);
+    }
+    for part in s.split(',') {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (day(first)?, day(last)?);
+                // A range like sat-mon wraps around the end of the week.
+                let mut day = first;
+                loop {
+                    days[day] = true;
+                    if day == last {
+                        break;
+                    }
+                    day = (day + 1) % 7;
+                }
+            }
+            None => days[day(part)?] = true,
+        }
+    }
+    Ok(days)
+}
+
+/// Parses a time of the day of the form `HH:MM` into minutes since midnight. `24:00` is the
+/// end of the day.
+fn parse_time(s: &str) -> anyhow::Result<u32> {
+    let invalid = || format!("Time {s} is not of the form HH:MM.");
+    let (hours, minutes) = s.trim().split_once(':').with_context(invalid)?;
+    let hours = hours.parse::<u32>().with_context(invalid)?;
+    let minutes = minutes.parse::<u32>().with_context(invalid)?;
+    anyhow::ensure!(
+        minutes < 60 && hours <= 24 && hours * 60 + minutes <= MINUTES_PER_DAY,
+        invalid()
+    );
+    Ok((hours * 60 + minutes) % MINUTES_PER_DAY)
+}
+
+/// A weekly time table of when the alternative rate limits are in use.
+///
+/// The schedule is made of [`TimeWindow`]s, in the time zone given by its offset from UTC,
+/// which defaults to UTC. The alternative limits are in use within any of the windows, and
+/// the normal limits at any other time.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schedule {
+    windows: Vec<TimeWindow>,
+    /// Offset of the time zone of the windows from UTC, in minutes.
+    utc_offset: i32,
+}
+
+impl Schedule {
+    /// Creates a schedule out of time windows in UTC.
+    pub fn new(windows: Vec<TimeWindow>) -> Self {
+        Self {
+            windows,
+            utc_offset: 0,
+        }
+    }
+
+    /// Sets the offset of the time zone of the windows from UTC, in minutes.
+    pub fn with_utc_offset(mut self, minutes: i32) -> Self {
+        self.utc_offset = minutes;
+        self
+    }
+
+    /// Returns the time windows of the schedule.
+    pub fn windows(&self) -> &[TimeWindow] {
+        &self.windows
+    }
+
+    /// Returns the profile the schedule calls for at a point in time.
+    pub fn profile_at(&self, time: SystemTime) -> SpeedProfile {
+        let minute = self.minute_of_week(time);
+        if self.windows.iter().any(|window| window.contains(minute)) {
+            SpeedProfile::Alternative
+        } else {
+            SpeedProfile::Normal
+        }
+    }
+
+    /// Returns the minute of the week of a point in time in the time zone of the schedule,
+    /// counted from Monday 00:00.
+    fn minute_of_week(&self, time: SystemTime) -> u32 {
+        let minutes = time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64
+            / 60
+            + self.utc_offset as i64;
+        // The Unix epoch was a Thursday, the fourth day of the week.
+        (minutes + 3 * MINUTES_PER_DAY as i64).rem_euclid(MINUTES_PER_WEEK as i64) as u32
+    }
+}
+
+/// Parses an offset from UTC of the form `+HH:MM` or `-HH:MM` into minutes.
+///
+/// # Errors
+///
+/// This function will return an error if the offset is malformed.
+pub fn parse_utc_offset(s: &str) -> anyhow::Result<i32> {
+    let (sign, time) = match s.trim().split_at_checked(1) {
+        Some(("+", time)) => (1, time),
+        Some(("-", time)) => (-1, time),
+        _ => anyhow::bail!("UTC offset {s} is not of the form +HH:MM or -HH:MM."),
+    };
+    Ok(sign * parse_time(time)? as i32)
+}
+
+/// Commands that change a running scheduler.
+#[derive(Debug)]
+enum Control {
+    SetOverride(Option<SpeedProfile>),
+}
+
+/// A handle to override the profile of a running [`LimitScheduler`], and follow its changes.
+#[derive(Debug, Clone)]
+pub struct SchedulerControl {
+    commands: mpsc::UnboundedSender<Control>,
+    active: watch::Receiver<SpeedProfile>,
+}
+
+impl SchedulerControl {
+    /// Forces a profile regardless of the schedule, or follows the schedule again with `None`.
+    pub fn set_override(&self, profile: Option<SpeedProfile>) {
+        let _ = self.commands.send(Control::SetOverride(profile));
+    }
+
+    /// Returns the profile in use.
+    pub fn active(&self) -> SpeedProfile {
+        *self.active.borrow()
+    }
+
+    /// Returns a receiver that is notified whenever the profile in use changes.
+    pub fn subscribe(&self) -> watch::Receiver<SpeedProfile> {
+        self.active.clone()
+    }
+}
+
+/// Switches the global rate limits between their normal and alternative rates, following a
+/// weekly [`Schedule`] unless a profile is forced through its [`SchedulerControl`].
+#[derive(Debug)]
+pub struct LimitScheduler {
+    limits: RateLimits,
+    normal: Rates,
+    alternative: Rates,
+    schedule: Schedule,
+    active: watch::Sender<SpeedProfile>,
+    control_tx: mpsc::UnboundedSender<Control>,
+    control_rx: mpsc::UnboundedReceiver<Control>,
+}
+
+impl LimitScheduler {
+    /// Creates a scheduler that applies `normal` or `alternative` to `limits`.
+    pub fn new(limits: RateLimits, normal: Rates, alternative: Rates, schedule: Schedule) -> Self {
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        Self {
+            limits,
+            normal,
+            alternative,
+            schedule,
+            active: watch::Sender::new(SpeedProfile::Normal),
+            control_tx,
+            control_rx,
+        }
+    }
+
+    /// Creates a scheduler for the global limits of a configuration, if it has a schedule.
+    pub fn from_config(config: &Configuration) -> Option<Self> {
+        let schedule = config.schedule()?.clone();
+        Some(Self::new(
+            config.global_limits().clone(),
+            config.global_limit(),
+            config.alternative_limit(),
+            schedule,
+        ))
+    }
+
+    /// Returns a handle to override the profile while the scheduler runs.
+    pub fn control(&self) -> SchedulerControl {
+        SchedulerControl {
+            commands: self.control_tx.clone(),
+            active: self.active.subscribe(),
+        }
+    }
+
+    /// Applies the profile the schedule calls for, and checks it again at the start of every
+    /// minute. A forced profile applies until the override is lifted. It runs until the task
+    /// is aborted.
+    pub async fn run(mut self) {
+        let mut forced = None;
+        loop {
+            let profile = forced.unwrap_or_else(|| self.schedule.profile_at(SystemTime::now()));
+            self.apply(profile);
+
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            let next_minute = Duration::from_secs(60 - now.as_secs() % 60);
+            tokio::select! {
+                _ = tokio::time::sleep(next_minute) => {}
+                Some(Control::SetOverride(profile)) = self.control_rx.recv() => forced = profile,
+            }
+        }
+    }
+
+    /// Sets the rates of a profile, and notifies its change.
+    fn apply(&self, profile: SpeedProfile) {
+        let rates = match profile {
+            SpeedProfile::Normal => self.normal,
+            SpeedProfile::Alternative => self.alternative,
+        };
+        if self.limits.rates() != rates {
+            self.limits.set_rates(rates);
+        }
+        self.active.send_if_modified(|active| {
+            let changed = *active != profile;
+            *active = profile;
+            changed
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns a point in time, in UTC, of the week of Monday 2024-01-01.
+    fn at(day: u64, hours: u64, minutes: u64) -> SystemTime {
+        let monday = 1_704_067_200;
+        UNIX_EPOCH + Duration::from_secs(monday + day * 86_400 + hours * 3600 + minutes * 60)
+    }
+
+    fn window(s: &str) -> TimeWindow {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parses_time_windows() {
+        assert_eq!(
+            window("mon-fri 09:00-17:00").to_string(),
+            "mon,tue,wed,thu,fri 09:00-17:00"
+        );
+        assert_eq!(
+            window("SAT,sun 22:00-06:00").to_string(),
+            "sat,sun 22:00-06:00"
+        );
+        assert_eq!(
+            window("daily 00:00-24:00").to_string(),
+            "mon,tue,wed,thu,fri,sat,sun 00:00-00:00"
+        );
+        // A range of days wraps around the end of the week.
+        assert_eq!(
+            window("sat-mon 08:00-09:00").to_string(),
+            "mon,sat,sun 08:00-09:00"
+        );
+        assert_eq!(window("wed-wed 08:00-09:00").to_string(), "wed 08:00-09:00");
+
+        assert!("mon-fri 9-17".parse::<TimeWindow>().is_err());
+        assert!("someday 09:00-17:00".parse::<TimeWindow>().is_err());
+        assert!("mon-fri".parse::<TimeWindow>().is_err());
+        assert!("mon-fri 09:00".parse::<TimeWindow>().is_err());
+    }
+
+    #[test]
+    fn test_follows_windows_within_a_day() {
+        let schedule = Schedule::new(vec![window("mon-fri 09:00-17:00")]);
+        assert_eq!(schedule.profile_at(at(0, 8, 59)), SpeedProfile::Normal);
+        assert_eq!(schedule.profile_at(at(0, 9, 0)), SpeedProfile::Alternative);
+        assert_eq!(
+            schedule.profile_at(at(4, 16, 59)),
+            SpeedProfile::Alternative
+        );
+        assert_eq!(schedule.profile_at(at(4, 17, 0)), SpeedProfile::Normal);
+        assert_eq!(schedule.profile_at(at(5, 12, 0)), SpeedProfile::Normal);
+    }
+
+    #[test]
+    fn test_follows_windows_over_midnight() {
+        let schedule = Schedule::new(vec![window("fri 22:00-06:00")]);
+        assert_eq!(schedule.profile_at(at(4, 21, 59)), SpeedProfile::Normal);
+        assert_eq!(schedule.profile_at(at(4, 22, 0)), SpeedProfile::Alternative);
+        assert_eq!(schedule.profile_at(at(5, 5, 59)), SpeedProfile::Alternative);
+        assert_eq!(schedule.profile_at(at(5, 6, 0)), SpeedProfile::Normal);
+        // The window starts on Fridays only, so Friday morning is not part of it.
+        assert_eq!(schedule.profile_at(at(4, 5, 0)), SpeedProfile::Normal);
+    }
+
+    #[test]
+    fn test_follows_windows_from_sunday_into_monday() {
+        let schedule = Schedule::new(vec![window("sun 22:00-06:00")]);
+        assert_eq!(schedule.profile_at(at(6, 23, 0)), SpeedProfile::Alternative);
+        assert_eq!(schedule.profile_at(at(7, 5, 59)), SpeedProfile::Alternative);
+        // Monday morning of the week before is covered by the Sunday before it.
+        assert_eq!(schedule.profile_at(at(0, 5, 59)), SpeedProfile::Alternative);
+        assert_eq!(schedule.profile_at(at(0, 6, 0)), SpeedProfile::Normal);
+        assert_eq!(schedule.profile_at(at(1, 5, 59)), SpeedProfile::Normal);
+    }
+
+    #[test]
+    fn test_covers_a_whole_day_when_start_is_end() {
+        let always = Schedule::new(vec![window("daily 00:00-24:00")]);
+        for day in 0..7 {
+            assert_eq!(always.profile_at(at(day, 0, 0)), SpeedProfile::Alternative);
+            assert_eq!(
+                always.profile_at(at(day, 23, 59)),
+                SpeedProfile::Alternative
+            );
+        }
+
+        let schedule = Schedule::new(vec![window("mon 09:00-09:00")]);
+        assert_eq!(schedule.profile_at(at(1, 8, 59)), SpeedProfile::Alternative);
+        assert_eq!(schedule.profile_at(at(1, 9, 0)), SpeedProfile::Normal);
+    }
+
+    #[test]
+    fn test_follows_windows_in_their_time_zone() {
+        // At UTC+02:00, 07:00 UTC is office hours.
+        let office = Schedule::new(vec![window("mon-fri 09:00-17:00")])
+            .with_utc_offset(parse_utc_offset("+02:00").unwrap());
+        assert_eq!(office.profile_at(at(2, 7, 0)), SpeedProfile::Alternative);
+        assert_eq!(office.profile_at(at(2, 15, 0)), SpeedProfile::Normal);
+
+        // At UTC-02:00, Monday 01:00 UTC is still Sunday.
+        let sunday = Schedule::new(vec![window("sun 23:00-24:00")])
+            .with_utc_offset(parse_utc_offset("-02:00").unwrap());
+        assert_eq!(sunday.profile_at(at(0, 1, 0)), SpeedProfile::Alternative);
+        assert_eq!(sunday.profile_at(at(0, 2, 0)), SpeedProfile::Normal);
+    }
+
+    #[test]
+    fn test_parses_utc_offsets() {
+        assert_eq!(parse_utc_offset("+05:30").unwrap(), 330);
+        assert_eq!(parse_utc_offset("-02:00").unwrap(), -120);
+        assert_eq!(parse_utc_offset(" +00:00").unwrap(), 0);
+        assert!(parse_utc_offset("02:00").is_err());
+        assert!(parse_utc_offset("+").is_err());
+        assert!(parse_utc_offset("").is_err());
+    }
+
+    #[test]
+    fn test_parses_times_of_the_day() {
+        assert_eq!(parse_time("00:00").unwrap(), 0);
+        assert_eq!(parse_time(" 09:30").unwrap(), 9 * 60 + 30);
+        // The end of the day is the same minute as its start.
+        assert_eq!(parse_time("24:00").unwrap(), 0);
+        assert!(parse_time("24:01").is_err());
+        assert!(parse_time("12:60").is_err());
+        // Hours that would overflow the minutes are rejected, not wrapped around.
+        assert!(parse_time("71582789:00").is_err());
+        assert!(parse_time("4294967295:00").is_err());
+        assert!(parse_time("12").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_override_switches_limits_and_notifies() {
+        let limits = RateLimits::default();
+        let alternative = Rates::new(Some(1024), Some(2048));
+        let scheduler = LimitScheduler::new(
+            limits.clone(),
+            Rates::UNLIMITED,
+            alternative,
+            Schedule::default(),
+        );
+        let control = scheduler.control();
+        let mut changes = control.subscribe();
+        let task = tokio::spawn(scheduler.run());
+
+        control.set_override(Some(SpeedProfile::Alternative));
+        changes.changed().await.unwrap();
+        assert_eq!(control.active(), SpeedProfile::Alternative);
+        assert_eq!(limits.rates(), alternative);
+
+        // Without windows, the schedule calls for the normal limits.
+        control.set_override(None);
+        changes.changed().await.unwrap();
+        assert_eq!(*changes.borrow(), SpeedProfile::Normal);
+        assert_eq!(limits.rates(), Rates::UNLIMITED);
+        task.abort();
+    }
+}
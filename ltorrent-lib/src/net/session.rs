@@ -0,0 +1,628 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddrV4;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch};
+use tokio::time::{sleep_until, Instant};
+
+use super::bitfield::BitField;
+use super::block::{have_message, parse_have, Block, BlockRequest, BLOCK_SIZE};
+use super::message::{Message, MessageTag};
+use super::peers::Peer;
+
+/// Interval after which a keep-alive is sent, if no other message was sent in the meantime.
+pub const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// Time after which a peer that has not sent anything, not even a keep-alive, is dropped.
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Maximum number of requests from the peer that are queued before further ones are dropped.
+pub const MAX_PEER_REQUESTS: usize = 250;
+
+/// Time after we cancel a request during which the block is still accepted, in case the peer
+/// sent it before it got the `Cancel`.
+pub const CANCEL_GRACE: Duration = Duration::from_secs(30);
+
+/// The choke and interest flags of a connection.
+///
+/// Both sides of a connection start out choking and not interested. Data is only transferred
+/// in one direction when the receiving side is interested and the sending side is not choking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerState {
+    am_choking: bool,
+    am_interested: bool,
+    peer_choking: bool,
+    peer_interested: bool,
+}
+
+impl PeerState {
+    /// Returns whether we are choking the peer.
+    pub fn am_choking(&self) -> bool {
+        self.am_choking
+    }
+
+    /// Returns whether we are interested in the pieces of the peer.
+    pub fn am_interested(&self) -> bool {
+        self.am_interested
+    }
+
+    /// Returns whether the peer is choking us.
+    pub fn peer_choking(&self) -> bool {
+        self.peer_choking
+    }
+
+    /// Returns whether the peer is interested in our pieces.
+    pub fn peer_interested(&self) -> bool {
+        self.peer_interested
+    }
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        Self {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+/// Commands that higher layers send to a running peer session.
+#[derive(Debug)]
+pub enum PeerCommand {
+    /// Choke the peer, dropping all the requests it has queued.
+    Choke,
+    /// Unchoke the peer.
+    UnChoke,
+    /// Tell the peer we are interested in its pieces.
+    Interested,
+    /// Tell the peer we are not interested in its pieces.
+    NotInterested,
+    /// Request a block from the peer.
+    Request(BlockRequest),
+    /// Cancel a block previously requested from the peer.
+    Cancel(BlockRequest),
+    /// Send a block the peer has requested. It is dropped if the peer cancelled the request.
+    Block(Block),
+    /// Drop a request of the peer that will not be answered. The peer is not told.
+    Reject(BlockRequest),
+    /// Announce that we have completed a piece.
+    Have(usize),
+    /// Close the connection.
+    Close,
+}
+
+/// Events that a peer session reports to higher layers.
+#[derive(Debug)]
+pub enum PeerEvent {
+    /// The peer choked us. All our outstanding requests have been dropped by the peer.
+    Choked,
+    /// The peer unchoked us.
+    UnChoked,
+    /// The peer is interested in our pieces.
+    Interested,
+    /// The peer is not interested in our pieces.
+    NotInterested,
+    /// The peer completed a piece.
+    Have(usize),
+    /// The peer sent a block we requested.
+    Block(Block),
+    /// The peer requested a block from us.
+    Request(BlockRequest),
+    /// The peer cancelled a block it requested from us.
+    Cancel(BlockRequest),
+    /// We sent the peer a block it requested.
+    BlockSent(BlockRequest),
+    /// The connection was closed, with the error that caused it if any.
+    Disconnected(Option<anyhow::Error>),
+}
+
+/// An event reported by the session of the peer at `peer`.
+#[derive(Debug)]
+pub struct SessionEvent {
+    pub peer: SocketAddrV4,
+    pub event: PeerEvent,
+}
+
+/// A handle to a running peer session.
+///
+/// Dropping every handle of a session closes its connection.
+#[derive(Debug, Clone)]
+pub struct PeerHandle {
+    address: SocketAddrV4,
+    commands: mpsc::UnboundedSender<PeerCommand>,
+    state: watch::Receiver<PeerState>,
+}
+
+impl PeerHandle {
+    /// Returns the socket address of the peer.
+    pub fn address(&self) -> SocketAddrV4 {
+        self.address
+    }
+
+    /// Returns the current choke and interest flags of the connection.
+    pub fn state(&self) -> PeerState {
+        *self.state.borrow()
+    }
+
+    /// Sends a command to the session.
+    ///
+    /// Returns `false` if the session has already terminated.
+    pub fn send(&self, command: PeerCommand) -> bool {
+        self.commands.send(command).is_ok()
+    }
+}
+
+/// Actor that owns the connection with a peer and speaks the wire protocol on behalf of
+/// higher layers.
+///
+/// The session tracks the choke and interest flags of both sides, keeps the bitfield of the
+/// peer up to date from `Have` messages, and keeps the queues of outstanding requests in both
+/// directions. It sends keep-alives when the connection is idle, and drops peers that stay
+/// silent for longer than [`PEER_TIMEOUT`]. Everything else is reported as a [`PeerEvent`].
+pub struct PeerSession<S> {
+    peer: Peer<S>,
+    state: watch::Sender<PeerState>,
+    /// Blocks we requested from the peer, which have not arrived yet.
+    requested: HashSet<BlockRequest>,
+    /// Blocks we cancelled, which the peer may have sent before it got the `Cancel`, with
+    /// when they were cancelled.
+    cancelled: HashMap<BlockRequest, Instant>,
+    /// Blocks the peer requested from us, which have not been sent yet.
+    requests: VecDeque<BlockRequest>,
+    commands: mpsc::UnboundedReceiver<PeerCommand>,
+    events: mpsc::UnboundedSender<SessionEvent>,
+    last_received: Instant,
+    last_sent: Instant,
+    /// Whether the peer sent a message since the handshake. Its bitfield may still come
+    /// until then.
+    received: bool,
+}
+
+impl<S> PeerSession<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    /// Spawns a session for a connected peer on the tokio runtime.
+    ///
+    /// The events of the session are sent on `events`, and the session is driven with the
+    /// returned handle.
+    pub fn spawn(peer: Peer<S>, events: mpsc::UnboundedSender<SessionEvent>) -> PeerHandle {
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let (state_tx, state_rx) = watch::channel(PeerState::default());
+        let handle = PeerHandle {
+            address: peer.address(),
+            commands: commands_tx,
+            state: state_rx,
+        };
+
+        let now = Instant::now();
+        let session = PeerSession {
+            peer,
+            state: state_tx,
+            requested: HashSet::new(),
+            cancelled: HashMap::new(),
+            requests: VecDeque::new(),
+            commands: commands_rx,
+            events,
+            last_received: now,
+            last_sent: now,
+            received: false,
+        };
+        tokio::spawn(session.run());
+        handle
+    }
+
+    async fn run(mut self) {
+        let reason = self.event_loop().await.err();
+        self.emit(PeerEvent::Disconnected(reason));
+    }
+
+    async fn event_loop(&mut self) -> anyhow::Result<()> {
+        loop {
+            let keep_alive_at = self.last_sent + KEEP_ALIVE_INTERVAL;
+            let timeout_at = self.last_received + PEER_TIMEOUT;
+
+            tokio::select! {
+                message = self.peer.next() => match message {
+                    Some(message) => self.handle_message(message?)?,
+                    None => return Ok(()),
+                },
+                command = self.commands.recv() => match command {
+                    Some(PeerCommand::Close) | None => return Ok(()),
+                    Some(command) => self.handle_command(command).await?,
+                },
+                _ = sleep_until(keep_alive_at) => {
+                    let keep_alive = Message::without_payload(MessageTag::KeepAlive)?;
+                    self.send(keep_alive).await?;
+                }
+                _ = sleep_until(timeout_at) => {
+                    anyhow::bail!("Peer has not sent anything for {} seconds.", PEER_TIMEOUT.as_secs());
+                }
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) -> anyhow::Result<()> {
+        self.last_received = Instant::now();
+        let first = !std::mem::replace(&mut self.received, true);
+
+        match message.tag() {
+            MessageTag::Choke => {
+                // A choking peer discards all the requests it has not answered yet.
+                self.requested.clear();
+                self.cancelled.clear();
+                self.state.send_modify(|state| state.peer_choking = true);
+                self.emit(PeerEvent::Choked);
+            }
+            MessageTag::UnChoke => {
+                self.state.send_modify(|state| state.peer_choking = false);
+                self.emit(PeerEvent::UnChoked);
+            }
+            MessageTag::Interested => {
+                self.state.send_modify(|state| state.peer_interested = true);
+                self.emit(PeerEvent::Interested);
+            }
+            MessageTag::NotInterested => {
+                self.state
+                    .send_modify(|state| state.peer_interested = false);
+                self.emit(PeerEvent::NotInterested);
+            }
+            MessageTag::Have => {
+                let piece_i = parse_have(message.payload())?;
+                self.peer.bitfield_mut().set_piece(piece_i);
+                self.emit(PeerEvent::Have(piece_i));
+            }
+            // A peer that was slow to send its bitfield after the handshake. Its pieces are
+            // reported as if it had sent a `Have` for each of them.
+            MessageTag::Bitfield if first && self.peer.bitfield().into_iter().next().is_none() => {
+                for piece_i in &BitField::from_payload(message.payload()) {
+                    self.peer.bitfield_mut().set_piece(piece_i);
+                    self.emit(PeerEvent::Have(piece_i));
+                }
+            }
+            MessageTag::Bitfield => {
+                anyhow::bail!("Peer sent Bitfield message after the handshake.");
+            }
+            MessageTag::Request => {
+                let request = BlockRequest::from_payload(message.payload())?;
+                // Requests from choked peers, duplicates, requests for more than a block and
+                // requests over the queue limit are silently dropped.
+                if self.state.borrow().am_choking
+                    || request.length() > BLOCK_SIZE
+                    || self.requests.len() >= MAX_PEER_REQUESTS
+                    || self.requests.contains(&request)
+                {
+                    return Ok(());
+                }
+                self.requests.push_back(request);
+                self.emit(PeerEvent::Request(request));
+            }
+            MessageTag::Piece => {
+                let block = Block::from_payload(message.payload())?;
+                // Blocks we did not request are discarded. Blocks that crossed our `Cancel` are
+                // still reported, so that the data they wasted is accounted for.
+                let request = block.request();
+                let cancelled = self
+                    .cancelled
+                    .remove(&request)
+                    .is_some_and(|at| at.elapsed() < CANCEL_GRACE);
+                if self.requested.remove(&request) || cancelled {
+                    self.emit(PeerEvent::Block(block));
+                }
+            }
+            MessageTag::Cancel => {
+                let request = BlockRequest::from_payload(message.payload())?;
+                if let Some(position) = self.requests.iter().position(|r| *r == request) {
+                    self.requests.remove(position);
+                    self.emit(PeerEvent::Cancel(request));
+                }
+            }
+            MessageTag::KeepAlive => {}
+        }
+        Ok(())
+    }
+
+    async fn handle_command(&mut self, command: PeerCommand) -> anyhow::Result<()> {
+        let state = *self.state.borrow();
+        match command {
+            PeerCommand::Choke if !state.am_choking => {
+                self.requests.clear();
+                self.state.send_modify(|state| state.am_choking = true);
+                self.send(Message::without_payload(MessageTag::Choke)?)
+                    .await?;
+            }
+            PeerCommand::UnChoke if state.am_choking => {
+                self.state.send_modify(|state| state.am_choking = false);
+                self.send(Message::without_payload(MessageTag::UnChoke)?)
+                    .await?;
+            }
+            PeerCommand::Interested if !state.am_interested => {
+                self.state.send_modify(|state| state.am_interested = true);
+                self.send(Message::without_payload(MessageTag::Interested)?)
+                    .await?;
+            }
+            PeerCommand::NotInterested if state.am_interested => {
+                self.state.send_modify(|state| state.am_interested = false);
+                self.send(Message::without_payload(MessageTag::NotInterested)?)
+                    .await?;
+            }
+            PeerCommand::Request(request) if !self.requested.contains(&request) => {
+                self.cancelled.remove(&request);
+                self.requested.insert(request);
+                self.send(request.request_message()).await?;
+            }
+            PeerCommand::Cancel(request) if self.requested.remove(&request) => {
+                // Cancels older than the grace period are forgotten, so that the blocks of
+                // a peer that never answers them do not pile up.
+                self.cancelled
+                    .retain(|_, cancelled_at| cancelled_at.elapsed() < CANCEL_GRACE);
+                self.cancelled.insert(request, Instant::now());
+                self.send(request.cancel_message()).await?;
+            }
+            PeerCommand::Block(block) => {
+                let request = block.request();
+                if let Some(position) = self.requests.iter().position(|r| *r == request) {
+                    self.requests.remove(position);
+                    self.send(block.to_message()).await?;
+                    self.emit(PeerEvent::BlockSent(request));
+                }
+            }
+            PeerCommand::Reject(request) => {
+                self.requests.retain(|r| *r != request);
+            }
+            PeerCommand::Have(piece_i) => {
+                self.send(have_message(piece_i)).await?;
+            }
+            // Commands that would not change anything, such as repeated requests, are no-ops.
+            _ => {}
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, message: Message) -> anyhow::Result<()> {
+        self.peer.send(message).await?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn emit(&self, event: PeerEvent) {
+        // The receiver going away means nobody is interested in the peer anymore, and the
+        // session is closed once its handles are dropped.
+        let _ = self.events.send(SessionEvent {
+            peer: self.peer.address(),
+            event,
+        });
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::atomic::{AtomicU16, Ordering};
+
+    use futures_util::{SinkExt, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio_util::codec::Framed;
+
+    use super::*;
+    use crate::net::bitfield::BitField;
+    use crate::net::message::MessageFramer;
+
+    /// Connects a `Peer` to a remote end over an in-memory stream. The remote end completes the
+    /// handshake and sends `bitfield`.
+    pub(crate) async fn connect(
+        bitfield: &[u8],
+    ) -> (Peer<DuplexStream>, Framed<DuplexStream, MessageFramer>) {
+        static NEXT_PORT: AtomicU16 = AtomicU16::new(6881);
+
+        let (local, mut remote) = tokio::io::duplex(1 << 20);
+        let port = NEXT_PORT.fetch_add(1, Ordering::Relaxed);
+        // Every peer gets an address of its own, since bans apply to a whole IP.
+        let ip = Ipv4Addr::from(u32::from(Ipv4Addr::LOCALHOST) + u32::from(port - 6881));
+        let address = SocketAddrV4::new(ip, port);
+        let payload = bitfield.to_vec();
+        let remote = tokio::spawn(async move {
+            let mut handshake = [0u8; 68];
+            remote.read_exact(&mut handshake).await.unwrap();
+            remote.write_all(&handshake).await.unwrap();
+            let mut framed = Framed::new(remote, MessageFramer);
+            framed
+                .send(Message::new(MessageTag::Bitfield, payload).unwrap())
+                .await
+                .unwrap();
+            framed
+        });
+        let peer = Peer::handshake(address, local, [1; 20], [2; 20], &BitField::new(0))
+            .await
+            .unwrap();
+        (peer, remote.await.unwrap())
+    }
+
+    /// Seeds `data` to the local end: unchokes it as soon as it is interested, and answers its
+    /// requests. The blocks of the pieces in `corrupt` are sent with garbage.
+    pub(crate) fn seed(
+        mut remote: Framed<DuplexStream, MessageFramer>,
+        data: Vec<u8>,
+        piece_length: usize,
+        corrupt: &[usize],
+    ) -> tokio::task::JoinHandle<()> {
+        let corrupt = corrupt.to_vec();
+        tokio::spawn(async move {
+            while let Some(Ok(message)) = remote.next().await {
+                match message.tag() {
+                    MessageTag::Interested => {
+                        let unchoke = Message::without_payload(MessageTag::UnChoke).unwrap();
+                        remote.send(unchoke).await.unwrap();
+                    }
+                    MessageTag::Request => {
+                        let request = BlockRequest::from_payload(message.payload()).unwrap();
+                        let begin = request.piece_i() * piece_length + request.begin();
+                        let mut block = data[begin..begin + request.length()].to_vec();
+                        if corrupt.contains(&request.piece_i()) {
+                            block.iter_mut().for_each(|byte| *byte = !*byte);
+                        }
+                        let block = Block::new(request.piece_i(), request.begin(), block);
+                        if remote.send(block.to_message()).await.is_err() {
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        })
+    }
+
+    async fn next_event(events: &mut mpsc::UnboundedReceiver<SessionEvent>) -> PeerEvent {
+        events.recv().await.unwrap().event
+    }
+
+    #[tokio::test]
+    async fn test_tracks_state_and_have() {
+        let (peer, mut remote) = connect(&[0b1000_0000]).await;
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let handle = PeerSession::spawn(peer, events_tx);
+
+        remote
+            .send(Message::without_payload(MessageTag::UnChoke).unwrap())
+            .await
+            .unwrap();
+        remote.send(have_message(3)).await.unwrap();
+        assert!(matches!(next_event(&mut events).await, PeerEvent::UnChoked));
+        assert!(matches!(next_event(&mut events).await, PeerEvent::Have(3)));
+        assert!(!handle.state().peer_choking());
+
+        handle.send(PeerCommand::Interested);
+        let message = remote.next().await.unwrap().unwrap();
+        assert_eq!(message.tag(), &MessageTag::Interested);
+        assert!(handle.state().am_interested());
+    }
+
+    #[tokio::test]
+    async fn test_discards_unrequested_blocks() {
+        let (peer, mut remote) = connect(&[0b1000_0000]).await;
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let handle = PeerSession::spawn(peer, events_tx);
+
+        let request = BlockRequest::new(0, 0, 4);
+        handle.send(PeerCommand::Request(request));
+        let message = remote.next().await.unwrap().unwrap();
+        assert_eq!(
+            BlockRequest::from_payload(message.payload()).unwrap(),
+            request
+        );
+
+        remote
+            .send(Block::new(0, 4, vec![0; 4]).to_message())
+            .await
+            .unwrap();
+        remote
+            .send(Block::new(0, 0, vec![1; 4]).to_message())
+            .await
+            .unwrap();
+        // The duplicate of an answered request is discarded as well.
+        remote
+            .send(Block::new(0, 0, vec![1; 4]).to_message())
+            .await
+            .unwrap();
+        drop(remote);
+
+        match next_event(&mut events).await {
+            PeerEvent::Block(block) => assert_eq!(block.request(), request),
+            event => panic!("Unexpected event {event:?}"),
+        }
+        assert!(matches!(
+            next_event(&mut events).await,
+            PeerEvent::Disconnected(None)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_accepts_cancelled_blocks_for_a_while() {
+        let (peer, mut remote) = connect(&[0b1000_0000]).await;
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let handle = PeerSession::spawn(peer, events_tx);
+
+        let (first, second) = (BlockRequest::new(0, 0, 4), BlockRequest::new(0, 4, 4));
+        for request in [first, second] {
+            handle.send(PeerCommand::Request(request));
+            handle.send(PeerCommand::Cancel(request));
+        }
+        for tag in [
+            MessageTag::Request,
+            MessageTag::Cancel,
+            MessageTag::Request,
+            MessageTag::Cancel,
+        ] {
+            assert_eq!(remote.next().await.unwrap().unwrap().tag(), &tag);
+        }
+
+        // The first block crossed the `Cancel`, and the second one comes too late.
+        remote
+            .send(Block::new(0, 0, vec![1; 4]).to_message())
+            .await
+            .unwrap();
+        match next_event(&mut events).await {
+            PeerEvent::Block(block) => assert_eq!(block.request(), first),
+            event => panic!("Unexpected event {event:?}"),
+        }
+        tokio::time::advance(CANCEL_GRACE).await;
+        remote
+            .send(Block::new(0, 4, vec![2; 4]).to_message())
+            .await
+            .unwrap();
+        drop(remote);
+        assert!(matches!(
+            next_event(&mut events).await,
+            PeerEvent::Disconnected(None)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_accepts_late_bitfield() {
+        // The remote end completes the handshake, and waits before sending its bitfield.
+        let (local, mut remote) = tokio::io::duplex(1 << 10);
+        let remote = tokio::spawn(async move {
+            let mut handshake = [0u8; 68];
+            remote.read_exact(&mut handshake).await.unwrap();
+            remote.write_all(&handshake).await.unwrap();
+            Framed::new(remote, MessageFramer)
+        });
+        let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 6881);
+        let peer = Peer::handshake(address, local, [1; 20], [2; 20], &BitField::new(0))
+            .await
+            .unwrap();
+        assert_eq!(peer.bitfield().into_iter().next(), None);
+        let mut remote = remote.await.unwrap();
+
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let _handle = PeerSession::spawn(peer, events_tx);
+        let bitfield = Message::new(MessageTag::Bitfield, vec![0b0101_0000]).unwrap();
+        remote.send(bitfield.clone()).await.unwrap();
+        assert!(matches!(next_event(&mut events).await, PeerEvent::Have(1)));
+        assert!(matches!(next_event(&mut events).await, PeerEvent::Have(3)));
+
+        // A second bitfield is a protocol error.
+        remote.send(bitfield).await.unwrap();
+        assert!(matches!(
+            next_event(&mut events).await,
+            PeerEvent::Disconnected(Some(_))
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_keep_alive_and_timeout() {
+        let (peer, mut remote) = connect(&[]).await;
+        let (events_tx, mut events) = mpsc::unbounded_channel();
+        let _handle = PeerSession::spawn(peer, events_tx);
+
+        let message = remote.next().await.unwrap().unwrap();
+        assert_eq!(message.tag(), &MessageTag::KeepAlive);
+        match next_event(&mut events).await {
+            PeerEvent::Disconnected(Some(_)) => {}
+            event => panic!("Unexpected event {event:?}"),
+        }
+    }
+}
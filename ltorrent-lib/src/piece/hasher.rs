@@ -0,0 +1,188 @@
+use std::future::Future;
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread;
+
+use futures_util::future::BoxFuture;
+use futures_util::stream::{FuturesOrdered, StreamExt};
+use futures_util::FutureExt;
+use sha1::{Digest, Sha1};
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The index of a piece, its length, and its hash if it could be read.
+type HashedPiece = (usize, usize, Option<[u8; 20]>);
+
+/// A pool of threads dedicated to hashing pieces.
+///
+/// Hashing a piece takes milliseconds of CPU time, which would stall the network I/O of every
+/// other task if it ran on the workers of the async runtime. The pool runs it on threads of
+/// its own instead, one per core by default, so that many pieces are hashed in parallel.
+///
+/// The pool is cheap to clone, and its threads exit once every clone has been dropped, except
+/// for those of [`HashPool::shared`], which live as long as the process.
+#[derive(Debug, Clone)]
+pub struct HashPool {
+    jobs: mpsc::Sender<Job>,
+    n_threads: usize,
+}
+
+impl HashPool {
+    /// Starts a pool of `n_threads` hashing threads.
+    pub fn new(n_threads: usize) -> Self {
+        let n_threads = n_threads.max(1);
+        let (jobs, queue) = mpsc::channel::<Job>();
+        let queue = Arc::new(Mutex::new(queue));
+        for i in 0..n_threads {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new()
+                .name(format!("ltorrent-hash-{i}"))
+                .spawn(move || loop {
+                    // The lock is only held while waiting for the next job.
+                    let job = queue
+                        .lock()
+                        .expect("Hashing queue is never poisoned.")
+                        .recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => break,
+                    }
+                })
+                .expect("Failed to spawn hashing thread.");
+        }
+        Self { jobs, n_threads }
+    }
+
+    /// Returns the pool shared by every download and check of the process, which is started
+    /// with one thread per core the first time it is needed.
+    pub fn shared() -> Self {
+        static SHARED: OnceLock<HashPool> = OnceLock::new();
+        SHARED.get_or_init(HashPool::default).clone()
+    }
+
+    /// Returns the number of threads of the pool.
+    pub fn n_threads(&self) -> usize {
+        self.n_threads
+    }
+
+    /// Runs `f` on a thread of the pool, and returns its result once it is done.
+    pub fn run<F, R>(&self, f: F) -> impl Future<Output = R> + Send + 'static
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (result_tx, result_rx) = oneshot::channel();
+        let _ = self.jobs.send(Box::new(move || {
+            let _ = result_tx.send(f());
+        }));
+        async move { result_rx.await.expect("Hashing thread panicked.") }
+    }
+
+    /// Computes the SHA1 hash of `data` on a thread of the pool.
+    pub fn hash(&self, data: Vec<u8>) -> impl Future<Output = [u8; 20]> + Send + 'static {
+        self.run(move || Sha1::digest(&data).into())
+    }
+}
+
+impl Default for HashPool {
+    /// Starts a pool with one thread per core.
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, |n| n.get()))
+    }
+}
+
+/// Pieces being hashed on a [`HashPool`], whose hashes come out in the order the pieces went
+/// in.
+///
+/// The pieces are meant to be read one after the other, and pushed until the queue is full,
+/// so that enough of them are read ahead to keep every thread of the pool busy.
+pub struct PieceHashes {
+    pool: HashPool,
+    hashing: FuturesOrdered<BoxFuture<'static, HashedPiece>>,
+}
+
+impl PieceHashes {
+    /// Creates an empty queue of pieces to hash on `pool`.
+    pub fn new(pool: HashPool) -> Self {
+        Self {
+            pool,
+            hashing: FuturesOrdered::new(),
+        }
+    }
+
+    /// Returns whether enough pieces are being hashed to keep every thread of the pool busy.
+    pub fn is_full(&self) -> bool {
+        self.hashing.len() >= 2 * self.pool.n_threads()
+    }
+
+    /// Starts hashing the data of a piece of `length` bytes. A piece that could not be read
+    /// has no data, and comes out without a hash.
+    pub fn push(&mut self, piece_i: usize, length: usize, data: Option<Vec<u8>>) {
+        let hash = data.map(|data| self.pool.hash(data));
+        self.hashing.push_back(
+            async move {
+                let hash = match hash {
+                    Some(hash) => Some(hash.await),
+                    None => None,
+                };
+                (piece_i, length, hash)
+            }
+            .boxed(),
+        );
+    }
+
+    /// Waits for the hash of the oldest piece, and returns its index, its length and its
+    /// hash, or `None` if no piece is being hashed.
+    pub async fn next(&mut self) -> Option<HashedPiece> {
+        self.hashing.next().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::future::join_all;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_hashes_in_parallel() {
+        let pool = HashPool::new(4);
+        let data = (0..16u8).map(|i| vec![i; 1 << 16]).collect::<Vec<_>>();
+        let hashes = join_all(data.iter().map(|data| pool.hash(data.clone()))).await;
+        for (data, hash) in data.iter().zip(hashes) {
+            assert_eq!(hash, <[u8; 20]>::from(Sha1::digest(data)));
+        }
+
+        let thread = pool
+            .run(|| thread::current().name().map(str::to_string))
+            .await;
+        assert!(thread.unwrap().starts_with("ltorrent-hash-"));
+    }
+
+    #[tokio::test]
+    async fn test_yields_piece_hashes_in_order() {
+        let mut hashes = PieceHashes::new(HashPool::new(2));
+        assert!(!hashes.is_full());
+        // The large first piece takes the longest to hash, but still comes out first.
+        hashes.push(0, 1 << 20, Some(vec![0; 1 << 20]));
+        hashes.push(1, 0, None);
+        hashes.push(2, 1, Some(vec![2]));
+        hashes.push(3, 1, Some(vec![3]));
+        assert!(hashes.is_full());
+
+        let mut pieces = Vec::new();
+        while let Some(piece) = hashes.next().await {
+            pieces.push(piece);
+        }
+        let hash = |data: &[u8]| Some(<[u8; 20]>::from(Sha1::digest(data)));
+        assert_eq!(
+            pieces,
+            [
+                (0, 1 << 20, hash(&vec![0; 1 << 20])),
+                (1, 0, None),
+                (2, 1, hash(&[2])),
+                (3, 1, hash(&[3])),
+            ]
+        );
+    }
+}
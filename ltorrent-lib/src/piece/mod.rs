@@ -0,0 +1,84 @@
+use std::cmp::{Ord, Ordering, PartialOrd};
+use std::collections::BTreeSet;
+use std::net::SocketAddrV4;
+
+pub mod hasher;
+pub mod picker;
+pub mod set;
+
+/// The priority of pieces that nothing asked to download sooner or later.
+pub const DEFAULT_PRIORITY: u8 = 4;
+
+/// A piece of the torrent, together with the peers that have it.
+///
+/// Pieces are ordered by the number of peers that have them, so that the rarest piece comes
+/// first. Pieces that as many peers have are ordered by a random `seed`. If the order were
+/// deterministic, everyone would choose to download the same piece, causing congestion to
+/// the network needlessly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Piece {
+    peers: BTreeSet<SocketAddrV4>,
+    piece_i: usize,
+    seed: u64,
+    priority: u8,
+}
+
+impl Ord for Piece {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.peers.len().cmp(&other.peers.len())
+            .then(self.seed.cmp(&other.seed))
+            .then(self.peers.cmp(&other.peers))
+            .then(self.piece_i.cmp(&other.piece_i))
+    }
+}
+
+impl PartialOrd for Piece {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+
+impl Piece {
+    /// Creates the piece `piece_i` of the torrent, which no peer has yet, with a random seed.
+    pub fn new(piece_i: usize) -> Self {
+        Self {
+            peers: BTreeSet::new(),
+            piece_i,
+            seed: rand::random(),
+            priority: DEFAULT_PRIORITY,
+        }
+    }
+
+    /// Returns the index of the piece.
+    pub fn piece_i(&self) -> usize {
+        self.piece_i
+    }
+
+    /// Returns the number of peers that have the piece.
+    pub fn availability(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Returns the priority of the piece. Pieces with priority 0 are never downloaded.
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub(crate) fn set_priority(&mut self, priority: u8) {
+        self.priority = priority;
+    }
+
+    /// Returns whether a peer has the piece.
+    pub fn has_peer(&self, peer: &SocketAddrV4) -> bool {
+        self.peers.contains(peer)
+    }
+
+    pub(crate) fn add_peer(&mut self, peer: SocketAddrV4) -> bool {
+        self.peers.insert(peer)
+    }
+
+    pub(crate) fn remove_peer(&mut self, peer: &SocketAddrV4) -> bool {
+        self.peers.remove(peer)
+    }
+}
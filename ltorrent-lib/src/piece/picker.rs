@@ -0,0 +1,210 @@
+use std::fmt::Debug;
+use std::str::FromStr;
+
+use rand::Rng;
+
+use super::Piece;
+
+/// A strategy that chooses which piece to download next.
+///
+/// The strategy only decides the order of the pieces. It is handed the candidates, which are
+/// the pieces a peer has and that we still want, and returns the index of the one to download.
+pub trait PiecePicker: Debug + Send {
+    /// Chooses a piece among `candidates`, which is never empty. `n_have` is the number of
+    /// pieces we have downloaded so far.
+    fn choose(&mut self, candidates: &[&Piece], n_have: usize) -> Option<usize>;
+}
+
+/// Picks the piece that the fewest peers have, breaking ties at random.
+///
+/// Downloading the rarest pieces first keeps them from disappearing from the swarm, and makes
+/// us useful to other peers sooner, since we can seed pieces that few peers have.
+#[derive(Debug, Default)]
+pub struct RarestFirst;
+
+impl PiecePicker for RarestFirst {
+    fn choose(&mut self, candidates: &[&Piece], _n_have: usize) -> Option<usize> {
+        candidates.iter().min().map(|piece| piece.piece_i())
+    }
+}
+
+/// Picks the pieces in order, which lets media be played while it downloads.
+#[derive(Debug, Default)]
+pub struct Sequential;
+
+impl PiecePicker for Sequential {
+    fn choose(&mut self, candidates: &[&Piece], _n_have: usize) -> Option<usize> {
+        candidates.iter().map(|piece| piece.piece_i()).min()
+    }
+}
+
+/// Picks random pieces until `n` pieces have been downloaded, and the rarest pieces after.
+///
+/// Rare pieces are slow to download, since few peers have them. Picking random pieces at the
+/// start gets us complete pieces sooner, so that we have something to upload and get
+/// reciprocated for.
+#[derive(Debug)]
+pub struct RandomFirst {
+    n: usize,
+}
+
+impl RandomFirst {
+    /// Creates a strategy that picks random pieces for the first `n` pieces.
+    pub fn new(n: usize) -> Self {
+        Self { n }
+    }
+}
+
+impl PiecePicker for RandomFirst {
+    fn choose(&mut self, candidates: &[&Piece], n_have: usize) -> Option<usize> {
+        if n_have >= self.n {
+            return RarestFirst.choose(candidates, n_have);
+        }
+        let i = rand::thread_rng().gen_range(0..candidates.len());
+        Some(candidates[i].piece_i())
+    }
+}
+
+/// Picks pieces at random, with a probability proportional to their priority.
+///
+/// High priority pieces are downloaded sooner on average, while low priority pieces still get
+/// their turn and are never starved.
+#[derive(Debug, Default)]
+pub struct PriorityWeighted;
+
+impl PiecePicker for PriorityWeighted {
+    fn choose(&mut self, candidates: &[&Piece], _n_have: usize) -> Option<usize> {
+        let total = candidates
+            .iter()
+            .map(|piece| piece.priority() as usize)
+            .sum::<usize>();
+        if total == 0 {
+            return None;
+        }
+        let mut point = rand::thread_rng().gen_range(0..total);
+        for piece in candidates {
+            let weight = piece.priority() as usize;
+            if point < weight {
+                return Some(piece.piece_i());
+            }
+            point -= weight;
+        }
+        None
+    }
+}
+
+/// The built-in piece picking strategies.
+///
+/// Strategies are parsed from their names: `rarest-first`, `sequential`, `random-first:<N>`
+/// and `priority`.
+///
+/// # Examples
+///
+/// ```
+/// use ltorrent::piece::picker::Strategy;
+///
+/// let strategy: Strategy = "random-first:4".parse().unwrap();
+/// assert_eq!(strategy, Strategy::RandomFirst(4));
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    /// See [`RarestFirst`].
+    #[default]
+    RarestFirst,
+    /// See [`Sequential`].
+    Sequential,
+    /// See [`RandomFirst`].
+    RandomFirst(usize),
+    /// See [`PriorityWeighted`].
+    PriorityWeighted,
+}
+
+impl Strategy {
+    /// Returns a new picker implementing the strategy.
+    pub fn picker(&self) -> Box<dyn PiecePicker> {
+        match self {
+            Self::RarestFirst => Box::new(RarestFirst),
+            Self::Sequential => Box::new(Sequential),
+            Self::RandomFirst(n) => Box::new(RandomFirst::new(*n)),
+            Self::PriorityWeighted => Box::new(PriorityWeighted),
+        }
+    }
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "rarest-first" => Ok(Self::RarestFirst),
+            None if s == "sequential" => Ok(Self::Sequential),
+            None if s == "priority" => Ok(Self::PriorityWeighted),
+            Some(("random-first", n)) => Ok(Self::RandomFirst(
+                n.parse().map_err(|_| anyhow::anyhow!("Invalid number of pieces: {n}."))?,
+            )),
+            _ => Err(anyhow::anyhow!(
+                "Unknown strategy {s}. Expected rarest-first, sequential, random-first:<N> or priority."
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    fn pieces(n: usize) -> Vec<Piece> {
+        (0..n).map(Piece::new).collect()
+    }
+
+    #[test]
+    fn test_sequential() {
+        let pieces = pieces(5);
+        let candidates = pieces.iter().rev().collect::<Vec<_>>();
+        assert_eq!(Sequential.choose(&candidates, 0), Some(0));
+    }
+
+    #[test]
+    fn test_random_first() {
+        let mut pieces = pieces(50);
+        pieces[7].add_peer("127.0.0.1:1".parse().unwrap());
+        for piece in pieces.iter_mut().filter(|p| p.piece_i() != 7) {
+            piece.add_peer("127.0.0.1:1".parse().unwrap());
+            piece.add_peer("127.0.0.1:2".parse().unwrap());
+        }
+        let candidates = pieces.iter().collect::<Vec<_>>();
+        let mut picker = RandomFirst::new(2);
+        let picks = (0..20)
+            .map(|_| picker.choose(&candidates, 1).unwrap())
+            .collect::<HashSet<_>>();
+        assert!(picks.len() > 1);
+        assert_eq!(picker.choose(&candidates, 2), Some(7));
+    }
+
+    #[test]
+    fn test_priority_weighted() {
+        let mut pieces = pieces(3);
+        pieces[0].set_priority(0);
+        pieces[1].set_priority(0);
+        let candidates = pieces.iter().collect::<Vec<_>>();
+        for _ in 0..10 {
+            assert_eq!(PriorityWeighted.choose(&candidates, 0), Some(2));
+        }
+    }
+
+    #[test]
+    fn test_parse_strategy() {
+        assert_eq!(
+            "sequential".parse::<Strategy>().unwrap(),
+            Strategy::Sequential
+        );
+        assert_eq!(
+            "priority".parse::<Strategy>().unwrap(),
+            Strategy::PriorityWeighted
+        );
+        assert!("random-first:x".parse::<Strategy>().is_err());
+        assert!("fastest".parse::<Strategy>().is_err());
+    }
+}
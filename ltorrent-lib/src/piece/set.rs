@@ -0,0 +1,270 @@
+use std::collections::HashSet;
+use std::net::SocketAddrV4;
+
+use crate::net::bitfield::BitField;
+use crate::torrent::Torrent;
+
+use super::picker::{PiecePicker, Strategy};
+use super::Piece;
+
+/// The pieces of a torrent, and what we and the connected peers have of them.
+///
+/// The set keeps track of which peers have each piece, from their bitfields and `Have`
+/// messages, and of which pieces we have or are downloading. The next piece to download from
+/// a given peer is chosen by a [`PiecePicker`] strategy, which can be switched at any time.
+///
+/// Pieces that have been started are handed out before any new piece, whatever the strategy,
+/// so that partially downloaded pieces are finished first and can be verified and shared.
+#[derive(Debug)]
+pub struct PieceSet {
+    pieces: Vec<Piece>,
+    have: BitField,
+    n_have: usize,
+    in_progress: HashSet<usize>,
+    picker: Box<dyn PiecePicker>,
+}
+
+impl PieceSet {
+    /// Creates the set of pieces of a torrent, none of which we have yet, picked with the
+    /// given strategy.
+    pub fn new(torrent: &Torrent, strategy: Strategy) -> Self {
+        Self {
+            pieces: (0..torrent.n_pieces()).map(Piece::new).collect(),
+            have: BitField::new(torrent.n_pieces()),
+            n_have: 0,
+            in_progress: HashSet::new(),
+            picker: strategy.picker(),
+        }
+    }
+
+    /// Switches the strategy used to pick the next pieces.
+    pub fn set_picker(&mut self, picker: Box<dyn PiecePicker>) {
+        self.picker = picker;
+    }
+
+    /// Sets the priority of a piece, as used by priority-aware strategies.
+    pub fn set_priority(&mut self, piece_i: usize, priority: u8) {
+        if let Some(piece) = self.pieces.get_mut(piece_i) {
+            piece.set_priority(priority);
+        }
+    }
+
+    /// Returns the priority of a piece, or 0 if it is out of bounds.
+    pub fn priority(&self, piece_i: usize) -> u8 {
+        self.pieces.get(piece_i).map_or(0, Piece::priority)
+    }
+
+    /// Returns the number of pieces in the torrent.
+    pub fn n_pieces(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// Returns the pieces we have.
+    pub fn have(&self) -> &BitField {
+        &self.have
+    }
+
+    /// Returns the number of pieces we have.
+    pub fn n_have(&self) -> usize {
+        self.n_have
+    }
+
+    /// Returns the number of pieces we want, whose priority is not 0.
+    pub fn n_wanted(&self) -> usize {
+        self.pieces
+            .iter()
+            .filter(|piece| piece.priority() > 0)
+            .count()
+    }
+
+    /// Returns whether we have every piece we want.
+    pub fn is_complete(&self) -> bool {
+        self.pieces
+            .iter()
+            .all(|piece| piece.priority() == 0 || self.has_piece(piece.piece_i()))
+    }
+
+    /// Returns whether we have a piece.
+    pub fn has_piece(&self, piece_i: usize) -> bool {
+        self.have.contains_piece(piece_i)
+    }
+
+    /// Returns the number of pieces we still want that are neither downloaded nor in progress.
+    pub fn n_unstarted(&self) -> usize {
+        self.pieces
+            .iter()
+            .filter(|piece| piece.priority() > 0)
+            .filter(|piece| {
+                !self.has_piece(piece.piece_i()) && !self.is_in_progress(piece.piece_i())
+            })
+            .count()
+    }
+
+    /// Returns whether a piece is being downloaded.
+    pub fn is_in_progress(&self, piece_i: usize) -> bool {
+        self.in_progress.contains(&piece_i)
+    }
+
+    /// Returns the number of connected peers that have a piece.
+    pub fn availability(&self, piece_i: usize) -> usize {
+        self.pieces.get(piece_i).map_or(0, Piece::availability)
+    }
+
+    /// Returns whether a peer has a piece we want and do not have yet.
+    pub fn is_interesting(&self, peer: &SocketAddrV4) -> bool {
+        self.pieces.iter().any(|piece| {
+            piece.priority() > 0 && piece.has_peer(peer) && !self.has_piece(piece.piece_i())
+        })
+    }
+
+    /// Accounts for the pieces of a newly connected peer.
+    pub fn add_peer(&mut self, peer: SocketAddrV4, bitfield: &BitField) {
+        for piece_i in bitfield {
+            self.peer_has(peer, piece_i);
+        }
+    }
+
+    /// Accounts for a `Have` message of a peer.
+    pub fn peer_has(&mut self, peer: SocketAddrV4, piece_i: usize) {
+        if let Some(piece) = self.pieces.get_mut(piece_i) {
+            piece.add_peer(peer);
+        }
+    }
+
+    /// Forgets the pieces of a disconnected peer.
+    pub fn remove_peer(&mut self, peer: &SocketAddrV4) {
+        for piece in &mut self.pieces {
+            piece.remove_peer(peer);
+        }
+    }
+
+    /// Picks the next piece to download from a peer, and marks it as in progress.
+    ///
+    /// Only pieces the peer has, that we do not have, and for which `wanted` returns `true`
+    /// are considered. The strategy chooses among the pieces in progress if there are any, and
+    /// among new pieces otherwise.
+    pub fn pick(&mut self, peer: &SocketAddrV4, wanted: impl Fn(usize) -> bool) -> Option<usize> {
+        let (started, new): (Vec<&Piece>, Vec<&Piece>) = self
+            .pieces
+            .iter()
+            .filter(|piece| piece.has_peer(peer) && !self.has_piece(piece.piece_i()))
+            .filter(|piece| piece.priority() > 0 && wanted(piece.piece_i()))
+            .partition(|piece| self.in_progress.contains(&piece.piece_i()));
+        let candidates = if started.is_empty() { new } else { started };
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let piece_i = self.picker.choose(&candidates, self.n_have)?;
+        self.in_progress.insert(piece_i);
+        Some(piece_i)
+    }
+
+    /// Marks a piece as in progress without picking it, for instance because some of its
+    /// blocks were restored from resume data.
+    pub fn start(&mut self, piece_i: usize) {
+        if piece_i < self.pieces.len() && !self.has_piece(piece_i) {
+            self.in_progress.insert(piece_i);
+        }
+    }
+
+    /// Marks a piece as downloaded and verified.
+    pub fn complete(&mut self, piece_i: usize) {
+        self.in_progress.remove(&piece_i);
+        if piece_i < self.pieces.len() && !self.has_piece(piece_i) {
+            self.have.set_piece(piece_i);
+            self.n_have += 1;
+        }
+    }
+
+    /// Returns a piece that is no longer being downloaded, for instance because it failed its
+    /// hash check, to the pieces to pick.
+    pub fn abort(&mut self, piece_i: usize) {
+        self.in_progress.remove(&piece_i);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddrV4 {
+        SocketAddrV4::new(Ipv4Addr::LOCALHOST, port)
+    }
+
+    fn picker() -> PieceSet {
+        let torrent = Torrent::for_data("test", &[0; 40], 10);
+        let mut picker = PieceSet::new(&torrent, Strategy::RarestFirst);
+        picker.add_peer(peer(1), &BitField::from_payload(&[0b1111_0000]));
+        picker.add_peer(peer(2), &BitField::from_payload(&[0b1101_0000]));
+        picker.add_peer(peer(3), &BitField::from_payload(&[0b1000_0000]));
+        picker
+    }
+
+    #[test]
+    fn test_picks_rarest_piece_of_peer() {
+        let mut picker = picker();
+        assert_eq!(picker.availability(0), 3);
+        assert_eq!(picker.pick(&peer(1), |_| true), Some(2));
+        assert_eq!(picker.pick(&peer(3), |_| true), Some(0));
+        // Piece 0 is in progress, but it is not wanted.
+        assert!(matches!(
+            picker.pick(&peer(2), |i| i != 0),
+            Some(1) | Some(3)
+        ));
+
+        picker.peer_has(peer(3), 1);
+        picker.remove_peer(&peer(1));
+        assert_eq!(picker.availability(1), 2);
+        assert_eq!(picker.availability(2), 0);
+    }
+
+    #[test]
+    fn test_finishes_started_pieces_first() {
+        let mut picker = picker();
+        assert_eq!(picker.pick(&peer(3), |_| true), Some(0));
+        assert_eq!(picker.pick(&peer(1), |_| true), Some(0));
+        assert_eq!(picker.n_unstarted(), 3);
+
+        picker.complete(0);
+        assert!(picker.has_piece(0));
+        assert_eq!(picker.pick(&peer(3), |_| true), None);
+        assert!(!picker.is_interesting(&peer(3)));
+
+        picker.abort(2);
+        assert_eq!(picker.pick(&peer(1), |_| true), Some(2));
+    }
+
+    #[test]
+    fn test_breaks_ties_randomly() {
+        let torrent = Torrent::for_data("test", &[0; 1000], 10);
+        let bitfield = BitField::from_payload(&[0xff; 13]);
+        let first_picks = (0..10)
+            .map(|_| {
+                let mut picker = PieceSet::new(&torrent, Strategy::RarestFirst);
+                picker.add_peer(peer(1), &bitfield);
+                picker.pick(&peer(1), |_| true).unwrap()
+            })
+            .collect::<HashSet<_>>();
+        assert!(first_picks.len() > 1);
+    }
+
+    #[test]
+    fn test_switches_strategy() {
+        let torrent = Torrent::for_data("test", &[0; 100], 10);
+        let mut pieces = PieceSet::new(&torrent, Strategy::Sequential);
+        pieces.add_peer(peer(1), &BitField::from_payload(&[0xff, 0xff]));
+        pieces.add_peer(
+            peer(2),
+            &BitField::from_payload(&[0b1111_1110, 0b1100_0000]),
+        );
+        assert_eq!(pieces.pick(&peer(1), |_| true), Some(0));
+        pieces.complete(0);
+
+        // Piece 7 is the only piece that one peer has.
+        pieces.set_picker(Strategy::RarestFirst.picker());
+        assert_eq!(pieces.pick(&peer(1), |_| true), Some(7));
+    }
+}
@@ -0,0 +1,323 @@
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+
+use crate::download::priority::FilePriority;
+use crate::torrent::Torrent;
+
+use super::{create_files, stat_file, Allocation, FileLayout, FileSlice, FileStat, Storage};
+
+/// Stores the content of a torrent in its files, under a download directory.
+///
+/// Blocks are written to and read from the files they span, as mapped by the [`FileLayout`]
+/// of the torrent.
+///
+/// Skipped files are not created. The pieces at the boundary of a skipped file are still
+/// downloaded for the wanted files they overlap, and the bytes that belong to the skipped
+/// file are kept in a part-file, `.<name>.parts` under the download directory, instead. The
+/// part-file is sparse, with every byte at its offset in the content, so that it stays valid
+/// whatever files are skipped.
+#[derive(Debug)]
+pub struct FileStorage {
+    layout: FileLayout,
+    root: PathBuf,
+    /// The open files, or `None` for the skipped files.
+    files: Vec<Option<File>>,
+    part_path: PathBuf,
+    part: Option<File>,
+}
+
+impl FileStorage {
+    /// Creates the directory tree and the files of a torrent under `root`, and opens them.
+    ///
+    /// Existing files are opened as they are, so that their content can be checked and
+    /// reused. The files take up space on the disk according to `allocation`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The layout of the torrent is invalid.
+    /// - The disk does not have enough free space for the content.
+    /// - A directory or file cannot be created or allocated.
+    pub async fn create(
+        torrent: &Torrent,
+        root: impl Into<PathBuf>,
+        allocation: Allocation,
+    ) -> anyhow::Result<Self> {
+        let n_files = FileLayout::new(torrent)?.files().len();
+        Self::create_with_priorities(
+            torrent,
+            root,
+            allocation,
+            &vec![FilePriority::Normal; n_files],
+        )
+        .await
+    }
+
+    /// Creates the files of a torrent under `root` like [`FileStorage::create`], except for
+    /// the files whose priority is [`FilePriority::Skip`], whose boundary bytes go to the
+    /// part-file.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The layout of the torrent is invalid, or there is not one priority per file.
+    /// - The disk does not have enough free space for the wanted files.
+    /// - A directory or file cannot be created or allocated.
+    pub async fn create_with_priorities(
+        torrent: &Torrent,
+        root: impl Into<PathBuf>,
+        allocation: Allocation,
+        priorities: &[FilePriority],
+    ) -> anyhow::Result<Self> {
+        let layout = FileLayout::new(torrent)?;
+        anyhow::ensure!(
+            priorities.len() == layout.files().len(),
+            "Expected {} file priorities, got {}.",
+            layout.files().len(),
+            priorities.len()
+        );
+        let root = root.into();
+        let skipped = priorities
+            .iter()
+            .map(|priority| *priority == FilePriority::Skip)
+            .collect::<Vec<_>>();
+        let files = create_files(&layout, &root, allocation, &skipped).await?;
+
+        let part_path = root.join(format!(".{}.parts", torrent.name()));
+        let part = if skipped.contains(&true) {
+            let part = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&part_path)
+                .await
+                .with_context(|| format!("Failed to open part-file {}.", part_path.display()))?;
+            Some(part)
+        } else {
+            None
+        };
+        Ok(Self {
+            layout,
+            root,
+            files,
+            part_path,
+            part,
+        })
+    }
+
+    /// Returns the directory the files are stored under.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the file that holds a range, and the offset of the range in it.
+    fn locate(&mut self, slice: &FileSlice) -> (&mut File, u64) {
+        match &mut self.files[slice.file_i()] {
+            Some(file) => (file, slice.offset() as u64),
+            None => {
+                let offset = self.layout.files()[slice.file_i()].offset() + slice.offset();
+                let part = self
+                    .part
+                    .as_mut()
+                    .expect("Part-file is open with skipped files.");
+                (part, offset as u64)
+            }
+        }
+    }
+}
+
+impl Storage for FileStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    /// Writes a block of a piece, splitting it across the files it spans.
+    async fn write_block(
+        &mut self,
+        piece_i: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut data = data;
+        for slice in self.layout.map_block(piece_i, begin, data.len())? {
+            let (file, offset) = self.locate(&slice);
+            let (head, rest) = data.split_at(slice.length());
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.write_all(head)
+                .await
+                .context("Failed to write block to file.")?;
+            data = rest;
+        }
+        Ok(())
+    }
+
+    /// Reads a block of a piece back from the files it spans. The parts of growing files and
+    /// of the part-file that have not been written yet read as zeros.
+    async fn read_block(
+        &mut self,
+        piece_i: usize,
+        begin: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![0; length];
+        let mut filled = 0;
+        for slice in self.layout.map_block(piece_i, begin, length)? {
+            let (file, offset) = self.locate(&slice);
+            file.seek(SeekFrom::Start(offset)).await?;
+            let end = filled + slice.length();
+            while filled < end {
+                let n = file
+                    .read(&mut data[filled..end])
+                    .await
+                    .context("Failed to read block from file.")?;
+                if n == 0 {
+                    break;
+                }
+                filled += n;
+            }
+            filled = end;
+        }
+        Ok(data)
+    }
+
+    /// Flushes the written data of every file to the disk.
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        for file in self.files.iter_mut().flatten().chain(&mut self.part) {
+            file.flush().await?;
+            file.sync_data().await.context("Failed to sync file.")?;
+        }
+        Ok(())
+    }
+
+    /// Returns the stats of the files, where the skipped files take the stat of the
+    /// part-file.
+    async fn file_stats(&self) -> anyhow::Result<Option<Vec<FileStat>>> {
+        let mut stats = Vec::with_capacity(self.files.len());
+        for (file, entry) in self.files.iter().zip(self.layout.files()) {
+            let path = match file {
+                Some(_) => self.root.join(entry.path()),
+                None => self.part_path.clone(),
+            };
+            stats.push(stat_file(&path).await?);
+        }
+        Ok(Some(stats))
+    }
+
+    async fn release(mut self) -> anyhow::Result<()> {
+        self.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_writes_blocks_across_files() {
+        let torrent = Torrent::for_files(
+            "dir",
+            &[
+                ("a", &b"hello"[..]),
+                ("sub/b", &b" multi-file"[..]),
+                ("c", &b" world"[..]),
+            ],
+            8,
+        );
+        let root = tempfile::tempdir().unwrap();
+        let mut storage = FileStorage::create(&torrent, root.path(), Allocation::Sparse)
+            .await
+            .unwrap();
+
+        // Blocks are written out of order, and cross the file boundaries.
+        storage.write_block(1, 0, b"lti-file").await.unwrap();
+        storage.write_block(0, 4, b"o mu").await.unwrap();
+        storage.write_block(0, 0, b"hell").await.unwrap();
+        storage.write_block(2, 0, b" world").await.unwrap();
+        storage.flush().await.unwrap();
+
+        let dir = root.path().join("dir");
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"hello");
+        assert_eq!(std::fs::read(dir.join("sub/b")).unwrap(), b" multi-file");
+        assert_eq!(std::fs::read(dir.join("c")).unwrap(), b" world");
+        assert_eq!(storage.read_block(0, 3, 8).await.unwrap(), b"lo multi");
+        assert!(storage.write_block(2, 2, b"world!").await.is_err());
+        storage.release().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_allocation_modes() {
+        let torrent = Torrent::for_data("content.bin", &[1; 100], 50);
+        let root = tempfile::tempdir().unwrap();
+        let path = root.path().join("content.bin");
+
+        let mut storage = FileStorage::create(&torrent, root.path(), Allocation::GrowOnWrite)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        storage.write_block(0, 10, &[1; 5]).await.unwrap();
+        storage.flush().await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 15);
+        assert_eq!(
+            storage.read_block(0, 8, 10).await.unwrap(),
+            [0, 0, 1, 1, 1, 1, 1, 0, 0, 0]
+        );
+
+        // Full allocation keeps the content that is already there.
+        let mut storage = FileStorage::create(&torrent, root.path(), Allocation::Full)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 100);
+        assert_eq!(storage.read_block(0, 10, 5).await.unwrap(), [1; 5]);
+    }
+
+    #[tokio::test]
+    async fn test_keeps_skipped_bytes_in_part_file() {
+        let torrent = Torrent::for_files(
+            "dir",
+            &[
+                ("a", &b"hello "[..]),
+                ("b", &b"skippd"[..]),
+                ("c", &b"file"[..]),
+            ],
+            4,
+        );
+        let root = tempfile::tempdir().unwrap();
+        let priorities = [FilePriority::Normal, FilePriority::Skip, FilePriority::High];
+        let mut storage = FileStorage::create_with_priorities(
+            &torrent,
+            root.path(),
+            Allocation::Sparse,
+            &priorities,
+        )
+        .await
+        .unwrap();
+        storage.write_block(1, 0, b"o sk").await.unwrap();
+        storage.write_block(0, 0, b"hell").await.unwrap();
+        storage.write_block(2, 0, b"ippd").await.unwrap();
+        storage.write_block(3, 0, b"file").await.unwrap();
+        storage.release().await.unwrap();
+
+        let dir = root.path().join("dir");
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"hello ");
+        assert!(!dir.join("b").exists());
+        assert_eq!(std::fs::read(dir.join("c")).unwrap(), b"file");
+
+        // The boundary bytes are read back from the part-file after a restart.
+        let mut storage = FileStorage::create_with_priorities(
+            &torrent,
+            root.path(),
+            Allocation::Sparse,
+            &priorities,
+        )
+        .await
+        .unwrap();
+        assert!(root.path().join(".dir.parts").exists());
+        assert_eq!(storage.read_block(1, 0, 4).await.unwrap(), b"o sk");
+        assert_eq!(storage.file_stats().await.unwrap().unwrap().len(), 3);
+    }
+}
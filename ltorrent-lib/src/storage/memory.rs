@@ -0,0 +1,105 @@
+use crate::torrent::Torrent;
+
+use super::{FileLayout, Storage};
+
+/// Keeps the content of a torrent in memory.
+///
+/// Useful for tests and small payloads that are consumed right away, without going through
+/// the filesystem. The content is laid out as on the wire, with the files concatenated.
+/// There are no files to leave out, so the bytes of skipped files read as zeros until they
+/// are written.
+#[derive(Debug, Clone)]
+pub struct MemoryStorage {
+    layout: FileLayout,
+    data: Vec<u8>,
+}
+
+impl MemoryStorage {
+    /// Creates an empty storage for the content of a torrent.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the layout of the torrent is invalid.
+    pub fn new(torrent: &Torrent) -> anyhow::Result<Self> {
+        let layout = FileLayout::new(torrent)?;
+        let data = vec![0; layout.length()];
+        Ok(Self { layout, data })
+    }
+
+    /// Returns the content of the torrent.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the content of a file of the torrent.
+    pub fn file_data(&self, file_i: usize) -> Option<&[u8]> {
+        let file = self.layout.files().get(file_i)?;
+        Some(&self.data[file.offset()..file.offset() + file.length()])
+    }
+
+    /// Consumes the storage, returning the content of the torrent.
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    fn range(&self, piece_i: usize, begin: usize, length: usize) -> anyhow::Result<usize> {
+        // Mapping the block checks that it fits in the content.
+        self.layout.map_block(piece_i, begin, length)?;
+        Ok(piece_i * self.layout.piece_length + begin)
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    async fn write_block(
+        &mut self,
+        piece_i: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        let offset = self.range(piece_i, begin, data.len())?;
+        self.data[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    async fn read_block(
+        &mut self,
+        piece_i: usize,
+        begin: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let offset = self.range(piece_i, begin, length)?;
+        Ok(self.data[offset..offset + length].to_vec())
+    }
+
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn release(self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha1::{Digest, Sha1};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_checks_pieces() {
+        let torrent = Torrent::for_files("dir", &[("a", &[1; 5][..]), ("b", &[2; 5][..])], 4);
+        let mut storage = MemoryStorage::new(&torrent).unwrap();
+        storage.write_block(1, 0, &[1, 2, 2, 2]).await.unwrap();
+        assert_eq!(storage.file_data(1).unwrap(), &[2, 2, 2, 0, 0]);
+
+        let hash: [u8; 20] = Sha1::digest([1, 2, 2, 2]).into();
+        assert!(storage.check_piece(1, &hash).await.unwrap());
+        assert!(!storage.check_piece(0, &hash).await.unwrap());
+        assert!(storage.check_piece(3, &hash).await.is_err());
+    }
+}
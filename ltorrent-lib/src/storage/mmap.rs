@@ -0,0 +1,332 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use memmap2::MmapMut;
+
+use crate::download::priority::FilePriority;
+use crate::torrent::Torrent;
+
+use super::{create_files, stat_file, Allocation, FileLayout, FileSlice, FileStat, Storage};
+
+/// Stores the content of a torrent in its files, which are mapped into memory.
+///
+/// Blocks are copied straight into the page cache, without a system call per block, and the
+/// kernel writes them back to the files. Empty files are created but not mapped.
+///
+/// Skipped files are not created, and their bytes at the boundaries of the pieces of wanted
+/// files are kept in a part-file, like [`FileStorage`] does. The part-file is mapped too.
+///
+/// [`FileStorage`]: super::file::FileStorage
+#[derive(Debug)]
+pub struct MmapStorage {
+    layout: FileLayout,
+    root: PathBuf,
+    /// The mapped files, or `None` for the empty and skipped files.
+    maps: Vec<Option<MmapMut>>,
+    part_path: PathBuf,
+    part: Option<MmapMut>,
+}
+
+impl MmapStorage {
+    /// Creates the directory tree and the files of a torrent under `root`, and maps them into
+    /// memory.
+    ///
+    /// The files are mapped at their full length, so they cannot grow on write, and are
+    /// created sparse instead.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The layout of the torrent is invalid.
+    /// - The disk does not have enough free space for the content.
+    /// - A directory or file cannot be created, allocated or mapped.
+    pub async fn create(
+        torrent: &Torrent,
+        root: impl Into<PathBuf>,
+        allocation: Allocation,
+    ) -> anyhow::Result<Self> {
+        let n_files = FileLayout::new(torrent)?.files().len();
+        Self::create_with_priorities(
+            torrent,
+            root,
+            allocation,
+            &vec![FilePriority::Normal; n_files],
+        )
+        .await
+    }
+
+    /// Creates and maps the files of a torrent under `root` like [`MmapStorage::create`],
+    /// except for the files whose priority is [`FilePriority::Skip`], whose boundary bytes go
+    /// to the part-file.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if:
+    /// - The layout of the torrent is invalid, or there is not one priority per file.
+    /// - The disk does not have enough free space for the wanted files.
+    /// - A directory or file cannot be created, allocated or mapped.
+    pub async fn create_with_priorities(
+        torrent: &Torrent,
+        root: impl Into<PathBuf>,
+        allocation: Allocation,
+        priorities: &[FilePriority],
+    ) -> anyhow::Result<Self> {
+        let layout = FileLayout::new(torrent)?;
+        anyhow::ensure!(
+            priorities.len() == layout.files().len(),
+            "Expected {} file priorities, got {}.",
+            layout.files().len(),
+            priorities.len()
+        );
+        let root = root.into();
+        let allocation = match allocation {
+            Allocation::GrowOnWrite => Allocation::Sparse,
+            allocation => allocation,
+        };
+        let skipped = priorities
+            .iter()
+            .map(|priority| *priority == FilePriority::Skip)
+            .collect::<Vec<_>>();
+        let mut maps = Vec::with_capacity(layout.files().len());
+        for (file, entry) in create_files(&layout, &root, allocation, &skipped)
+            .await?
+            .into_iter()
+            .zip(layout.files())
+        {
+            let Some(file) = file.filter(|_| entry.length() > 0) else {
+                maps.push(None);
+                continue;
+            };
+            let file = file.into_std().await;
+            // SAFETY: The maps are only accessed through the storage, which is their sole owner.
+            // Another process truncating the files would fault, as with any memory map.
+            let map = unsafe { MmapMut::map_mut(&file) }
+                .with_context(|| format!("Failed to map file {}.", entry.path().display()))?;
+            maps.push(Some(map));
+        }
+
+        let part_path = root.join(format!(".{}.parts", torrent.name()));
+        let part = if skipped.contains(&true) {
+            let part = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&part_path)
+                .with_context(|| format!("Failed to open part-file {}.", part_path.display()))?;
+            // The part-file is sparse, so only the bytes written to it take up space. It is
+            // only resized when it is new, so that its modification time still tells whether
+            // it changed since the last run.
+            let length = layout.length() as u64;
+            let current = part
+                .metadata()
+                .with_context(|| format!("Failed to stat part-file {}.", part_path.display()))?
+                .len();
+            if current != length {
+                part.set_len(length).with_context(|| {
+                    format!("Failed to size part-file {}.", part_path.display())
+                })?;
+            }
+            // SAFETY: As for the files, the map is only accessed through the storage.
+            let map = unsafe { MmapMut::map_mut(&part) }
+                .with_context(|| format!("Failed to map part-file {}.", part_path.display()))?;
+            Some(map)
+        } else {
+            None
+        };
+        Ok(Self {
+            layout,
+            root,
+            maps,
+            part_path,
+            part,
+        })
+    }
+
+    /// Returns the directory the files are stored under.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the map that holds a range, and the offset of the range in it.
+    ///
+    /// Empty files never hold a range, so a file that is not mapped is a skipped file.
+    fn locate(&mut self, slice: &FileSlice) -> (&mut MmapMut, usize) {
+        match &mut self.maps[slice.file_i()] {
+            Some(map) => (map, slice.offset()),
+            None => {
+                let offset = self.layout.files()[slice.file_i()].offset() + slice.offset();
+                let part = self
+                    .part
+                    .as_mut()
+                    .expect("Part-file is mapped with skipped files.");
+                (part, offset)
+            }
+        }
+    }
+}
+
+impl Storage for MmapStorage {
+    fn layout(&self) -> &FileLayout {
+        &self.layout
+    }
+
+    async fn write_block(
+        &mut self,
+        piece_i: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        let mut data = data;
+        for slice in self.layout.map_block(piece_i, begin, data.len())? {
+            let (map, offset) = self.locate(&slice);
+            let (head, rest) = data.split_at(slice.length());
+            map[offset..offset + slice.length()].copy_from_slice(head);
+            data = rest;
+        }
+        Ok(())
+    }
+
+    async fn read_block(
+        &mut self,
+        piece_i: usize,
+        begin: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(length);
+        for slice in self.layout.map_block(piece_i, begin, length)? {
+            let (map, offset) = self.locate(&slice);
+            data.extend_from_slice(&map[offset..offset + slice.length()]);
+        }
+        Ok(data)
+    }
+
+    /// Writes the dirty pages of every file back to the disk.
+    async fn flush(&mut self) -> anyhow::Result<()> {
+        for map in self.maps.iter().flatten().chain(&self.part) {
+            map.flush().context("Failed to flush mapped file.")?;
+        }
+        Ok(())
+    }
+
+    /// Returns the stats of the files, where the skipped files take the stat of the
+    /// part-file.
+    async fn file_stats(&self) -> anyhow::Result<Option<Vec<FileStat>>> {
+        let mut stats = Vec::with_capacity(self.maps.len());
+        for (map, entry) in self.maps.iter().zip(self.layout.files()) {
+            let path = match map {
+                None if entry.length() > 0 => self.part_path.clone(),
+                _ => self.root.join(entry.path()),
+            };
+            stats.push(stat_file(&path).await?);
+        }
+        Ok(Some(stats))
+    }
+
+    async fn release(mut self) -> anyhow::Result<()> {
+        self.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_writes_through_mapped_files() {
+        let torrent = Torrent::for_files(
+            "dir",
+            &[("a", &b"abc"[..]), ("empty", &[][..]), ("b", &b"defgh"[..])],
+            4,
+        );
+        let root = tempfile::tempdir().unwrap();
+        let mut storage = MmapStorage::create(&torrent, root.path(), Allocation::Full)
+            .await
+            .unwrap();
+        storage.write_block(0, 0, b"abcd").await.unwrap();
+        storage.write_block(1, 0, b"efgh").await.unwrap();
+        assert_eq!(storage.read_block(0, 2, 3).await.unwrap(), b"cde");
+        storage.release().await.unwrap();
+
+        let dir = root.path().join("dir");
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"abc");
+        assert_eq!(std::fs::read(dir.join("empty")).unwrap(), b"");
+        assert_eq!(std::fs::read(dir.join("b")).unwrap(), b"defgh");
+    }
+
+    #[tokio::test]
+    async fn test_keeps_skipped_bytes_in_mapped_part_file() {
+        let torrent = Torrent::for_files(
+            "dir",
+            &[
+                ("a", &b"hello "[..]),
+                ("b", &b"skippd"[..]),
+                ("c", &b"file"[..]),
+            ],
+            4,
+        );
+        let root = tempfile::tempdir().unwrap();
+        let priorities = [FilePriority::Normal, FilePriority::Skip, FilePriority::High];
+        let mut storage = MmapStorage::create_with_priorities(
+            &torrent,
+            root.path(),
+            Allocation::Sparse,
+            &priorities,
+        )
+        .await
+        .unwrap();
+        storage.write_block(1, 0, b"o sk").await.unwrap();
+        storage.write_block(0, 0, b"hell").await.unwrap();
+        storage.write_block(3, 0, b"file").await.unwrap();
+        storage.release().await.unwrap();
+
+        let dir = root.path().join("dir");
+        assert_eq!(std::fs::read(dir.join("a")).unwrap(), b"hello ");
+        assert!(!dir.join("b").exists());
+        assert_eq!(std::fs::read(dir.join("c")).unwrap(), b"file");
+
+        // The boundary bytes are read back from the part-file after a restart.
+        let mut storage = MmapStorage::create_with_priorities(
+            &torrent,
+            root.path(),
+            Allocation::Sparse,
+            &priorities,
+        )
+        .await
+        .unwrap();
+        assert_eq!(storage.read_block(1, 0, 4).await.unwrap(), b"o sk");
+        assert_eq!(storage.file_stats().await.unwrap().unwrap().len(), 3);
+        assert!(MmapStorage::create_with_priorities(
+            &torrent,
+            root.path(),
+            Allocation::Sparse,
+            &priorities[..2],
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_leaves_part_file_alone_on_restart() {
+        let torrent = Torrent::for_files("dir", &[("a", &b"hello "[..]), ("b", &b"skip"[..])], 4);
+        let root = tempfile::tempdir().unwrap();
+        let priorities = [FilePriority::Normal, FilePriority::Skip];
+        let open = || {
+            MmapStorage::create_with_priorities(
+                &torrent,
+                root.path(),
+                Allocation::Sparse,
+                &priorities,
+            )
+        };
+        let mut storage = open().await.unwrap();
+        storage.write_block(1, 0, b"o sk").await.unwrap();
+        storage.release().await.unwrap();
+
+        // The resume data still matches the files every time the storage is opened again.
+        let stats = open().await.unwrap().file_stats().await.unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        let storage = open().await.unwrap();
+        assert_eq!(storage.file_stats().await.unwrap(), stats);
+    }
+}
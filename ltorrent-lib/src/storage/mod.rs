@@ -0,0 +1,530 @@
+use std::future::Future;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use fs4::tokio::AsyncFileExt;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use tokio::fs::OpenOptions;
+
+use crate::torrent::{Keys, Torrent};
+
+pub mod file;
+pub mod memory;
+pub mod mmap;
+pub mod verify;
+
+/// Where the content of a torrent is kept while it is downloaded and uploaded.
+///
+/// Blocks are addressed by piece and offset within the piece, as on the wire, and every
+/// backend knows how the content maps onto the files of the torrent through its
+/// [`FileLayout`]. The methods return `Send` futures, so that downloads can run on any task.
+pub trait Storage: Send {
+    /// Returns the layout of the files of the torrent.
+    fn layout(&self) -> &FileLayout;
+
+    /// Writes a block of a piece.
+    fn write_block(
+        &mut self,
+        piece_i: usize,
+        begin: usize,
+        data: &[u8],
+    ) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Reads a block of a piece.
+    fn read_block(
+        &mut self,
+        piece_i: usize,
+        begin: usize,
+        length: usize,
+    ) -> impl Future<Output = anyhow::Result<Vec<u8>>> + Send;
+
+    /// Reads a whole piece back, and returns whether it matches its SHA1 hash.
+    fn check_piece(
+        &mut self,
+        piece_i: usize,
+        hash: &[u8; 20],
+    ) -> impl Future<Output = anyhow::Result<bool>> + Send {
+        async move {
+            let length = self.layout().piece_length(piece_i)?;
+            let data = self.read_block(piece_i, 0, length).await?;
+            let actual: [u8; 20] = Sha1::digest(&data).into();
+            Ok(actual == *hash)
+        }
+    }
+
+    /// Makes sure the written blocks are durably stored.
+    fn flush(&mut self) -> impl Future<Output = anyhow::Result<()>> + Send;
+
+    /// Returns the length and modification time of every file, to tell whether the files
+    /// changed while the storage was not in use.
+    ///
+    /// Backends whose content does not outlive them return `None`, which is the default.
+    fn file_stats(&self) -> impl Future<Output = anyhow::Result<Option<Vec<FileStat>>>> + Send {
+        async { Ok(None) }
+    }
+
+    /// Flushes the storage and releases what it holds, such as open files, once no more
+    /// blocks will be read or written.
+    fn release(self) -> impl Future<Output = anyhow::Result<()>> + Send
+    where
+        Self: Sized;
+}
+
+/// How the files of a torrent take up space on the disk.
+///
+/// Allocations are parsed from their names: `sparse`, `full` and `grow`.
+///
+/// # Examples
+///
+/// ```
+/// use ltorrent::storage::Allocation;
+///
+/// let allocation: Allocation = "full".parse().unwrap();
+/// assert_eq!(allocation, Allocation::Full);
+/// ```
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Allocation {
+    /// The files are created at their full length, but the disk blocks are only allocated as
+    /// they are written.
+    #[default]
+    Sparse,
+    /// The disk blocks of the files are allocated before the download starts. This keeps
+    /// large files from fragmenting, and the disk from filling up halfway through.
+    Full,
+    /// The files start out empty, and grow as the blocks are written.
+    GrowOnWrite,
+}
+
+impl FromStr for Allocation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sparse" => Ok(Self::Sparse),
+            "full" => Ok(Self::Full),
+            "grow" => Ok(Self::GrowOnWrite),
+            _ => Err(anyhow::anyhow!(
+                "Unknown allocation {s}. Expected sparse, full or grow."
+            )),
+        }
+    }
+}
+
+/// A file of the torrent, and where it starts in the content.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    path: PathBuf,
+    length: usize,
+    offset: usize,
+}
+
+impl FileEntry {
+    /// Returns the path of the file, relative to the download directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the length of the file in bytes.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Returns the offset of the first byte of the file in the content of the torrent.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+/// The length and modification time of a file on the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStat {
+    length: u64,
+    /// Nanoseconds since the Unix epoch.
+    mtime: u64,
+}
+
+impl FileStat {
+    /// Creates the stat of a file of `length` bytes, last modified `mtime` nanoseconds after
+    /// the Unix epoch.
+    pub fn new(length: u64, mtime: u64) -> Self {
+        Self { length, mtime }
+    }
+
+    /// Returns the length of the file in bytes.
+    pub fn length(&self) -> u64 {
+        self.length
+    }
+
+    /// Returns the modification time of the file, in nanoseconds since the Unix epoch.
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+}
+
+/// A contiguous range of bytes within a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSlice {
+    file_i: usize,
+    offset: usize,
+    length: usize,
+}
+
+impl FileSlice {
+    /// Returns the index of the file in the layout.
+    pub fn file_i(&self) -> usize {
+        self.file_i
+    }
+
+    /// Returns the offset of the range within the file.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the length of the range in bytes.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+}
+
+/// Maps the content of a torrent onto its files.
+///
+/// The pieces of a torrent are cut from the concatenation of its files, in the order of the
+/// info dictionary, so a piece or even a block may span several files. The layout translates
+/// offsets in the content into ranges of the files they belong to.
+///
+/// The files of a single file torrent are stored as `<name>`, and those of a multi-file
+/// torrent under the directory `<name>`.
+#[derive(Debug, Clone)]
+pub struct FileLayout {
+    files: Vec<FileEntry>,
+    piece_length: usize,
+    length: usize,
+}
+
+impl FileLayout {
+    /// Builds the layout of the files of a torrent.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a file has no path, or a path that would leave
+    /// the download directory, such as one containing `..`.
+    pub fn new(torrent: &Torrent) -> anyhow::Result<Self> {
+        let root = sanitize(&[torrent.name().to_string()])?;
+        let files = match torrent.keys() {
+            Keys::SingleFile { length } => vec![FileEntry {
+                path: root,
+                length: *length,
+                offset: 0,
+            }],
+            Keys::MultiFile { files } => {
+                let mut offset = 0;
+                let mut entries = Vec::with_capacity(files.len());
+                for file in files {
+                    entries.push(FileEntry {
+                        path: root.join(sanitize(file.subdirectories())?),
+                        length: file.length(),
+                        offset,
+                    });
+                    offset += file.length();
+                }
+                entries
+            }
+        };
+        Ok(Self {
+            files,
+            piece_length: torrent.piece_length(),
+            length: torrent.length(),
+        })
+    }
+
+    /// Returns the files of the torrent, in the order their content is concatenated.
+    pub fn files(&self) -> &[FileEntry] {
+        &self.files
+    }
+
+    /// Returns the file at `path`, either relative to the download directory as in
+    /// [`FileEntry::path`], or relative to the directory of a multi-file torrent as in
+    /// [`File::path`](crate::torrent::File::path).
+    pub fn find(&self, path: impl AsRef<Path>) -> Option<&FileEntry> {
+        let path = path.as_ref();
+        self.files.iter().find(|entry| {
+            let in_directory = entry.path.iter().count() > 1;
+            entry.path == path || in_directory && entry.path.iter().skip(1).eq(path.iter())
+        })
+    }
+
+    /// Returns the total length of the content in bytes.
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    /// Returns the number of pieces of the content.
+    pub fn n_pieces(&self) -> usize {
+        self.length.div_ceil(self.piece_length)
+    }
+
+    /// Returns the number of bytes in a piece.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the piece index is out of bounds.
+    pub fn piece_length(&self, piece_i: usize) -> anyhow::Result<usize> {
+        let begin = piece_i * self.piece_length;
+        anyhow::ensure!(begin < self.length, "Piece index out of bounds.");
+        Ok(self.piece_length.min(self.length - begin))
+    }
+
+    /// Returns the ranges of the files that hold `length` bytes of content starting at
+    /// `offset`, in order.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the range goes past the end of the content.
+    pub fn map(&self, offset: usize, length: usize) -> anyhow::Result<Vec<FileSlice>> {
+        anyhow::ensure!(
+            offset + length <= self.length,
+            "Range {}..{} is out of the content of {} bytes.",
+            offset,
+            offset + length,
+            self.length
+        );
+
+        let mut slices = Vec::new();
+        let mut offset = offset;
+        let mut remaining = length;
+        let first = self
+            .files
+            .partition_point(|file| file.offset + file.length <= offset);
+        for (file_i, file) in self.files.iter().enumerate().skip(first) {
+            if remaining == 0 {
+                break;
+            }
+            if file.length == 0 {
+                continue;
+            }
+            let within = offset - file.offset;
+            let length = remaining.min(file.length - within);
+            slices.push(FileSlice {
+                file_i,
+                offset: within,
+                length,
+            });
+            offset += length;
+            remaining -= length;
+        }
+        Ok(slices)
+    }
+
+    /// Returns the ranges of the files that hold a block of a piece.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the block goes past the end of the content.
+    pub fn map_block(
+        &self,
+        piece_i: usize,
+        begin: usize,
+        length: usize,
+    ) -> anyhow::Result<Vec<FileSlice>> {
+        self.map(piece_i * self.piece_length + begin, length)
+    }
+}
+
+/// Creates the directory tree and the files of a layout under `root`, and opens them.
+///
+/// Existing files are opened as they are, so that their content can be checked and reused.
+/// The files are then sized according to the allocation mode. Before anything is allocated,
+/// the disk must have enough free space for the content that is not on it yet.
+///
+/// The files marked in `skipped` are neither created nor opened, and come back as `None`.
+/// Files past the end of `skipped` are not skipped.
+async fn create_files(
+    layout: &FileLayout,
+    root: &Path,
+    allocation: Allocation,
+    skipped: &[bool],
+) -> anyhow::Result<Vec<Option<tokio::fs::File>>> {
+    tokio::fs::create_dir_all(root)
+        .await
+        .with_context(|| format!("Failed to create directory {}.", root.display()))?;
+    check_free_space(layout, root, skipped).await?;
+
+    let mut files = Vec::with_capacity(layout.files().len());
+    for (file_i, entry) in layout.files().iter().enumerate() {
+        if skipped.get(file_i).copied().unwrap_or(false) {
+            files.push(None);
+            continue;
+        }
+        let path = root.join(entry.path());
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("Failed to create directory {}.", parent.display()))?;
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .await
+            .with_context(|| format!("Failed to open file {}.", path.display()))?;
+        let length = entry.length() as u64;
+        let current = file.metadata().await?.len();
+        // Growing files are only cut down, in case they hold stale bytes past their end. Files
+        // that already have the right length are left alone, so that their modification time
+        // still tells whether they changed since the last run.
+        if current != length && (allocation != Allocation::GrowOnWrite || current > length) {
+            file.set_len(length)
+                .await
+                .with_context(|| format!("Failed to resize file {}.", path.display()))?;
+        }
+        if allocation == Allocation::Full && length > 0 && file.allocated_size().await? < length {
+            file.allocate(length)
+                .await
+                .with_context(|| format!("Failed to allocate file {}.", path.display()))?;
+        }
+        files.push(Some(file));
+    }
+    Ok(files)
+}
+
+/// Returns the stat of the file at `path`.
+async fn stat_file(path: &Path) -> anyhow::Result<FileStat> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("Failed to read metadata of {}.", path.display()))?;
+    let mtime = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |mtime| mtime.as_nanos() as u64);
+    Ok(FileStat::new(metadata.len(), mtime))
+}
+
+/// Checks that the disk holding `root` has room for the parts of the files that do not exist
+/// yet. Skipped files take no space.
+async fn check_free_space(
+    layout: &FileLayout,
+    root: &Path,
+    skipped: &[bool],
+) -> anyhow::Result<()> {
+    let mut needed = 0;
+    for (file_i, entry) in layout.files().iter().enumerate() {
+        if skipped.get(file_i).copied().unwrap_or(false) {
+            continue;
+        }
+        let existing = tokio::fs::metadata(root.join(entry.path()))
+            .await
+            .map_or(0, |metadata| metadata.len());
+        needed += (entry.length() as u64).saturating_sub(existing);
+    }
+    let available = fs4::statvfs(root)
+        .with_context(|| format!("Failed to query free space of {}.", root.display()))?
+        .available_space();
+    anyhow::ensure!(
+        needed <= available,
+        "Not enough free space in {}: {} bytes needed, {} bytes available.",
+        root.display(),
+        needed,
+        available
+    );
+    Ok(())
+}
+
+/// Turns the path components of a file into a relative path, refusing the components that
+/// would point outside of the download directory.
+fn sanitize(components: &[String]) -> anyhow::Result<PathBuf> {
+    anyhow::ensure!(!components.is_empty(), "File has an empty path.");
+    let mut path = PathBuf::new();
+    for component in components {
+        let mut parsed = Path::new(component).components();
+        anyhow::ensure!(
+            matches!(
+                (parsed.next(), parsed.next()),
+                (Some(Component::Normal(_)), None)
+            ),
+            "Invalid path component {:?}.",
+            component
+        );
+        path.push(component);
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_maps_blocks_across_files() {
+        let torrent = Torrent::for_files(
+            "dir",
+            &[
+                ("a", &[0; 5][..]),
+                ("empty", &[][..]),
+                ("sub/b", &[0; 10][..]),
+                ("c", &[0; 3][..]),
+            ],
+            8,
+        );
+        let layout = FileLayout::new(&torrent).unwrap();
+        assert_eq!(layout.files()[2].path(), Path::new("dir/sub/b"));
+        assert_eq!(layout.files()[3].offset(), 15);
+
+        // Piece 0 ends in the middle of the second non-empty file.
+        let slices = layout.map_block(0, 0, 8).unwrap();
+        assert_eq!(
+            slices,
+            vec![
+                FileSlice {
+                    file_i: 0,
+                    offset: 0,
+                    length: 5
+                },
+                FileSlice {
+                    file_i: 2,
+                    offset: 0,
+                    length: 3
+                },
+            ]
+        );
+        let slices = layout.map_block(1, 4, 4).unwrap();
+        assert_eq!(slices.len(), 2);
+        assert_eq!((slices[0].file_i(), slices[0].offset()), (2, 7));
+        assert_eq!((slices[1].file_i(), slices[1].length()), (3, 1));
+        assert!(layout.map_block(2, 0, 3).is_err());
+        assert_eq!(layout.piece_length(2).unwrap(), 2);
+        assert!(layout.piece_length(3).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fails_without_free_space() {
+        let length = usize::MAX / 2;
+        let layout = FileLayout {
+            files: vec![FileEntry {
+                path: PathBuf::from("huge"),
+                length,
+                offset: 0,
+            }],
+            piece_length: 1 << 18,
+            length,
+        };
+        let root = tempfile::tempdir().unwrap();
+        let error = create_files(&layout, root.path(), Allocation::Sparse, &[])
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Not enough free space"));
+        assert!(!root.path().join("huge").exists());
+    }
+
+    #[test]
+    fn test_rejects_escaping_paths() {
+        let torrent = Torrent::for_files("dir", &[("../evil", &[0; 5][..])], 8);
+        assert!(FileLayout::new(&torrent).is_err());
+        let torrent = Torrent::for_files("..", &[("a", &[0; 5][..])], 8);
+        assert!(FileLayout::new(&torrent).is_err());
+    }
+}
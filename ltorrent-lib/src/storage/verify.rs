@@ -0,0 +1,223 @@
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+use crate::net::bitfield::BitField;
+use crate::piece::hasher::{HashPool, PieceHashes};
+use crate::torrent::Torrent;
+
+use super::{FileLayout, FileSlice};
+
+/// What is wrong with a file of the torrent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStatus {
+    /// The file does not exist.
+    Missing,
+    /// The file does not have the length listed in the torrent.
+    WrongSize { expected: u64, actual: u64 },
+    /// Some of the pieces that overlap the file do not match their hash.
+    Corrupt,
+}
+
+/// The result of checking existing content against its torrent.
+#[derive(Debug, Clone)]
+pub struct Verification {
+    pieces: BitField,
+    n_good: usize,
+    n_pieces: usize,
+    problems: Vec<(PathBuf, FileStatus)>,
+}
+
+impl Verification {
+    /// Returns the pieces that match their hash.
+    pub fn pieces(&self) -> &BitField {
+        &self.pieces
+    }
+
+    /// Returns the number of pieces that match their hash.
+    pub fn n_good(&self) -> usize {
+        self.n_good
+    }
+
+    /// Returns the number of pieces in the torrent.
+    pub fn n_pieces(&self) -> usize {
+        self.n_pieces
+    }
+
+    /// Returns the files that are missing, have the wrong size or are corrupt, with their
+    /// paths relative to the checked directory.
+    pub fn problems(&self) -> &[(PathBuf, FileStatus)] {
+        &self.problems
+    }
+
+    /// Returns whether every piece matches its hash.
+    pub fn is_complete(&self) -> bool {
+        self.n_good == self.n_pieces
+    }
+}
+
+/// Checks the content of a torrent stored under `dir` against the hashes of its pieces.
+///
+/// The files are looked up as laid out by [`FileLayout`], and are only read, never created or
+/// modified. Pieces that overlap a missing file or a file of the wrong size fail without
+/// being read. `on_piece` is called with the index of every checked piece and whether it is
+/// good, in order, to report progress.
+///
+/// The pieces are read one after the other, and hashed in parallel on the shared
+/// [`HashPool`].
+///
+/// # Errors
+///
+/// This function will return an error if the layout of the torrent is invalid, or an existing
+/// file cannot be read.
+pub async fn verify(
+    torrent: &Torrent,
+    dir: impl AsRef<Path>,
+    mut on_piece: impl FnMut(usize, bool),
+) -> anyhow::Result<Verification> {
+    let layout = FileLayout::new(torrent)?;
+    let dir = dir.as_ref();
+
+    let mut files = Vec::with_capacity(layout.files().len());
+    let mut statuses = Vec::with_capacity(layout.files().len());
+    for entry in layout.files() {
+        let path = dir.join(entry.path());
+        let file = match File::open(&path).await {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                files.push(None);
+                statuses.push(Some(FileStatus::Missing));
+                continue;
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to open file {}.", path.display()))
+            }
+        };
+        let expected = entry.length() as u64;
+        let actual = file.metadata().await?.len();
+        if actual == expected {
+            files.push(Some(file));
+            statuses.push(None);
+        } else {
+            files.push(None);
+            statuses.push(Some(FileStatus::WrongSize { expected, actual }));
+        }
+    }
+
+    let n_pieces = torrent.n_pieces();
+    let mut pieces = BitField::new(n_pieces);
+    let mut n_good = 0;
+    let mut hashes = PieceHashes::new(HashPool::shared());
+    let mut to_read = 0..n_pieces;
+    loop {
+        while !hashes.is_full() {
+            let Some(piece_i) = to_read.next() else {
+                break;
+            };
+            let length = layout.piece_length(piece_i)?;
+            let slices = layout.map_block(piece_i, 0, length)?;
+            hashes.push(piece_i, length, read_slices(&mut files, &slices).await?);
+        }
+        let Some((piece_i, length, actual)) = hashes.next().await else {
+            break;
+        };
+        let slices = layout.map_block(piece_i, 0, length)?;
+        let good = actual.is_some_and(|actual| actual == torrent.pieces_sha1()[piece_i]);
+        if good {
+            pieces.set_piece(piece_i);
+            n_good += 1;
+        } else {
+            for slice in &slices {
+                statuses[slice.file_i()].get_or_insert(FileStatus::Corrupt);
+            }
+        }
+        on_piece(piece_i, good);
+    }
+
+    let problems = layout
+        .files()
+        .iter()
+        .zip(statuses)
+        .filter_map(|(entry, status)| Some((entry.path().to_path_buf(), status?)))
+        .collect();
+    Ok(Verification {
+        pieces,
+        n_good,
+        n_pieces,
+        problems,
+    })
+}
+
+/// Reads the content of file ranges, or returns `None` if one of the files is unusable.
+async fn read_slices(
+    files: &mut [Option<File>],
+    slices: &[FileSlice],
+) -> anyhow::Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    for slice in slices {
+        let Some(file) = files[slice.file_i()].as_mut() else {
+            return Ok(None);
+        };
+        let start = data.len();
+        data.resize(start + slice.length(), 0);
+        file.seek(std::io::SeekFrom::Start(slice.offset() as u64))
+            .await?;
+        file.read_exact(&mut data[start..])
+            .await
+            .context("Failed to read file.")?;
+    }
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reports_bad_files() {
+        let torrent = Torrent::for_files(
+            "dir",
+            &[
+                ("a", &b"hello"[..]),
+                ("sub/b", &b" multi-file"[..]),
+                ("c", &b" world"[..]),
+                ("d", &b"!"[..]),
+            ],
+            8,
+        );
+        let root = tempfile::tempdir().unwrap();
+        let dir = root.path().join("dir");
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("a"), b"hello").unwrap();
+        std::fs::write(dir.join("sub/b"), b" multi-fiLe").unwrap();
+        std::fs::write(dir.join("d"), b"!!").unwrap();
+
+        let mut checked = Vec::new();
+        let verification = verify(&torrent, root.path(), |piece_i, good| {
+            checked.push((piece_i, good))
+        })
+        .await
+        .unwrap();
+        assert_eq!(checked, vec![(0, true), (1, false), (2, false)]);
+        assert!(!verification.is_complete());
+        assert_eq!(verification.n_good(), 1);
+        assert!(verification.pieces().contains_piece(0));
+        assert_eq!(
+            verification.problems(),
+            [
+                (PathBuf::from("dir/sub/b"), FileStatus::Corrupt),
+                (PathBuf::from("dir/c"), FileStatus::Missing),
+                (
+                    PathBuf::from("dir/d"),
+                    FileStatus::WrongSize {
+                        expected: 1,
+                        actual: 2
+                    }
+                ),
+            ]
+        );
+    }
+}
@@ -112,6 +112,20 @@ impl Torrent {
         self.info.piece_length
     }
 
+    /// Returns the number of bytes in the specified piece.
+    ///
+    /// All the pieces are `piece_length` bytes long, except for the last one, which is
+    /// truncated to the end of the content.
+    ///
+    /// # Errors
+    ///
+    /// This function can return an error if the piece index is out of bounds.
+    pub fn get_piece_length(&self, piece_i: usize) -> anyhow::Result<usize> {
+        anyhow::ensure!(piece_i < self.n_pieces(), "Piece index out of bounds.");
+        let begin = piece_i * self.piece_length();
+        Ok(self.piece_length().min(self.length() - begin))
+    }
+
     /// Returns the SHA1 hash of a specified piece in the torrent as a sequence of bytes.
     ///
     /// Each piece is assigned a SHA-1 hash value. On public networks, there may be
@@ -165,6 +179,43 @@ impl Torrent {
     }
 }
 
+#[cfg(test)]
+impl Torrent {
+    /// Creates a single file torrent with the given content, hashing it into pieces.
+    pub(crate) fn for_data(name: &str, data: &[u8], piece_length: usize) -> Self {
+        Self::with_keys(name, data, piece_length, Keys::SingleFile { length: data.len() })
+    }
+
+    /// Creates a multi-file torrent with the given files, whose paths are separated by `/`.
+    pub(crate) fn for_files(name: &str, files: &[(&str, &[u8])], piece_length: usize) -> Self {
+        let data = files.iter().flat_map(|(_, data)| *data).copied().collect::<Vec<_>>();
+        let files = files
+            .iter()
+            .map(|(path, data)| File {
+                length: data.len(),
+                subdirectories: path.split('/').map(str::to_string).collect(),
+            })
+            .collect();
+        Self::with_keys(name, &data, piece_length, Keys::MultiFile { files })
+    }
+
+    fn with_keys(name: &str, data: &[u8], piece_length: usize, keys: Keys) -> Self {
+        let pieces = data
+            .chunks(piece_length)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect();
+        Self {
+            announce: "http://localhost/announce".to_string(),
+            info: Info {
+                name: name.to_string(),
+                piece_length,
+                pieces: Hashes(pieces),
+                keys,
+            },
+        }
+    }
+}
+
 /// Torrent info dictionary.
 ///
 /// - `name`: The name of the file or directory.
@@ -237,6 +288,11 @@ impl File {
     pub fn length(&self) -> usize {
         self.length
     }
+
+    /// Returns the subdirectory names leading to the file, the last of which is the file name.
+    pub fn subdirectories(&self) -> &[String] {
+        &self.subdirectories
+    }
 }
 
 /// A list of SHA1 hashes.
@@ -274,7 +330,7 @@ impl<'de> serde::de::Visitor<'de> for HashesVisitor {
     where
         E: serde::de::Error,
     {
-        if v.len() % 20 != 0 {
+        if !v.len().is_multiple_of(20) {
             return Err(E::invalid_length(v.len(), &self));
         }
         Ok(Hashes(
@@ -191,7 +191,7 @@ impl<'de> serde::de::Visitor<'de> for PeersVisitor {
     where
         E: serde::de::Error,
     {
-        if v.len() % 6 != 0 {
+        if !v.len().is_multiple_of(6) {
             return Err(E::invalid_length(v.len(), &self));
         }
         Ok(PeersAddresses(